@@ -0,0 +1,83 @@
+//! Benchmarks comparing the two ways of turning batched messages into request
+//! bodies: re-serializing the flushed `Message`, as `Client::send` does, and
+//! reusing the bytes retained by the batcher via `flush_serialized`.
+
+use analytics::batcher::Batcher;
+use analytics::message::{BatchMessage, Track, User};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use serde_json::json;
+
+const MESSAGES: usize = 10_000;
+
+fn messages() -> Vec<BatchMessage> {
+    (0..MESSAGES)
+        .map(|i| {
+            BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: format!("user-{}", i),
+                },
+                event: "Example Event".to_owned(),
+                properties: json!({
+                    "foo": format!("bar-{}", i),
+                    "index": i,
+                    "nested": { "a": [1, 2, 3], "b": true },
+                }),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn via_message(msgs: Vec<BatchMessage>) -> usize {
+    let mut batcher = Batcher::new(None);
+    let mut bytes = 0;
+
+    for msg in msgs {
+        if let Some(msg) = batcher.push(msg).unwrap() {
+            let batch = batcher.flush().unwrap();
+            bytes += serde_json::to_vec(&batch).unwrap().len();
+            batcher.push(msg).unwrap();
+        }
+    }
+
+    if let Some(batch) = batcher.flush() {
+        bytes += serde_json::to_vec(&batch).unwrap().len();
+    }
+
+    bytes
+}
+
+fn via_serialized(msgs: Vec<BatchMessage>) -> usize {
+    let mut batcher = Batcher::new(None);
+    let mut bytes = 0;
+
+    for msg in msgs {
+        if let Some(msg) = batcher.push(msg).unwrap() {
+            bytes += batcher.flush_serialized().unwrap().as_bytes().len();
+            batcher.push(msg).unwrap();
+        }
+    }
+
+    if let Some(batch) = batcher.flush_serialized() {
+        bytes += batch.as_bytes().len();
+    }
+
+    bytes
+}
+
+fn batching(c: &mut Criterion) {
+    let msgs = messages();
+
+    let mut group = c.benchmark_group("batching");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("flush", |b| {
+        b.iter_batched(|| msgs.clone(), via_message, BatchSize::LargeInput)
+    });
+    group.bench_function("flush_serialized", |b| {
+        b.iter_batched(|| msgs.clone(), via_serialized, BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, batching);
+criterion_main!(benches);
@@ -1,5 +1,8 @@
 //! An example showing how to do an ETL-like operation loading events into
 //! Segment.
+//!
+//! Batches are sent using the bytes serialized when each message was pushed,
+//! so no message is serialized more than once.
 
 use analytics::batcher::Batcher;
 use analytics::client::Client;
@@ -31,10 +34,16 @@ fn main() {
         // would probably want to put this message in a deadletter queue or some
         // equivalent.
         if let Some(msg) = batcher.push(msg).unwrap() {
-            client.send(write_key, &batcher.into_message()).unwrap();
+            if let Some(batch) = batcher.flush_serialized() {
+                client.send_serialized(write_key, &batch).unwrap();
+            }
 
-            batcher = Batcher::new(None);
             batcher.push(msg).unwrap(); // Same error condition as above.
         }
     }
+
+    // Send whatever is left over in the batcher.
+    if let Some(batch) = batcher.flush_serialized() {
+        client.send_serialized(write_key, &batch).unwrap();
+    }
 }
@@ -0,0 +1,423 @@
+//! Reading and replaying archives of messages.
+//!
+//! Segment's S3 and data lake destinations write every message a source
+//! receives to gzipped files of newline-delimited JSON. An
+//! [`Archive`](struct.Archive.html) streams messages back out of such files,
+//! or out of the files written by [`FileClient`](../file/struct.FileClient.html),
+//! optionally filtered to a window of time or to particular events, and can
+//! replay them to any `Client`:
+//!
+//! ```no_run
+//! use analytics::archive::Archive;
+//! use analytics::http::HttpClient;
+//! use chrono::{TimeZone, Utc};
+//!
+//! let archive = Archive::open("segment-logs/*/1690848000000/*.gz")
+//!     .unwrap()
+//!     .since(Utc.with_ymd_and_hms(2023, 8, 1, 0, 0, 0).unwrap())
+//!     .until(Utc.with_ymd_and_hms(2023, 8, 2, 0, 0, 0).unwrap())
+//!     .event("Order Completed");
+//!
+//! let replayed = archive
+//!     .replay(&HttpClient::default(), "YOUR_WRITE_KEY")
+//!     .unwrap();
+//! println!("replayed {} messages", replayed.sent);
+//! ```
+//!
+//! Replayed messages keep their original `messageId` and `timestamp`, so
+//! Segment deduplicates any which were already delivered.
+
+use crate::batcher::Batcher;
+use crate::client::Client;
+use crate::errors::Error as AnalyticsError;
+use crate::message::BatchMessage;
+use chrono::{DateTime, Utc};
+use failure::Error;
+use flate2::read::MultiGzDecoder;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Fields which Segment adds to messages as it receives them.
+///
+/// These are removed from replayed messages. In particular, a stale `sentAt`
+/// would cause Segment to "correct" each message's `timestamp` towards the
+/// time it was replayed.
+const RECEIVED_FIELDS: &[&str] = &[
+    "originalTimestamp",
+    "projectId",
+    "receivedAt",
+    "sentAt",
+    "writeKey",
+];
+
+/// A set of archive files, and the messages to read from them.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    paths: Vec<PathBuf>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    events: BTreeSet<String>,
+}
+
+/// The outcome of replaying an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Replayed {
+    /// The number of messages sent.
+    pub sent: usize,
+
+    /// The number of messages skipped by the archive's filters.
+    pub skipped: usize,
+}
+
+impl Archive {
+    /// Open the archive files at `path`.
+    ///
+    /// `path` may be a single file, a directory (every file beneath which is
+    /// read), or a glob pattern such as `logs/*/*.gz`. Files are read in
+    /// order of their paths, and may be plain or gzipped.
+    pub fn open(path: &str) -> Result<Archive, Error> {
+        let mut paths = Vec::new();
+
+        if Path::new(path).exists() {
+            walk(Path::new(path), &mut paths)?;
+        } else {
+            for entry in glob::glob(path)? {
+                walk(&entry?, &mut paths)?;
+            }
+            if paths.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no archive files match {}", path),
+                )
+                .into());
+            }
+        }
+
+        paths.sort();
+        paths.dedup();
+        Ok(Archive::from_paths(paths))
+    }
+
+    /// Construct an archive from a list of files, read in the given order.
+    pub fn from_paths(paths: Vec<PathBuf>) -> Archive {
+        Archive {
+            paths,
+            since: None,
+            until: None,
+            events: BTreeSet::new(),
+        }
+    }
+
+    /// The files in this archive.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Only read messages with a timestamp at or after `since`.
+    pub fn since(mut self, since: DateTime<Utc>) -> Archive {
+        self.since = Some(since);
+        self
+    }
+
+    /// Only read messages with a timestamp before `until`.
+    pub fn until(mut self, until: DateTime<Utc>) -> Archive {
+        self.until = Some(until);
+        self
+    }
+
+    /// Only read `track` events with this name. May be called more than once
+    /// to read several events.
+    pub fn event(mut self, event: &str) -> Archive {
+        self.events.insert(event.to_owned());
+        self
+    }
+
+    /// Whether a message passes this archive's filters. Messages without a
+    /// timestamp never pass a time window.
+    pub fn matches(&self, msg: &BatchMessage) -> bool {
+        if self.since.is_some() || self.until.is_some() {
+            match msg.timestamp() {
+                Some(timestamp) => {
+                    if self.since.is_some_and(|since| timestamp < since)
+                        || self.until.is_some_and(|until| timestamp >= until)
+                    {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+
+        if !self.events.is_empty() {
+            return match msg {
+                BatchMessage::Track(track) => self.events.contains(&track.event),
+                _ => false,
+            };
+        }
+
+        true
+    }
+
+    /// Iterate over every message in the archive, ignoring filters.
+    ///
+    /// Fields which Segment adds on receipt, such as `receivedAt` and
+    /// `sentAt`, are removed.
+    pub fn messages(&self) -> Messages {
+        Messages {
+            paths: self.paths.clone().into_iter(),
+            current: None,
+        }
+    }
+
+    /// Send every message which passes this archive's filters to `client`,
+    /// in batches.
+    ///
+    /// Replaying stops at the first line which can't be parsed, or the first
+    /// batch which can't be sent.
+    pub fn replay<C: Client>(&self, client: &C, write_key: &str) -> Result<Replayed, Error> {
+        let mut batcher = Batcher::new(None);
+        let mut replayed = Replayed::default();
+
+        for msg in self.messages() {
+            let msg = msg?;
+            if !self.matches(&msg) {
+                replayed.skipped += 1;
+                continue;
+            }
+
+            if let Some(msg) = batcher.push(msg)? {
+                let len = batcher.len();
+                if let Some(batch) = batcher.flush_serialized() {
+                    client.send_serialized(write_key, &batch)?;
+                    replayed.sent += len;
+                }
+                batcher.push(msg)?;
+            }
+        }
+
+        let len = batcher.len();
+        if let Some(batch) = batcher.flush_serialized() {
+            client.send_serialized(write_key, &batch)?;
+            replayed.sent += len;
+        }
+
+        Ok(replayed)
+    }
+}
+
+type Lines = io::Lines<BufReader<Box<dyn Read>>>;
+
+/// An iterator over the messages in an archive.
+///
+/// See [`Archive::messages`](struct.Archive.html#method.messages).
+pub struct Messages {
+    paths: std::vec::IntoIter<PathBuf>,
+    current: Option<(PathBuf, Lines, u64)>,
+}
+
+impl Iterator for Messages {
+    type Item = Result<BatchMessage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let path = self.paths.next()?;
+                match open(&path) {
+                    Ok(lines) => self.current = Some((path, lines, 0)),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            let (path, lines, line) = self.current.as_mut().unwrap();
+            let text = match lines.next() {
+                Some(Ok(text)) => text,
+                Some(Err(e)) => {
+                    self.current = None;
+                    return Some(Err(e.into()));
+                }
+                None => {
+                    self.current = None;
+                    continue;
+                }
+            };
+
+            *line += 1;
+            if text.trim().is_empty() {
+                continue;
+            }
+
+            return Some(match serde_json::from_str::<BatchMessage>(&text) {
+                Ok(mut msg) => {
+                    let extra = msg.extra_mut();
+                    for field in RECEIVED_FIELDS {
+                        extra.remove(*field);
+                    }
+                    Ok(msg)
+                }
+                Err(e) => Err(AnalyticsError::InvalidArchive {
+                    path: path.display().to_string(),
+                    line: *line,
+                    reason: e.to_string(),
+                }
+                .into()),
+            });
+        }
+    }
+}
+
+/// Collect the files at or beneath `path`.
+fn walk(path: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            walk(&entry?.path(), paths)?;
+        }
+    } else {
+        paths.push(path.to_owned());
+    }
+    Ok(())
+}
+
+/// Open an archive file, decompressing it if it starts with the gzip magic
+/// number.
+fn open(path: &Path) -> Result<Lines, Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = if file.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(BufReader::new(reader).lines())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryClient;
+    use chrono::TimeZone;
+    use flate2::write::GzEncoder;
+    use serde_json::json;
+    use std::io::Write;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("analytics-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("source/day")).unwrap();
+        dir
+    }
+
+    fn line(event: &str, day: u32) -> String {
+        json!({
+            "type": "track",
+            "userId": "user",
+            "event": event,
+            "properties": {},
+            "messageId": format!("{}-{}", event, day),
+            "timestamp": format!("2023-08-{:02}T12:00:00Z", day),
+            "sentAt": "2023-09-01T00:00:00Z",
+            "receivedAt": "2023-09-01T00:00:01Z",
+        })
+        .to_string()
+    }
+
+    fn write_archive(dir: &Path) {
+        let mut gz = GzEncoder::new(
+            File::create(dir.join("source/day/b.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        writeln!(
+            gz,
+            "{}\n{}",
+            line("Signed Up", 1),
+            line("Order Completed", 2)
+        )
+        .unwrap();
+        gz.finish().unwrap();
+
+        fs::write(
+            dir.join("source/day/a.ndjson"),
+            format!(
+                "{}\n\n{}\n",
+                line("Order Completed", 1),
+                line("Order Completed", 3)
+            ),
+        )
+        .unwrap();
+    }
+
+    fn message_ids(msgs: &[BatchMessage]) -> Vec<String> {
+        msgs.iter()
+            .map(|msg| match msg {
+                BatchMessage::Track(track) => track.extra["messageId"].as_str().unwrap().to_owned(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = dir("archive-replay");
+        write_archive(&dir);
+
+        let archive = Archive::open(dir.join("*/*/*").to_str().unwrap())
+            .unwrap()
+            .since(Utc.with_ymd_and_hms(2023, 8, 1, 0, 0, 0).unwrap())
+            .until(Utc.with_ymd_and_hms(2023, 8, 3, 0, 0, 0).unwrap())
+            .event("Order Completed");
+
+        let client = MemoryClient::new();
+        let replayed = archive.replay(&client, "write_key").unwrap();
+        assert_eq!(
+            Replayed {
+                sent: 2,
+                skipped: 2
+            },
+            replayed
+        );
+
+        assert_eq!(1, client.sent().len());
+        let msgs = client.messages();
+        assert_eq!(
+            vec!["Order Completed-1", "Order Completed-2"],
+            message_ids(&msgs)
+        );
+        match &msgs[1] {
+            BatchMessage::Track(track) => {
+                assert_eq!(
+                    Some(Utc.with_ymd_and_hms(2023, 8, 2, 12, 0, 0).unwrap()),
+                    track.timestamp
+                );
+                assert!(!track.extra.contains_key("sentAt"));
+                assert!(!track.extra.contains_key("receivedAt"));
+            }
+            _ => unreachable!(),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_line() {
+        let dir = dir("archive-invalid");
+        let path = dir.join("source/day/a.ndjson");
+        fs::write(&path, format!("{}\nnot json\n", line("Signed Up", 1))).unwrap();
+
+        let archive = Archive::open(dir.to_str().unwrap()).unwrap();
+        let mut messages = archive.messages();
+        assert!(messages.next().unwrap().is_ok());
+
+        let err = messages.next().unwrap().unwrap_err();
+        match err.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::InvalidArchive { line, .. }) => assert_eq!(2, *line),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(messages.next().is_none());
+
+        let client = MemoryClient::new();
+        assert!(archive.replay(&client, "write_key").is_err());
+        assert!(client.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
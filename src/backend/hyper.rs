@@ -0,0 +1,156 @@
+use super::{ConnectionOptions, HttpBackend, Request, Response};
+use crate::errors::Error as AnalyticsError;
+use ::hyper::body::Bytes;
+use ::rustls::pki_types::pem::PemObject;
+use ::rustls::pki_types::CertificateDer;
+use ::rustls::{ClientConfig, RootCertStore};
+use failure::Error;
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+/// A backend which makes requests with `hyper`, on a `tokio` runtime with a
+/// single worker thread which the backend starts for itself.
+///
+/// This is available with the `hyper` feature. Connections are made over
+/// HTTP/1.1, with TLS from `rustls` trusting Mozilla's root certificates.
+/// Proxies are not supported.
+///
+/// Requests block until they complete. Made from within another `tokio`
+/// runtime, where blocking on the backend's own runtime isn't allowed, each
+/// request waits on a thread of its own instead, and the backend may be
+/// dropped there too.
+pub struct HyperBackend {
+    /// Only taken when the backend is dropped.
+    runtime: Option<Runtime>,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    options: ConnectionOptions,
+}
+
+impl HyperBackend {
+    /// Construct a backend which connects according to `options`.
+    pub fn new(options: &ConnectionOptions) -> Result<HyperBackend, Error> {
+        if options.proxy.is_some() {
+            return Err(AnalyticsError::Unsupported {
+                option: "a proxy",
+                backend: "hyper",
+            }
+            .into());
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        for pem in &options.root_certificates {
+            for cert in CertificateDer::pem_slice_iter(pem) {
+                roots.add(cert?)?;
+            }
+        }
+        let tls = ClientConfig::builder_with_provider(Arc::new(
+            ::rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(options.connect_timeout);
+        http.set_keepalive(options.tcp_keepalive);
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http);
+
+        let mut builder = Client::builder(TokioExecutor::new());
+        builder.pool_timer(TokioTimer::new());
+        if let Some(timeout) = options.pool_idle_timeout {
+            builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = options.pool_max_idle {
+            builder.pool_max_idle_per_host(max);
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        // The client's connection pool uses the runtime it is built on.
+        let client = {
+            let _guard = runtime.enter();
+            builder.build(connector)
+        };
+
+        Ok(HyperBackend {
+            runtime: Some(runtime),
+            client,
+            options: options.clone(),
+        })
+    }
+}
+
+impl HttpBackend for HyperBackend {
+    fn post(&self, request: Request) -> Result<Response, Error> {
+        let mut builder = ::hyper::Request::post(request.url.as_str());
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let request = builder.body(Full::new(request.body))?;
+
+        let send = async {
+            let response = self.client.request(request).await?;
+            let status = response.status().as_u16();
+            let body = response.into_body().collect().await?.to_bytes();
+            Ok::<_, Error>(Response {
+                status,
+                body: body.to_vec(),
+            })
+        };
+
+        let runtime = self.runtime.as_ref().unwrap();
+        let run = || {
+            runtime.block_on(async {
+                match self.options.timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, send).await?,
+                    None => send.await,
+                }
+            })
+        };
+        // `block_on` panics on a thread which is already running a runtime.
+        if tokio::runtime::Handle::try_current().is_err() {
+            return run();
+        }
+        std::thread::scope(|scope| match scope.spawn(run).join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        })
+    }
+}
+
+impl Drop for HyperBackend {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks until its tasks finish, which panics
+        // within another runtime, so leave them to finish in the background.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+pub(crate) fn is_transient(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<hyper_util::client::legacy::Error>() {
+        // Other than failing to connect, the client fails on its own
+        // account, or with the connection's error once it was made.
+        return e.is_connect()
+            || std::error::Error::source(e)
+                .and_then(|source| source.downcast_ref::<::hyper::Error>())
+                .is_some_and(|e| !e.is_user());
+    }
+    e.downcast_ref::<::hyper::Error>()
+        .is_some_and(|e| !e.is_user())
+        || e.downcast_ref::<tokio::time::error::Elapsed>().is_some()
+}
@@ -0,0 +1,272 @@
+//! HTTP libraries for an [`HttpClient`](../http/struct.HttpClient.html) to
+//! send requests with.
+//!
+//! Each backend is behind a feature of the same name:
+//!
+//! | Feature | Backend | Notes |
+//! |---------|---------|-------|
+//! | `reqwest` | [`ReqwestBackend`](struct.ReqwestBackend.html) | Enabled by default. |
+//! | `ureq` | [`UreqBackend`](struct.UreqBackend.html) | Small, and without an async runtime. |
+//! | `hyper` | [`HyperBackend`](struct.HyperBackend.html) | Runs its own `tokio` runtime. |
+//!
+//! `HttpClient::builder().build()` uses the first of these which is enabled.
+//! Another can be chosen with `build_with`:
+//!
+//! ```no_run
+//! # #[cfg(feature = "ureq")]
+//! # {
+//! use analytics::backend::UreqBackend;
+//! use analytics::http::HttpClient;
+//!
+//! let client = HttpClient::builder().build_with(UreqBackend::new).unwrap();
+//! # }
+//! ```
+//!
+//! Other HTTP libraries can be used by implementing
+//! [`HttpBackend`](trait.HttpBackend.html).
+
+use bytes::Bytes;
+use failure::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "hyper")]
+mod hyper;
+#[cfg(feature = "reqwest")]
+mod reqwest;
+#[cfg(feature = "ureq")]
+mod ureq;
+
+#[cfg(feature = "hyper")]
+pub use self::hyper::HyperBackend;
+#[cfg(feature = "reqwest")]
+pub use self::reqwest::ReqwestBackend;
+#[cfg(feature = "ureq")]
+pub use self::ureq::UreqBackend;
+
+/// An HTTP library which can make the requests of an `HttpClient`.
+pub trait HttpBackend: Send + Sync {
+    /// Make a `POST` request.
+    ///
+    /// A response is returned whatever its status; an error means that no
+    /// response was received, such as because connecting or the request
+    /// timed out.
+    fn post(&self, request: Request) -> Result<Response, Error>;
+}
+
+impl<B: HttpBackend + ?Sized> HttpBackend for Box<B> {
+    fn post(&self, request: Request) -> Result<Response, Error> {
+        (**self).post(request)
+    }
+}
+
+impl<B: HttpBackend + ?Sized> HttpBackend for Arc<B> {
+    fn post(&self, request: Request) -> Result<Response, Error> {
+        (**self).post(request)
+    }
+}
+
+/// A request for an `HttpBackend` to make.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The URL to post to.
+    pub url: String,
+
+    /// The request's headers, as name and value.
+    pub headers: Vec<(String, String)>,
+
+    /// The request's body.
+    pub body: Bytes,
+}
+
+/// A response received by an `HttpBackend`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// The response's HTTP status.
+    pub status: u16,
+
+    /// The response's body.
+    pub body: Vec<u8>,
+}
+
+/// How a backend makes connections, as configured through an
+/// [`HttpClientBuilder`](../http/struct.HttpClientBuilder.html).
+///
+/// Backends return `Error::Unsupported` for options they can't honour,
+/// rather than ignoring them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// How long a whole request may take.
+    pub timeout: Option<Duration>,
+
+    /// How long connecting may take.
+    pub connect_timeout: Option<Duration>,
+
+    /// How long an idle connection is kept open for reuse.
+    pub pool_idle_timeout: Option<Duration>,
+
+    /// The most idle connections to keep open for reuse.
+    pub pool_max_idle: Option<usize>,
+
+    /// The interval at which to probe idle connections with TCP keepalive.
+    pub tcp_keepalive: Option<Duration>,
+
+    /// The URL of a proxy to send every request through.
+    pub proxy: Option<String>,
+
+    /// PEM-encoded root certificates to trust, in addition to the bundled or
+    /// system ones.
+    pub root_certificates: Vec<Vec<u8>>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            timeout: Some(Duration::from_secs(30)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            pool_idle_timeout: None,
+            pool_max_idle: None,
+            tcp_keepalive: None,
+            proxy: None,
+            root_certificates: Vec::new(),
+        }
+    }
+}
+
+/// Returns whether an error from a backend is a failure to connect or to
+/// receive a response, which may succeed if retried.
+pub(crate) fn is_transient(e: &Error) -> bool {
+    #[cfg(feature = "reqwest")]
+    {
+        if let Some(e) = e.downcast_ref::<::reqwest::Error>() {
+            return self::reqwest::is_transient(e);
+        }
+    }
+    #[cfg(feature = "ureq")]
+    {
+        if let Some(e) = e.downcast_ref::<::ureq::Error>() {
+            return self::ureq::is_transient(e);
+        }
+    }
+    #[cfg(feature = "hyper")]
+    {
+        if self::hyper::is_transient(e) {
+            return true;
+        }
+    }
+
+    let _ = e;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::errors::Error as AnalyticsError;
+    use crate::http::{HttpClient, HttpClientBuilder};
+    use crate::message::{Message, Track, User};
+    use crate::testing::{MockResponse, MockServer};
+
+    type Build = fn(HttpClientBuilder) -> Result<HttpClient, Error>;
+
+    fn backends() -> Vec<(&'static str, Build)> {
+        vec![
+            #[cfg(feature = "reqwest")]
+            ("reqwest", |b| b.build_with(ReqwestBackend::new)),
+            #[cfg(feature = "ureq")]
+            ("ureq", |b| b.build_with(UreqBackend::new)),
+            #[cfg(feature = "hyper")]
+            ("hyper", |b| b.build_with(HyperBackend::new)),
+        ]
+    }
+
+    #[test]
+    fn test_backends() {
+        let msg = Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        });
+
+        for (name, build) in backends() {
+            let server = MockServer::with_write_key("write_key");
+            let client = build(HttpClient::builder().with_endpoint(server.endpoint())).unwrap();
+
+            client.send("write_key", &msg).unwrap();
+            assert_eq!(vec![msg.clone()], server.messages(), "{}", name);
+
+            server.enqueue(MockResponse::status(503));
+            let e = client.send("write_key", &msg).unwrap_err();
+            match e.downcast_ref::<AnalyticsError>() {
+                Some(AnalyticsError::Status { status: 503, .. }) => {}
+                _ => panic!("unexpected error from {}: {}", name, e),
+            }
+            assert!(crate::retry::is_retryable(&e));
+
+            let e = client.send("wrong_key", &msg).unwrap_err();
+            assert!(!crate::retry::is_retryable(&e), "{}", name);
+        }
+    }
+
+    #[cfg(feature = "ureq")]
+    #[test]
+    fn test_ureq_unsupported() {
+        let options = ConnectionOptions {
+            root_certificates: vec![b"-----BEGIN CERTIFICATE-----".to_vec()],
+            ..Default::default()
+        };
+        let e = UreqBackend::new(&options).unwrap_err();
+        match e.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::Unsupported {
+                backend: "ureq", ..
+            }) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(feature = "hyper")]
+    #[tokio::test]
+    async fn test_hyper_dropped_in_runtime() {
+        let backend = HyperBackend::new(&ConnectionOptions::default()).unwrap();
+        drop(backend);
+    }
+
+    #[cfg(feature = "hyper")]
+    #[test]
+    fn test_hyper_in_runtime() {
+        let server = MockServer::start();
+        let client = HttpClient::builder()
+            .with_endpoint(server.endpoint())
+            .build_with(HyperBackend::new)
+            .unwrap();
+        let msg = Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        });
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async { client.send("write_key", &msg) })
+            .unwrap();
+        assert_eq!(vec![msg], server.messages());
+
+        // Nothing listens on port 1, so connecting fails.
+        let client = HttpClient::builder()
+            .with_endpoint(crate::endpoint::Endpoint::new("http://127.0.0.1:1").unwrap())
+            .build_with(HyperBackend::new)
+            .unwrap();
+        let e = client
+            .send("write_key", &Message::Batch(Default::default()))
+            .unwrap_err();
+        assert!(crate::retry::is_retryable(&e), "unexpected error: {}", e);
+    }
+}
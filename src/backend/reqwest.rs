@@ -0,0 +1,67 @@
+use super::{ConnectionOptions, HttpBackend, Request, Response};
+use failure::Error;
+
+/// A backend which makes requests with `reqwest`'s blocking client.
+///
+/// This is available with the `reqwest` feature, which is enabled by
+/// default.
+#[derive(Debug, Clone)]
+pub struct ReqwestBackend {
+    client: ::reqwest::blocking::Client,
+}
+
+impl ReqwestBackend {
+    /// Construct a backend which connects according to `options`.
+    pub fn new(options: &ConnectionOptions) -> Result<ReqwestBackend, Error> {
+        let mut builder = ::reqwest::blocking::Client::builder()
+            .timeout(options.timeout)
+            .tcp_keepalive(options.tcp_keepalive);
+        if let Some(timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = options.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = options.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(ref url) = options.proxy {
+            builder = builder.proxy(::reqwest::Proxy::all(url.as_str())?);
+        }
+        for pem in &options.root_certificates {
+            builder = builder.add_root_certificate(::reqwest::Certificate::from_pem(pem)?);
+        }
+
+        Ok(ReqwestBackend {
+            client: builder.build()?,
+        })
+    }
+}
+
+impl From<::reqwest::blocking::Client> for ReqwestBackend {
+    fn from(client: ::reqwest::blocking::Client) -> ReqwestBackend {
+        ReqwestBackend { client }
+    }
+}
+
+impl HttpBackend for ReqwestBackend {
+    fn post(&self, request: Request) -> Result<Response, Error> {
+        let mut builder = self.client.post(&request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+
+        let response = builder.body(request.body).send()?;
+        Ok(Response {
+            status: response.status().as_u16(),
+            body: response.bytes()?.to_vec(),
+        })
+    }
+}
+
+pub(crate) fn is_transient(e: &::reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status.as_u16() == 429 || status.is_server_error(),
+        None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+    }
+}
@@ -0,0 +1,89 @@
+use super::{ConnectionOptions, HttpBackend, Request, Response};
+use crate::errors::Error as AnalyticsError;
+use ::ureq::{Agent, Proxy};
+use failure::Error;
+
+/// A backend which makes requests with `ureq`, a small blocking HTTP library
+/// without an async runtime.
+///
+/// This is available with the `ureq` feature. TCP keepalive and extra root
+/// certificates are not supported: `ureq` can only trust its bundled roots
+/// or a given set instead of them.
+#[derive(Debug, Clone)]
+pub struct UreqBackend {
+    agent: Agent,
+}
+
+impl UreqBackend {
+    /// Construct a backend which connects according to `options`.
+    pub fn new(options: &ConnectionOptions) -> Result<UreqBackend, Error> {
+        if options.tcp_keepalive.is_some() {
+            return Err(AnalyticsError::Unsupported {
+                option: "TCP keepalive",
+                backend: "ureq",
+            }
+            .into());
+        }
+
+        if !options.root_certificates.is_empty() {
+            return Err(AnalyticsError::Unsupported {
+                option: "root certificates",
+                backend: "ureq",
+            }
+            .into());
+        }
+
+        let mut config = Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(options.timeout)
+            .timeout_connect(options.connect_timeout);
+        if let Some(timeout) = options.pool_idle_timeout {
+            config = config.max_idle_age(timeout);
+        }
+        if let Some(max) = options.pool_max_idle {
+            config = config.max_idle_connections_per_host(max);
+        }
+        if let Some(ref url) = options.proxy {
+            config = config.proxy(Some(Proxy::new(url)?));
+        }
+
+        Ok(UreqBackend {
+            agent: config.build().new_agent(),
+        })
+    }
+}
+
+impl From<Agent> for UreqBackend {
+    /// Use an existing agent. It must be configured not to treat error
+    /// statuses as errors, with `http_status_as_error(false)`.
+    fn from(agent: Agent) -> UreqBackend {
+        UreqBackend { agent }
+    }
+}
+
+impl HttpBackend for UreqBackend {
+    fn post(&self, request: Request) -> Result<Response, Error> {
+        let mut builder = self.agent.post(&request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+
+        let mut response = builder.send(&request.body[..])?;
+        Ok(Response {
+            status: response.status().as_u16(),
+            body: response.body_mut().read_to_vec()?,
+        })
+    }
+}
+
+pub(crate) fn is_transient(e: &::ureq::Error) -> bool {
+    match e {
+        ::ureq::Error::StatusCode(status) => *status == 429 || (500..600).contains(status),
+        ::ureq::Error::Io(_)
+        | ::ureq::Error::Timeout(_)
+        | ::ureq::Error::HostNotFound
+        | ::ureq::Error::ConnectionFailed
+        | ::ureq::Error::BodyStalled => true,
+        _ => false,
+    }
+}
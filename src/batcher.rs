@@ -2,8 +2,11 @@
 
 use crate::errors::Error as AnalyticsError;
 use crate::message::{Batch, BatchMessage, Message};
+use bytes::Bytes;
 use failure::Error;
 use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 
 const MAX_MESSAGE_SIZE: usize = 1024 * 32;
 const MAX_BATCH_SIZE: usize = 1024 * 512;
@@ -13,7 +16,7 @@ const MAX_BATCH_SIZE: usize = 1024 * 512;
 ///
 /// The recommended usage pattern looks something like this:
 ///
-/// ```
+/// ```no_run
 /// use analytics::batcher::Batcher;
 /// use analytics::client::Client;
 /// use analytics::http::HttpClient;
@@ -34,14 +37,19 @@ const MAX_BATCH_SIZE: usize = 1024 * 512;
 ///     // Batcher returns back ownership of a message if the internal buffer
 ///     // would overflow.
 ///     //
-///     // When this occurs, we flush the batcher, create a new batcher, and add
-///     // the message into the new batcher.
+///     // When this occurs, we flush the batcher and add the message back in.
 ///     if let Some(msg) = batcher.push(msg).unwrap() {
-///         client.send("your_write_key", &batcher.into_message()).unwrap();
-///         batcher = Batcher::new(None);
+///         if let Some(batch) = batcher.flush() {
+///             client.send("your_write_key", &batch).unwrap();
+///         }
 ///         batcher.push(msg).unwrap();
 ///     }
 /// }
+///
+/// // Don't forget to send whatever is left over.
+/// if let Some(batch) = batcher.flush() {
+///     client.send("your_write_key", &batch).unwrap();
+/// }
 /// ```
 ///
 /// Batcher will attempt to fit messages into maximally-sized batches, thus
@@ -50,10 +58,16 @@ const MAX_BATCH_SIZE: usize = 1024 * 512;
 /// the sending of messages to Segment.
 ///
 /// If this delay is a concern, it is recommended that you periodically flush
-/// the batcher on your own by calling `into_message`.
+/// the batcher on your own by calling `flush`. The `len`, `is_empty` and
+/// `byte_size` methods can help decide when doing so is worthwhile.
+///
+/// Every message is serialized exactly once, when it is pushed. When sending
+/// large volumes of events, prefer `flush_serialized` together with
+/// [`Client::send_serialized`](../client/trait.Client.html#method.send_serialized),
+/// which sends those bytes as-is instead of serializing the batch again.
 pub struct Batcher {
     buf: Vec<BatchMessage>,
-    byte_count: usize,
+    serialized: Vec<u8>,
     context: Option<Value>,
 }
 
@@ -61,11 +75,11 @@ impl Batcher {
     /// Construct a new, empty batcher.
     ///
     /// Optionally, you may specify a `context` that should be set on every
-    /// batch returned by `into_message`.
+    /// batch returned by `flush` or `into_message`.
     pub fn new(context: Option<Value>) -> Self {
         Self {
             buf: Vec::new(),
-            byte_count: 0,
+            serialized: Vec::new(),
             context,
         }
     }
@@ -83,13 +97,25 @@ impl Batcher {
     /// Returns an error if the message is too large to be sent to Segment's
     /// API.
     pub fn push(&mut self, msg: BatchMessage) -> Result<Option<BatchMessage>, Error> {
-        let size = serde_json::to_vec(&msg)?.len();
-        if size > MAX_MESSAGE_SIZE {
+        // Serialize straight onto the end of the batch, and back the message
+        // out again if it doesn't fit.
+        let start = self.serialized.len();
+        if start > 0 {
+            self.serialized.push(b',');
+        }
+        let msg_start = self.serialized.len();
+        if let Err(e) = serde_json::to_writer(&mut self.serialized, &msg) {
+            self.serialized.truncate(start);
+            return Err(e.into());
+        }
+
+        if self.serialized.len() - msg_start > MAX_MESSAGE_SIZE {
+            self.serialized.truncate(start);
             return Err(AnalyticsError::MessageTooLarge.into());
         }
 
-        self.byte_count += size + 1; // +1 to account for Serialized data's extra commas
-        if self.byte_count > MAX_BATCH_SIZE {
+        if self.serialized.len() > MAX_BATCH_SIZE {
+            self.serialized.truncate(start);
             return Ok(Some(msg));
         }
 
@@ -97,6 +123,94 @@ impl Batcher {
         Ok(None)
     }
 
+    /// Like `push`, but for a message already serialized by `serialize`.
+    pub(crate) fn push_serialized(
+        &mut self,
+        msg: BatchMessage,
+        serialized: &[u8],
+    ) -> Result<Option<BatchMessage>, Error> {
+        if serialized.len() > MAX_MESSAGE_SIZE {
+            return Err(AnalyticsError::MessageTooLarge.into());
+        }
+        let separator = usize::from(!self.serialized.is_empty());
+        if self.serialized.len() + separator + serialized.len() > MAX_BATCH_SIZE {
+            return Ok(Some(msg));
+        }
+
+        if separator > 0 {
+            self.serialized.push(b',');
+        }
+        self.serialized.extend_from_slice(serialized);
+        self.buf.push(msg);
+        Ok(None)
+    }
+
+    /// Drains the batcher into a message that can be sent to Segment, leaving
+    /// it empty and ready to accept more messages.
+    ///
+    /// The batcher's `context`, if any, is retained and set on every batch.
+    /// Returns `None` if there is nothing to flush.
+    pub fn flush(&mut self) -> Option<Message> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        self.serialized.clear();
+        Some(Message::Batch(Batch {
+            batch: std::mem::take(&mut self.buf),
+            context: self.context.clone(),
+            integrations: None,
+            extra: Map::default(),
+        }))
+    }
+
+    /// Drains the batcher into the serialized body of a batch request, leaving
+    /// it empty and ready to accept more messages.
+    ///
+    /// Unlike `flush`, this reuses the bytes produced when each message was
+    /// pushed rather than serializing the batch again. Returns `None` if there
+    /// is nothing to flush.
+    pub fn flush_serialized(&mut self) -> Option<SerializedBatch> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let len = self.buf.len();
+        self.buf.clear();
+
+        let mut body = Vec::with_capacity(self.serialized.len() + 32);
+        body.extend_from_slice(br#"{"batch":["#);
+        body.append(&mut self.serialized);
+        body.push(b']');
+        if let Some(ref context) = self.context {
+            body.extend_from_slice(br#","context":"#);
+            // Writing a `Value` into a `Vec` cannot fail.
+            serde_json::to_writer(&mut body, context).unwrap();
+        }
+        body.push(b'}');
+
+        Some(SerializedBatch {
+            body: body.into(),
+            len,
+        })
+    }
+
+    /// Returns the number of messages currently buffered.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns `true` if no messages are currently buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns the approximate serialized size, in bytes, of the messages
+    /// currently buffered.
+    pub fn byte_size(&self) -> usize {
+        self.serialized.len()
+    }
+
     /// Consumes this batcher and converts it into a message that can be sent to
     /// Segment.
     pub fn into_message(self) -> Message {
@@ -109,6 +223,252 @@ impl Batcher {
     }
 }
 
+/// Serialize a message as it would appear in a batch, failing if it is too
+/// large to send.
+pub(crate) fn serialize(msg: &BatchMessage) -> Result<Vec<u8>, Error> {
+    let serialized = serde_json::to_vec(msg)?;
+    if serialized.len() > MAX_MESSAGE_SIZE {
+        return Err(AnalyticsError::MessageTooLarge.into());
+    }
+    Ok(serialized)
+}
+
+/// A set of batchers, one per write key, for services which send messages on
+/// behalf of many Segment sources.
+///
+/// Each write key's messages are batched and flushed independently. At most
+/// `max_keys` write keys are buffered at once; pushing a message for a new
+/// write key beyond that flushes and forgets the least recently used one.
+///
+/// Rather than handing messages back when a batch is full, `KeyedBatcher`
+/// flushes the batch itself and returns it, along with its write key, to be
+/// sent:
+///
+/// ```no_run
+/// use analytics::batcher::KeyedBatcher;
+/// use analytics::client::Client;
+/// use analytics::http::HttpClient;
+/// use analytics::message::{BatchMessage, Track, User};
+/// use std::time::Duration;
+///
+/// let client = HttpClient::default();
+/// let mut batcher = KeyedBatcher::new(None, 1000);
+///
+/// # let incoming: Vec<(String, BatchMessage)> = vec![];
+/// for (write_key, msg) in incoming {
+///     for (write_key, batch) in batcher.push(&write_key, msg).unwrap() {
+///         client.send_serialized(&write_key, &batch).unwrap();
+///     }
+///
+///     // Don't hold any tenant's messages for more than a second.
+///     for (write_key, batch) in batcher.flush_expired(Duration::from_secs(1)) {
+///         client.send_serialized(&write_key, &batch).unwrap();
+///     }
+/// }
+///
+/// for (write_key, batch) in batcher.flush_all() {
+///     client.send_serialized(&write_key, &batch).unwrap();
+/// }
+/// ```
+pub struct KeyedBatcher {
+    batchers: HashMap<String, Keyed>,
+    /// The write keys in `batchers`, by when they were last used.
+    recency: BTreeMap<u64, String>,
+    context: Option<Value>,
+    max_keys: usize,
+    clock: u64,
+}
+
+/// A write key's batcher, and when it was last used.
+struct Keyed {
+    batcher: Batcher,
+    last_used: u64,
+    oldest: Option<Instant>,
+}
+
+impl KeyedBatcher {
+    /// Construct a new, empty batcher holding messages for at most `max_keys`
+    /// write keys at once.
+    ///
+    /// As with `Batcher::new`, a `context` may be given to set on every
+    /// batch.
+    pub fn new(context: Option<Value>, max_keys: usize) -> Self {
+        Self {
+            batchers: HashMap::new(),
+            recency: BTreeMap::new(),
+            context,
+            max_keys: max_keys.max(1),
+            clock: 0,
+        }
+    }
+
+    /// Push a message for `write_key` into its batcher.
+    ///
+    /// Returns any batches which must be sent now: the write key's current
+    /// batch if the message did not fit in it, and the batch of the least
+    /// recently used write key if making room for `write_key` evicted it.
+    ///
+    /// Returns an error if the message is too large to be sent to Segment's
+    /// API.
+    pub fn push(
+        &mut self,
+        write_key: &str,
+        msg: BatchMessage,
+    ) -> Result<Vec<(String, SerializedBatch)>, Error> {
+        let mut flushed = Vec::new();
+
+        self.clock += 1;
+        let context = &self.context;
+        let keyed = self
+            .batchers
+            .entry(write_key.to_owned())
+            .or_insert_with(|| Keyed {
+                batcher: Batcher::new(context.clone()),
+                last_used: 0,
+                oldest: None,
+            });
+        self.recency.remove(&keyed.last_used);
+        keyed.last_used = self.clock;
+        self.recency.insert(self.clock, write_key.to_owned());
+
+        match keyed.batcher.push(msg) {
+            Ok(None) => {}
+            Ok(Some(msg)) => {
+                flushed.extend(
+                    keyed
+                        .batcher
+                        .flush_serialized()
+                        .map(|batch| (write_key.to_owned(), batch)),
+                );
+                keyed.oldest = None;
+                // The message fit on its own before, so fits in an empty batch.
+                keyed.batcher.push(msg)?;
+            }
+            Err(e) => {
+                if keyed.batcher.is_empty() {
+                    self.flush(write_key);
+                }
+                return Err(e);
+            }
+        }
+        keyed.oldest.get_or_insert_with(Instant::now);
+
+        if self.batchers.len() > self.max_keys {
+            let lru = self.recency.values().next().cloned();
+            if let Some(lru) = lru {
+                flushed.extend(self.flush(&lru).map(|batch| (lru, batch)));
+            }
+        }
+
+        Ok(flushed)
+    }
+
+    /// Flush the batch for `write_key`, forgetting the write key until it is
+    /// pushed to again. Returns `None` if there is nothing to flush.
+    pub fn flush(&mut self, write_key: &str) -> Option<SerializedBatch> {
+        let mut keyed = self.batchers.remove(write_key)?;
+        self.recency.remove(&keyed.last_used);
+        keyed.batcher.flush_serialized()
+    }
+
+    /// Flush the batches of write keys whose oldest buffered message was
+    /// pushed at least `max_age` ago.
+    pub fn flush_expired(&mut self, max_age: Duration) -> Vec<(String, SerializedBatch)> {
+        let expired: Vec<_> = self
+            .batchers
+            .iter()
+            .filter(|(_, keyed)| keyed.oldest.is_some_and(|t| t.elapsed() >= max_age))
+            .map(|(write_key, _)| write_key.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|write_key| self.flush(&write_key).map(|batch| (write_key, batch)))
+            .collect()
+    }
+
+    /// Flush the batches of every write key.
+    pub fn flush_all(&mut self) -> Vec<(String, SerializedBatch)> {
+        self.recency.clear();
+        self.batchers
+            .drain()
+            .filter_map(|(write_key, mut keyed)| {
+                keyed
+                    .batcher
+                    .flush_serialized()
+                    .map(|batch| (write_key, batch))
+            })
+            .collect()
+    }
+
+    /// Returns the batcher for `write_key`, if it has buffered messages.
+    pub fn get(&self, write_key: &str) -> Option<&Batcher> {
+        self.batchers.get(write_key).map(|keyed| &keyed.batcher)
+    }
+
+    /// Returns the number of write keys with buffered messages.
+    pub fn len(&self) -> usize {
+        self.batchers.len()
+    }
+
+    /// Returns `true` if no messages are buffered for any write key.
+    pub fn is_empty(&self) -> bool {
+        self.batchers.is_empty()
+    }
+}
+
+/// The serialized body of a batch request, as produced by
+/// `Batcher::flush_serialized`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerializedBatch {
+    body: Bytes,
+    len: usize,
+}
+
+impl SerializedBatch {
+    /// Reassemble a batch from a body previously returned by `as_bytes` or
+    /// `into_bytes`, and the number of messages it contains.
+    pub(crate) fn from_parts(body: Vec<u8>, len: usize) -> SerializedBatch {
+        SerializedBatch {
+            body: body.into(),
+            len,
+        }
+    }
+
+    /// Returns the number of messages in this batch.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if this batch contains no messages.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the JSON body of this batch.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Returns the JSON body of this batch, sharing rather than copying it.
+    pub fn body(&self) -> Bytes {
+        self.body.clone()
+    }
+
+    /// Consumes this batch, returning its JSON body.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.body.into()
+    }
+
+    /// Decodes this batch back into a message.
+    ///
+    /// This is relatively expensive, and is only intended for clients which
+    /// cannot send the serialized body directly.
+    pub fn to_message(&self) -> Result<Message, Error> {
+        Ok(Message::Batch(serde_json::from_slice(&self.body)?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -150,13 +510,14 @@ mod tests {
         });
 
         let mut batcher = Batcher::new(None);
-        let result = batcher.push(batch_msg.into());
+        let result = batcher.push(batch_msg);
 
         let err = result.err().unwrap();
         let err: &AnalyticsError = err.as_fail().downcast_ref().unwrap();
 
         match err {
             AnalyticsError::MessageTooLarge => {}
+            _ => panic!("unexpected error: {}", err),
         }
     }
 
@@ -172,7 +533,7 @@ mod tests {
         let mut batcher = Batcher::new(None);
         let mut result = Ok(None);
         for _i in 0..20 {
-            result = batcher.push(batch_msg.clone().into());
+            result = batcher.push(batch_msg.clone());
             dbg!(&result);
             if result.is_ok() && result.as_ref().ok().unwrap().is_some() {
                 break;
@@ -182,4 +543,181 @@ mod tests {
         let msg = result.ok().unwrap();
         assert_eq!(batch_msg, msg.unwrap());
     }
+
+    #[test]
+    fn test_flush_reuses_batcher() {
+        let batch_msg = BatchMessage::Track(Track {
+            ..Default::default()
+        });
+
+        let context = json!({
+            "foo": "bar",
+        });
+
+        let mut batcher = Batcher::new(Some(context.clone()));
+        assert!(batcher.is_empty());
+        assert_eq!(None, batcher.flush());
+
+        batcher.push(batch_msg.clone()).unwrap();
+        batcher.push(batch_msg.clone()).unwrap();
+        assert_eq!(2, batcher.len());
+        assert!(batcher.byte_size() > 0);
+
+        let inner_batch = match batcher.flush().unwrap() {
+            Message::Batch(b) => b,
+            _ => panic!("invalid message type"),
+        };
+        assert_eq!(context, inner_batch.context.unwrap());
+        assert_eq!(
+            inner_batch.batch,
+            vec![batch_msg.clone(), batch_msg.clone()]
+        );
+
+        assert!(batcher.is_empty());
+        assert_eq!(0, batcher.byte_size());
+
+        batcher.push(batch_msg.clone()).unwrap();
+        let inner_batch = match batcher.flush().unwrap() {
+            Message::Batch(b) => b,
+            _ => panic!("invalid message type"),
+        };
+        assert_eq!(context, inner_batch.context.unwrap());
+        assert_eq!(inner_batch.batch, vec![batch_msg]);
+    }
+
+    #[test]
+    fn test_rejected_push_keeps_byte_count() {
+        let batch_msg = BatchMessage::Track(Track {
+            user: User::UserId {
+                user_id: String::from_utf8(vec![b'a'; 1024 * 30]).unwrap(),
+            },
+            ..Default::default()
+        });
+
+        let mut batcher = Batcher::new(None);
+        while batcher.push(batch_msg.clone()).unwrap().is_none() {}
+
+        let byte_size = batcher.byte_size();
+        assert!(byte_size <= MAX_BATCH_SIZE);
+        assert!(batcher.push(batch_msg).unwrap().is_some());
+        assert_eq!(byte_size, batcher.byte_size());
+    }
+
+    #[test]
+    fn test_flush_serialized() {
+        let batch_msg = BatchMessage::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            properties: json!({}),
+            ..Default::default()
+        });
+
+        let mut batcher = Batcher::new(Some(json!({ "foo": "bar" })));
+        assert_eq!(None, batcher.flush_serialized());
+
+        batcher.push(batch_msg.clone()).unwrap();
+        batcher.push(batch_msg.clone()).unwrap();
+
+        let expected = Message::Batch(Batch {
+            batch: vec![batch_msg.clone(), batch_msg.clone()],
+            context: Some(json!({ "foo": "bar" })),
+            ..Default::default()
+        });
+        let batch = batcher.flush_serialized().unwrap();
+        assert_eq!(2, batch.len());
+        assert_eq!(
+            serde_json::to_vec(&expected).unwrap(),
+            batch.as_bytes().to_vec()
+        );
+        assert_eq!(expected, batch.to_message().unwrap());
+
+        assert!(batcher.is_empty());
+        assert_eq!(0, batcher.byte_size());
+
+        batcher.push(batch_msg).unwrap();
+        assert_eq!(1, batcher.flush_serialized().unwrap().len());
+    }
+
+    #[test]
+    fn test_keyed_batcher() {
+        let track = |user_id: &str| {
+            BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: user_id.to_owned(),
+                },
+                event: "Foo".to_owned(),
+                properties: json!({}),
+                ..Default::default()
+            })
+        };
+        let users = |batch: &SerializedBatch| match batch.to_message().unwrap() {
+            Message::Batch(b) => b
+                .batch
+                .iter()
+                .map(|msg| msg.user().user_id().unwrap().to_owned())
+                .collect::<Vec<_>>(),
+            _ => panic!("invalid message type"),
+        };
+
+        let mut batcher = KeyedBatcher::new(None, 2);
+        assert!(batcher.push("a", track("a1")).unwrap().is_empty());
+        assert!(batcher.push("b", track("b1")).unwrap().is_empty());
+        assert!(batcher.push("a", track("a2")).unwrap().is_empty());
+        assert_eq!(2, batcher.len());
+        assert_eq!(2, batcher.get("a").unwrap().len());
+
+        // "b" is the least recently used, so makes room for "c".
+        let flushed = batcher.push("c", track("c1")).unwrap();
+        assert_eq!(1, flushed.len());
+        assert_eq!("b", flushed[0].0);
+        assert_eq!(vec!["b1"], users(&flushed[0].1));
+        assert!(batcher.get("b").is_none());
+
+        let batch = batcher.flush("a").unwrap();
+        assert_eq!(vec!["a1", "a2"], users(&batch));
+        assert_eq!(None, batcher.flush("a"));
+
+        assert!(batcher.flush_expired(Duration::from_secs(60)).is_empty());
+        let flushed = batcher.flush_expired(Duration::from_secs(0));
+        assert_eq!(1, flushed.len());
+        assert_eq!("c", flushed[0].0);
+        assert!(batcher.is_empty());
+    }
+
+    #[test]
+    fn test_keyed_batcher_overflow() {
+        let batch_msg = BatchMessage::Track(Track {
+            user: User::UserId {
+                user_id: String::from_utf8(vec![b'a'; 1024 * 30]).unwrap(),
+            },
+            ..Default::default()
+        });
+
+        let mut batcher = KeyedBatcher::new(None, 10);
+        batcher.push("b", batch_msg.clone()).unwrap();
+
+        let mut pushed = 0;
+        let flushed = loop {
+            let flushed = batcher.push("a", batch_msg.clone()).unwrap();
+            pushed += 1;
+            if !flushed.is_empty() {
+                break flushed;
+            }
+        };
+        assert_eq!(1, flushed.len());
+        assert_eq!("a", flushed[0].0);
+        assert_eq!(pushed - 1, flushed[0].1.len());
+        assert_eq!(1, batcher.get("a").unwrap().len());
+        assert_eq!(1, batcher.get("b").unwrap().len());
+
+        let mut flushed = batcher.flush_all();
+        flushed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            vec![1, 1],
+            flushed.iter().map(|(_, b)| b.len()).collect::<Vec<_>>()
+        );
+        assert!(batcher.is_empty());
+    }
 }
@@ -0,0 +1,115 @@
+//! Delivery of spooled batches upstream.
+
+use analytics::client::Client;
+use analytics::retry::{self, RetryPolicy};
+use analytics::spool::Spool;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for new batches before checking the spool anyway.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A write key whose batches failed, and are held back until `until`.
+struct Backoff {
+    failures: u32,
+    until: Instant,
+}
+
+/// Send spooled batches upstream, stopping once `notified` is disconnected
+/// while there is nothing to send yet.
+///
+/// Each batch is sent according to `policy`. Batches which fail with an error
+/// that retrying cannot fix are moved aside into the spool's `dead`
+/// directory; otherwise, the batch is kept and retried after backing off, so
+/// that nothing is lost while the upstream is unavailable.
+///
+/// Backing off is per write key: while one key's batches are held back,
+/// they are moved to the back of the spool, so that other keys' batches are
+/// still sent. Each key's batches are otherwise sent in order.
+pub fn run<C: Client>(client: C, policy: RetryPolicy, spool: &Spool, notified: &Receiver<()>) {
+    let mut backoffs: HashMap<String, Backoff> = HashMap::new();
+    // The number of batches passed over in a row because of their key.
+    let mut skipped = 0;
+
+    loop {
+        let spooled = match spool.peek() {
+            Ok(Some(spooled)) => spooled,
+            Ok(None) => match notified.recv_timeout(POLL_INTERVAL) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            Err(e) => {
+                eprintln!("could not read spool: {}", e);
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+        if let Some(backoff) = backoffs.get(&spooled.write_key) {
+            if backoff.until > now {
+                skipped += 1;
+                let outcome = if skipped >= spool.len().unwrap_or(0) {
+                    // Every batch is held back, so wait for the first key to
+                    // be ready, or for a new batch.
+                    skipped = 0;
+                    let until = backoffs.values().map(|b| b.until).min().unwrap_or(now);
+                    match notified.recv_timeout(until.saturating_duration_since(now)) {
+                        Ok(()) | Err(RecvTimeoutError::Timeout) => Ok(()),
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                } else {
+                    spool.requeue(&spooled)
+                };
+                if let Err(e) = outcome {
+                    eprintln!("could not update spool: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+                continue;
+            }
+        }
+        skipped = 0;
+
+        let result = policy.run(|_| client.send_serialized(&spooled.write_key, &spooled.batch));
+        let outcome = match result {
+            Ok(()) => {
+                backoffs.remove(&spooled.write_key);
+                spool.remove(&spooled)
+            }
+            Err((e, attempts)) if retry::is_retryable(&e) => {
+                let backoff = backoffs
+                    .entry(spooled.write_key.clone())
+                    .or_insert(Backoff {
+                        failures: 0,
+                        until: now,
+                    });
+                backoff.failures += 1;
+                let delay = policy.backoff(policy.max_attempts + backoff.failures);
+                backoff.until = Instant::now() + delay;
+                eprintln!(
+                    "could not forward batch of {} after {} attempts, retrying in {:?}: {}",
+                    spooled.batch.len(),
+                    attempts,
+                    delay,
+                    e
+                );
+                Ok(())
+            }
+            Err((e, _)) => {
+                eprintln!(
+                    "dropping batch of {} into the dead directory: {}",
+                    spooled.batch.len(),
+                    e
+                );
+                spool.bury(&spooled)
+            }
+        };
+
+        if let Err(e) = outcome {
+            eprintln!("could not update spool: {}", e);
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! A Segment-compatible ingestion proxy.
+//!
+//! The proxy accepts the same requests as Segment's tracking API, re-batches
+//! the messages it receives across all of its clients, and forwards them
+//! upstream. Full batches are persisted to a spool directory before being
+//! sent, and are retried until the upstream accepts them, so upstream outages
+//! delay delivery rather than losing messages.
+//!
+//! Messages are held in memory for up to `--flush-interval` before being
+//! spooled; only those can be lost if the proxy itself crashes. While batches
+//! can't be spooled, such as because the disk is full, requests are refused
+//! with a `503` so that clients retry them. A write key whose batches keep
+//! failing upstream is backed off on its own, without holding up the others.
+
+mod forwarder;
+
+use analytics::batcher::{Batcher, KeyedBatcher, SerializedBatch};
+use analytics::endpoint::{Endpoint, Region};
+use analytics::http::HttpClient;
+use analytics::ingest;
+use analytics::message::Message;
+use analytics::retry::RetryPolicy;
+use analytics::spool::Spool;
+use clap::{App, AppSettings, Arg};
+use failure::{err_msg, Error};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Request, Server};
+
+fn main() -> Result<(), Error> {
+    let matches = App::new("Analytics Proxy")
+        .version("0.1")
+        .author("Segment <friends@segment.com>")
+        .about("Accepts tracking API requests, re-batches them and forwards them to Segment")
+        .setting(AppSettings::ColoredHelp)
+        .arg(
+            Arg::with_name("bind")
+                .help("Address to listen on")
+                .takes_value(true)
+                .long("bind")
+                .default_value("127.0.0.1"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .help("Port to listen on")
+                .takes_value(true)
+                .short("p")
+                .long("port")
+                .default_value("8080"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .help("Number of threads handling requests")
+                .takes_value(true)
+                .long("threads")
+                .default_value("4"),
+        )
+        .arg(
+            Arg::with_name("host")
+                .help("Scheme, host and optional path prefix to forward to")
+                .takes_value(true)
+                .long("host")
+                .conflicts_with("region"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .help("Segment region to forward to [default: us]")
+                .takes_value(true)
+                .possible_values(&["us", "eu"])
+                .long("region"),
+        )
+        .arg(
+            Arg::with_name("spool-dir")
+                .help("Directory to persist batches to until they are delivered")
+                .takes_value(true)
+                .long("spool-dir")
+                .default_value("analytics-spool"),
+        )
+        .arg(
+            Arg::with_name("flush-interval")
+                .help("Longest time, in milliseconds, to hold a partial batch before spooling it")
+                .takes_value(true)
+                .long("flush-interval")
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::with_name("max-write-keys")
+                .help("Most write keys to hold partial batches for at once")
+                .takes_value(true)
+                .long("max-write-keys")
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::with_name("max-attempts")
+                .help("Attempts to make at sending a batch before backing off")
+                .takes_value(true)
+                .long("max-attempts")
+                .default_value("5"),
+        )
+        .get_matches();
+
+    let endpoint = match (matches.value_of("host"), matches.value_of("region")) {
+        (Some(host), _) => Endpoint::new(host)?,
+        (None, Some(region)) => Endpoint::from(region.parse::<Region>()?),
+        (None, None) => Endpoint::default(),
+    };
+    let client = HttpClient::builder()
+        .with_endpoint(endpoint.clone())
+        .build()?;
+
+    let threads: usize = matches.value_of("threads").unwrap().parse()?;
+    let flush_interval =
+        Duration::from_millis(matches.value_of("flush-interval").unwrap().parse()?);
+    let max_write_keys = matches.value_of("max-write-keys").unwrap().parse()?;
+    let policy = RetryPolicy {
+        max_attempts: matches.value_of("max-attempts").unwrap().parse()?,
+        ..Default::default()
+    };
+
+    let spool = Arc::new(Spool::open(matches.value_of("spool-dir").unwrap())?);
+    let (notify, notified) = mpsc::channel();
+    let proxy = Arc::new(Proxy {
+        batchers: Mutex::new(KeyedBatcher::new(None, max_write_keys)),
+        pending: Mutex::new(VecDeque::new()),
+        spool: Arc::clone(&spool),
+        notify: Mutex::new(notify),
+    });
+
+    thread::spawn(move || forwarder::run(client, policy, &spool, &notified));
+
+    {
+        let proxy = Arc::clone(&proxy);
+        thread::spawn(move || loop {
+            thread::sleep(flush_interval / 4);
+            if let Err(e) = proxy.flush_expired(flush_interval) {
+                eprintln!("could not spool batches: {}", e);
+            }
+        });
+    }
+
+    let addr = format!(
+        "{}:{}",
+        matches.value_of("bind").unwrap(),
+        matches.value_of("port").unwrap()
+    );
+    let server = Arc::new(
+        Server::http(&addr).map_err(|e| err_msg(format!("could not listen on {}: {}", addr, e)))?,
+    );
+    eprintln!("listening on http://{}, forwarding to {}", addr, endpoint);
+
+    let handlers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let proxy = Arc::clone(&proxy);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(e) = handle(request, &proxy) {
+                        eprintln!("error: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handler in handlers {
+        let _ = handler.join();
+    }
+    Ok(())
+}
+
+/// The messages accepted by the proxy which have yet to be spooled, batched
+/// by write key.
+struct Proxy {
+    batchers: Mutex<KeyedBatcher>,
+    /// Batches flushed from `batchers` which have yet to be written to the
+    /// spool, in order. Only non-empty while spooling is failing.
+    pending: Mutex<VecDeque<(String, SerializedBatch)>>,
+    spool: Arc<Spool>,
+    notify: Mutex<Sender<()>>,
+}
+
+impl Proxy {
+    /// Accept every message of a request, or none of them, so that a client
+    /// retrying a rejected request can't duplicate part of it.
+    fn accept(&self, write_key: &str, message: Message) -> Result<(), Error> {
+        let messages = message.into_batch_messages();
+        // Check that every message fits in a batch before accepting any.
+        for msg in &messages {
+            Batcher::new(None).push(msg.clone())?;
+        }
+
+        // While batches can't be spooled, such as because the disk is full,
+        // refuse more messages rather than holding ever more in memory.
+        self.spool_pending()?;
+
+        let mut flushed = Vec::new();
+        {
+            let mut batchers = self.batchers.lock().unwrap();
+            for msg in messages {
+                flushed.extend(batchers.push(write_key, msg)?);
+            }
+        }
+
+        // The messages are accepted now; batches which fail to spool are
+        // retried along with the next request or flush.
+        if let Err(e) = self.spool(flushed) {
+            eprintln!("could not spool batches: {}", e);
+        }
+        Ok(())
+    }
+
+    fn flush_expired(&self, max_age: Duration) -> Result<(), Error> {
+        let flushed = self.batchers.lock().unwrap().flush_expired(max_age);
+        self.spool(flushed)
+    }
+
+    fn spool(&self, batches: Vec<(String, SerializedBatch)>) -> Result<(), Error> {
+        self.pending.lock().unwrap().extend(batches);
+        self.spool_pending()
+    }
+
+    /// Write every pending batch to the spool, stopping at the first which
+    /// fails.
+    fn spool_pending(&self) -> Result<(), Error> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        while let Some((write_key, batch)) = pending.front() {
+            if let Err(e) = self.spool.push(write_key, batch) {
+                result = Err(e);
+                break;
+            }
+            pending.pop_front();
+        }
+        let _ = self.notify.lock().unwrap().send(());
+        result
+    }
+}
+
+fn handle(mut request: Request, proxy: &Proxy) -> Result<(), Error> {
+    let ingest::TrackingRequest {
+        write_key, message, ..
+    } = ingest::read_request(&mut request);
+
+    let write_key = match write_key {
+        Some(write_key) => write_key,
+        None => return ingest::respond(request, 401, Some("missing write key")),
+    };
+
+    match message.and_then(|message| proxy.accept(&write_key, message)) {
+        Ok(()) => ingest::respond(request, 200, None),
+        // Only an invalid request is the client's fault; otherwise the proxy
+        // couldn't store it, and the client should retry.
+        Err(e) => ingest::respond(request, ingest::error_status(&e), Some(&e.to_string())),
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use analytics::client::Client;
+    use analytics::errors::Error as AnalyticsError;
+    use analytics::message::{Track, User};
+    use analytics::testing::MockServer;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::mpsc::Receiver;
+    use std::time::Instant;
+
+    fn track(user_id: &str) -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: user_id.to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    /// Start a proxy which holds batches for a single write key at a time,
+    /// returning a client which sends to it.
+    fn start(dir: &PathBuf) -> (Arc<Proxy>, HttpClient, Receiver<()>) {
+        let (notify, notified) = mpsc::channel();
+        let proxy = Arc::new(Proxy {
+            batchers: Mutex::new(KeyedBatcher::new(None, 1)),
+            pending: Mutex::new(VecDeque::new()),
+            spool: Arc::new(Spool::open(dir).unwrap()),
+            notify: Mutex::new(notify),
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::new(&format!("http://{}", server.server_addr())).unwrap();
+        {
+            let proxy = Arc::clone(&proxy);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, &proxy).unwrap();
+                }
+            });
+        }
+
+        let client = HttpClient::builder()
+            .with_endpoint(endpoint)
+            .build()
+            .unwrap();
+        (proxy, client, notified)
+    }
+
+    fn status(result: Result<(), Error>) -> u16 {
+        match result {
+            Ok(()) => 200,
+            Err(e) => match e.downcast_ref::<AnalyticsError>() {
+                Some(AnalyticsError::Status { status, .. }) => *status,
+                _ => panic!("unexpected error: {}", e),
+            },
+        }
+    }
+
+    #[test]
+    fn test_forwards_spooled_batches() {
+        let dir = std::env::temp_dir().join(format!("analytics-proxy-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (proxy, client, notified) = start(&dir);
+
+        assert_eq!(200, status(client.send("a", &track("foo"))));
+        assert_eq!(200, status(client.send("a", &track("bar"))));
+        let too_large = Message::Track(Track {
+            user: User::UserId {
+                user_id: "a".repeat(1024 * 33),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(400, status(client.send("a", &too_large)));
+        // Making room for another write key spools the first's batch.
+        assert_eq!(200, status(client.send("b", &track("baz"))));
+        assert_eq!(1, proxy.spool.len().unwrap());
+
+        let upstream = MockServer::with_write_key("a");
+        {
+            let spool = Arc::clone(&proxy.spool);
+            let client = upstream.client();
+            thread::spawn(move || {
+                forwarder::run(client, RetryPolicy::default(), &spool, &notified)
+            });
+        }
+
+        let start = Instant::now();
+        while !proxy.spool.is_empty().unwrap() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "batch not forwarded"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            vec![track("foo"), track("bar")]
+                .into_iter()
+                .flat_map(Message::into_batch_messages)
+                .collect::<Vec<_>>(),
+            upstream.batch_messages()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_requests_while_spooling_fails() {
+        let dir = std::env::temp_dir().join(format!("analytics-proxy-full-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (proxy, client, _notified) = start(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The first key's batch can't be spooled, but its messages were
+        // accepted, so the request which flushed it still succeeds.
+        assert_eq!(200, status(client.send("a", &track("foo"))));
+        assert_eq!(200, status(client.send("b", &track("bar"))));
+        assert_eq!(1, proxy.pending.lock().unwrap().len());
+        assert_eq!(503, status(client.send("c", &track("baz"))));
+
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(200, status(client.send("c", &track("baz"))));
+        assert!(proxy.pending.lock().unwrap().is_empty());
+        assert_eq!(2, proxy.spool.len().unwrap());
+        // The refused request wasn't accepted, so retrying it didn't
+        // duplicate its message.
+        assert_eq!(1, proxy.batchers.lock().unwrap().get("c").unwrap().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Fails every send with one write key, and records the others.
+    struct FailingKey(&'static str, analytics::testing::MemoryClient);
+
+    impl Client for FailingKey {
+        fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+            if write_key == self.0 {
+                return Err(AnalyticsError::Status {
+                    status: 503,
+                    response: Default::default(),
+                }
+                .into());
+            }
+            self.1.send(write_key, msg)
+        }
+    }
+
+    #[test]
+    fn test_failing_key_doesnt_hold_up_others() {
+        let dir = std::env::temp_dir().join(format!("analytics-proxy-keys-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let spool = Arc::new(Spool::open(&dir).unwrap());
+        for (write_key, user_id) in &[("bad", "foo"), ("good", "bar"), ("good", "baz")] {
+            let mut batcher = Batcher::new(None);
+            for msg in track(user_id).into_batch_messages() {
+                batcher.push(msg).unwrap();
+            }
+            spool
+                .push(write_key, &batcher.flush_serialized().unwrap())
+                .unwrap();
+        }
+
+        let client = Arc::new(FailingKey("bad", Default::default()));
+        let (notify, notified) = mpsc::channel::<()>();
+        let forwarder = {
+            let spool = Arc::clone(&spool);
+            let client = Arc::clone(&client);
+            thread::spawn(move || forwarder::run(client, RetryPolicy::none(), &spool, &notified))
+        };
+
+        let start = Instant::now();
+        while client.1.len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5), "batches held up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(2, client.1.messages_for("good").len());
+        // The failing batch is kept to be retried.
+        assert_eq!(1, spool.len().unwrap());
+        assert_eq!("bad", spool.peek().unwrap().unwrap().write_key);
+
+        drop(notify);
+        forwarder.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+//! Settings read from the CLI's configuration file.
+
+use analytics::endpoint::{Endpoint, Region};
+use failure::{Error, ResultExt};
+use serde::Deserialize;
+use std::env;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// The contents of a configuration file, such as:
+///
+/// ```json
+/// { "write_key": "YOUR_WRITE_KEY", "region": "eu" }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub write_key: Option<String>,
+    pub host: Option<String>,
+    pub region: Option<String>,
+}
+
+impl Config {
+    /// Load the configuration file at `path`.
+    ///
+    /// If no path was given explicitly, the default location is tried instead,
+    /// and an empty configuration is returned if nothing is there.
+    pub fn load(path: Option<&str>) -> Result<Config, Error> {
+        match path {
+            Some(path) => Config::read(Path::new(path)),
+            None => match default_path() {
+                Some(path) => {
+                    Config::read(&path).or_else(|e| match e.downcast_ref::<std::io::Error>() {
+                        Some(io) if io.kind() == ErrorKind::NotFound => Ok(Config::default()),
+                        _ => Err(e),
+                    })
+                }
+                None => Ok(Config::default()),
+            },
+        }
+    }
+
+    /// Returns the endpoint to send to, given the `--host` and `--region`
+    /// flags.
+    ///
+    /// Either flag overrides both the host and region of the configuration
+    /// file, so that `--region` isn't ignored because the file sets a host.
+    pub fn endpoint(&self, host: Option<&str>, region: Option<&str>) -> Result<Endpoint, Error> {
+        let (host, region) = match (host, region) {
+            (None, None) => (self.host.as_deref(), self.region.as_deref()),
+            flags => flags,
+        };
+        Ok(match (host, region) {
+            (Some(host), _) => Endpoint::new(host)?,
+            (None, Some(region)) => Endpoint::from(region.parse::<Region>()?),
+            (None, None) => Endpoint::default(),
+        })
+    }
+
+    fn read(path: &Path) -> Result<Config, Error> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)
+            .with_context(|_| format!("could not parse {}", path.display()))?)
+    }
+}
+
+/// `$XDG_CONFIG_HOME/analytics/config.json`, falling back to `~/.config`.
+fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("analytics").join("config.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        let config = Config {
+            host: Some("https://collector.internal".to_owned()),
+            ..Default::default()
+        };
+        let eu = Endpoint::from(Region::Eu);
+
+        assert_eq!(eu, config.endpoint(None, Some("eu")).unwrap());
+        assert_eq!(
+            Endpoint::new("https://other.internal").unwrap(),
+            config
+                .endpoint(Some("https://other.internal"), None)
+                .unwrap()
+        );
+        assert_eq!(
+            Endpoint::new("https://collector.internal").unwrap(),
+            config.endpoint(None, None).unwrap()
+        );
+        assert_eq!(
+            Endpoint::default(),
+            Config::default().endpoint(None, None).unwrap()
+        );
+    }
+}
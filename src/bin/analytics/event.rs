@@ -0,0 +1,256 @@
+//! Building messages out of command-line flags.
+
+use analytics::message::{Alias, Group, Identify, Message, Page, Screen, Track, User};
+use chrono::{DateTime, Utc};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{bail, format_err, Error};
+use serde_json::{Map, Value};
+use std::io;
+
+/// Flags which, when any is given, make a subcommand build its message from
+/// flags rather than reading JSON from stdin.
+const FIELD_FLAGS: &[&str] = &[
+    "user-id",
+    "anonymous-id",
+    "timestamp",
+    "context",
+    "event",
+    "name",
+    "group-id",
+    "previous-id",
+    "property",
+    "trait",
+];
+
+/// The subcommands which send a single event.
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        event_command("identify", "Send an identify event").arg(traits()),
+        event_command("track", "Send a track event")
+            .arg(field("event", "Name of the event being tracked"))
+            .arg(properties()),
+        event_command("page", "Send a page event")
+            .arg(field("name", "Name of the page being viewed"))
+            .arg(properties()),
+        event_command("screen", "Send a screen event")
+            .arg(field("name", "Name of the screen being viewed"))
+            .arg(properties()),
+        event_command("group", "Send a group event")
+            .arg(field("group-id", "Group to associate the user with"))
+            .arg(traits()),
+        event_command("alias", "Send an alias event")
+            .arg(field("previous-id", "The user's previous ID")),
+    ]
+}
+
+fn event_command(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .after_help("If no fields are given as flags, the event is read as JSON from stdin.")
+        .arg(field("user-id", "User ID to send the event as"))
+        .arg(field("anonymous-id", "Anonymous ID to send the event as"))
+        .arg(field("timestamp", "RFC 3339 timestamp of the event"))
+        .arg(field("context", "Context of the event, as JSON"))
+}
+
+fn field(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name).help(help).takes_value(true).long(name)
+}
+
+fn properties() -> Arg<'static, 'static> {
+    field("property", "A property of the event, as key=value")
+        .short("p")
+        .multiple(true)
+        .number_of_values(1)
+}
+
+fn traits() -> Arg<'static, 'static> {
+    field("trait", "A trait to set, as key=value")
+        .short("t")
+        .multiple(true)
+        .number_of_values(1)
+}
+
+/// Build the message for the single-event subcommand `name`.
+pub fn message(name: &str, matches: &ArgMatches) -> Result<Message, Error> {
+    if !FIELD_FLAGS.iter().any(|flag| matches.is_present(flag)) {
+        let stdin = io::stdin();
+        return Ok(match name {
+            "identify" => Message::Identify(serde_json::from_reader(stdin)?),
+            "track" => Message::Track(serde_json::from_reader(stdin)?),
+            "page" => Message::Page(serde_json::from_reader(stdin)?),
+            "screen" => Message::Screen(serde_json::from_reader(stdin)?),
+            "group" => Message::Group(serde_json::from_reader(stdin)?),
+            "alias" => Message::Alias(serde_json::from_reader(stdin)?),
+            _ => bail!("unknown message type: {}", name),
+        });
+    }
+
+    let user = user(matches)?;
+    let timestamp = matches
+        .value_of("timestamp")
+        .map(|t| DateTime::parse_from_rfc3339(t).map(|t| t.with_timezone(&Utc)))
+        .transpose()?;
+    let context = matches
+        .value_of("context")
+        .map(serde_json::from_str)
+        .transpose()?;
+
+    Ok(match name {
+        "identify" => Message::Identify(Identify {
+            user,
+            traits: key_values(matches, "trait")?,
+            timestamp,
+            context,
+            ..Default::default()
+        }),
+        "track" => Message::Track(Track {
+            user,
+            event: required(matches, "event")?,
+            properties: key_values(matches, "property")?,
+            timestamp,
+            context,
+            ..Default::default()
+        }),
+        "page" => Message::Page(Page {
+            user,
+            name: required(matches, "name")?,
+            properties: key_values(matches, "property")?,
+            timestamp,
+            context,
+            ..Default::default()
+        }),
+        "screen" => Message::Screen(Screen {
+            user,
+            name: required(matches, "name")?,
+            properties: key_values(matches, "property")?,
+            timestamp,
+            context,
+            ..Default::default()
+        }),
+        "group" => Message::Group(Group {
+            user,
+            group_id: required(matches, "group-id")?,
+            traits: key_values(matches, "trait")?,
+            timestamp,
+            context,
+            ..Default::default()
+        }),
+        "alias" => Message::Alias(Alias {
+            user,
+            previous_id: required(matches, "previous-id")?,
+            timestamp,
+            context,
+            ..Default::default()
+        }),
+        _ => bail!("unknown message type: {}", name),
+    })
+}
+
+fn user(matches: &ArgMatches) -> Result<User, Error> {
+    let user_id = matches.value_of("user-id").map(str::to_owned);
+    let anonymous_id = matches.value_of("anonymous-id").map(str::to_owned);
+
+    match (user_id, anonymous_id) {
+        (Some(user_id), Some(anonymous_id)) => Ok(User::Both {
+            user_id,
+            anonymous_id,
+        }),
+        (Some(user_id), None) => Ok(User::UserId { user_id }),
+        (None, Some(anonymous_id)) => Ok(User::AnonymousId { anonymous_id }),
+        (None, None) => bail!("--user-id or --anonymous-id is required"),
+    }
+}
+
+fn required(matches: &ArgMatches, name: &str) -> Result<String, Error> {
+    matches
+        .value_of(name)
+        .map(str::to_owned)
+        .ok_or_else(|| format_err!("--{} is required", name))
+}
+
+fn key_values(matches: &ArgMatches, name: &str) -> Result<Value, Error> {
+    let mut map = Map::new();
+    for kv in matches.values_of(name).into_iter().flatten() {
+        let (key, value) = parse_key_value(kv)?;
+        map.insert(key, value);
+    }
+
+    Ok(Value::Object(map))
+}
+
+/// Parse a `key=value` pair. Values which are valid JSON, such as numbers and
+/// booleans, are kept as such; anything else is taken as a string.
+pub fn parse_key_value(kv: &str) -> Result<(String, Value), Error> {
+    let mut parts = kv.splitn(2, '=');
+    let key = parts.next().unwrap_or_default();
+    let value = match parts.next() {
+        Some(value) if !key.is_empty() => value,
+        _ => bail!("expected key=value, got {:?}", kv),
+    };
+
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()));
+    Ok((key.to_owned(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_key_value() {
+        assert_eq!(
+            ("plan".to_owned(), json!("pro")),
+            parse_key_value("plan=pro").unwrap()
+        );
+        assert_eq!(
+            ("seats".to_owned(), json!(3)),
+            parse_key_value("seats=3").unwrap()
+        );
+        assert_eq!(
+            ("trial".to_owned(), json!(false)),
+            parse_key_value("trial=false").unwrap()
+        );
+        assert_eq!(
+            ("code".to_owned(), json!("007")),
+            parse_key_value(r#"code="007""#).unwrap()
+        );
+        assert_eq!(
+            ("query".to_owned(), json!("a=b")),
+            parse_key_value("query=a=b").unwrap()
+        );
+        assert!(parse_key_value("plan").is_err());
+        assert!(parse_key_value("=pro").is_err());
+    }
+
+    #[test]
+    fn test_message_from_flags() {
+        let app = App::new("test").subcommands(subcommands());
+        let matches = app.get_matches_from(vec![
+            "test",
+            "track",
+            "--user-id",
+            "foo",
+            "--event",
+            "Signed Up",
+            "-p",
+            "plan=pro",
+            "--property",
+            "seats=3",
+        ]);
+
+        let (name, matches) = matches.subcommand();
+        assert_eq!(
+            Message::Track(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned()
+                },
+                event: "Signed Up".to_owned(),
+                properties: json!({ "plan": "pro", "seats": 3 }),
+                ..Default::default()
+            }),
+            message(name, matches.unwrap()).unwrap()
+        );
+    }
+}
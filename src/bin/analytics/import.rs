@@ -0,0 +1,524 @@
+//! Bulk importing of events through the batching path.
+
+use analytics::batcher::{Batcher, SerializedBatch};
+use analytics::client::Client;
+use analytics::message::BatchMessage;
+use analytics::retry::RetryPolicy;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{bail, Error, ResultExt};
+use flate2::read::MultiGzDecoder;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The `import` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    with_import_args(
+        SubCommand::with_name("import")
+            .about("Send newline-delimited JSON events, each tagged with a type, in batches")
+            .arg(
+                Arg::with_name("input")
+                    .help("File to read events from, or - for stdin; .gz and .zst files are decompressed")
+                    .required(true),
+            ),
+    )
+}
+
+/// Add the arguments shared by every import subcommand.
+pub fn with_import_args(app: App<'static, 'static>) -> App<'static, 'static> {
+    app.arg(
+        Arg::with_name("concurrency")
+            .help("Number of batches to send at once")
+            .takes_value(true)
+            .long("concurrency")
+            .default_value("4"),
+    )
+    .arg(
+        Arg::with_name("max-attempts")
+            .help("Attempts to make at sending a batch before giving up on it")
+            .takes_value(true)
+            .long("max-attempts")
+            .default_value("5"),
+    )
+    .arg(
+        Arg::with_name("failed")
+            .help("File to write events which could not be sent to, replacing any from an earlier run [default: <input>.failed]")
+            .takes_value(true)
+            .long("failed"),
+    )
+}
+
+/// Run the `import` subcommand.
+pub fn run<C>(client: C, write_key: String, matches: &ArgMatches) -> Result<(), Error>
+where
+    C: Client + Send + Sync + 'static,
+{
+    let input = matches.value_of("input").unwrap();
+    let reader: Box<dyn Read> = if input == "-" {
+        Box::new(io::stdin())
+    } else {
+        let file = File::open(input).with_context(|_| format!("could not open {}", input))?;
+        decompress(input, file)?
+    };
+
+    let mut importer = Importer::new(client, write_key, matches)?;
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<BatchMessage>(&line) {
+            Ok(msg) => importer.push(msg, line)?,
+            Err(e) => importer.reject(line, &e.to_string())?,
+        }
+    }
+
+    importer.finish()
+}
+
+/// Decompress `file` according to the extension of its `path`, such as the
+/// `.gz` files written by a `FileClient` with gzip compression.
+fn decompress(path: &str, file: File) -> Result<Box<dyn Read>, Error> {
+    if path.ends_with(".gz") {
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    } else if path.ends_with(".zst") {
+        #[cfg(feature = "zstd")]
+        return Ok(Box::new(zstd::Decoder::new(file)?));
+        #[cfg(not(feature = "zstd"))]
+        bail!(
+            "{} is compressed with zstd, which requires the zstd feature",
+            path
+        );
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// A batch which is being sent, along with the source of each of its events.
+struct Job {
+    batch: SerializedBatch,
+    sources: Vec<String>,
+}
+
+/// The outcome of sending a `Job`.
+struct Outcome {
+    sources: Vec<String>,
+    error: Option<Error>,
+}
+
+/// Feeds events through a `Batcher`, sending batches from a pool of worker
+/// threads and recording the source of every event which could not be sent.
+pub struct Importer {
+    batcher: Batcher,
+    sources: Vec<String>,
+    jobs: Option<SyncSender<Job>>,
+    outcomes: Receiver<Outcome>,
+    workers: Vec<JoinHandle<()>>,
+    failed_path: String,
+    failed_header: Option<String>,
+    failed: Option<BufWriter<File>>,
+    read: usize,
+    sent: usize,
+    failed_count: usize,
+    batches: usize,
+    started: Instant,
+    last_progress: Instant,
+}
+
+impl Importer {
+    pub fn new<C>(client: C, write_key: String, matches: &ArgMatches) -> Result<Importer, Error>
+    where
+        C: Client + Send + Sync + 'static,
+    {
+        let concurrency: usize = matches.value_of("concurrency").unwrap().parse()?;
+        if concurrency == 0 {
+            bail!("--concurrency must be at least 1");
+        }
+
+        let failed_path = match (matches.value_of("failed"), matches.value_of("input")) {
+            (Some(path), _) => path.to_owned(),
+            (None, Some(input)) if input != "-" => format!("{}.failed", input),
+            (None, _) => "import.failed".to_owned(),
+        };
+        // Events left over from an earlier run would otherwise be mistaken
+        // for failures of this one.
+        if let Err(e) = fs::remove_file(&failed_path) {
+            if e.kind() != ErrorKind::NotFound {
+                bail!("could not remove {}: {}", failed_path, e);
+            }
+        }
+        let policy = RetryPolicy {
+            max_attempts: matches.value_of("max-attempts").unwrap().parse()?,
+            ..Default::default()
+        };
+
+        let client = Arc::new(client);
+        let write_key = Arc::new(write_key);
+        let (jobs, job_rx) = mpsc::sync_channel::<Job>(concurrency);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (outcome_tx, outcomes) = mpsc::channel();
+
+        let workers = (0..concurrency)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                let write_key = Arc::clone(&write_key);
+                let job_rx = Arc::clone(&job_rx);
+                let outcome_tx = outcome_tx.clone();
+                let policy = policy.clone();
+
+                thread::spawn(move || loop {
+                    let job = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+
+                    let error = policy
+                        .run(|_| client.send_serialized(&write_key, &job.batch))
+                        .err()
+                        .map(|(e, _)| e);
+                    let outcome = Outcome {
+                        sources: job.sources,
+                        error,
+                    };
+                    if outcome_tx.send(outcome).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+
+        let now = Instant::now();
+        Ok(Importer {
+            batcher: Batcher::new(None),
+            sources: Vec::new(),
+            jobs: Some(jobs),
+            outcomes,
+            workers,
+            failed_path,
+            failed_header: None,
+            failed: None,
+            read: 0,
+            sent: 0,
+            failed_count: 0,
+            batches: 0,
+            started: now,
+            last_progress: now,
+        })
+    }
+
+    /// Set a line to write at the top of the failed file, such as the header
+    /// of a CSV file.
+    pub fn set_failed_header(&mut self, header: String) {
+        self.failed_header = Some(header);
+    }
+
+    /// Queue an event for sending. `source` is what gets written to the
+    /// failed file should the event not make it to Segment.
+    pub fn push(&mut self, msg: BatchMessage, source: String) -> Result<(), Error> {
+        self.read += 1;
+
+        let msg = match self.batcher.push(msg) {
+            Ok(None) => {
+                self.sources.push(source);
+                return self.progress();
+            }
+            Ok(Some(msg)) => msg,
+            Err(e) => return self.skip(source, &e.to_string()),
+        };
+
+        self.flush()?;
+        match self.batcher.push(msg) {
+            Ok(_) => self.sources.push(source),
+            Err(e) => self.skip(source, &e.to_string())?,
+        }
+        self.progress()
+    }
+
+    /// Record an event which could not be parsed.
+    pub fn reject(&mut self, source: String, reason: &str) -> Result<(), Error> {
+        self.read += 1;
+        self.skip(source, reason)
+    }
+
+    fn skip(&mut self, source: String, reason: &str) -> Result<(), Error> {
+        eprintln!("skipping event: {}", reason);
+        self.failed_count += 1;
+        self.write_failed(&[source])
+    }
+
+    /// Send anything left over, wait for all batches to complete and print a
+    /// summary.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            worker.join().expect("import worker panicked");
+        }
+        while let Ok(outcome) = self.outcomes.try_recv() {
+            self.record(outcome)?;
+        }
+        if let Some(mut failed) = self.failed.take() {
+            failed.flush()?;
+        }
+
+        eprintln!(
+            "read {} events, sent {} in {} batches in {:.1}s",
+            self.read,
+            self.sent,
+            self.batches,
+            self.started.elapsed().as_secs_f64()
+        );
+
+        if self.failed_count > 0 {
+            bail!(
+                "{} events could not be sent; they were written to {}",
+                self.failed_count,
+                self.failed_path
+            );
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(batch) = self.batcher.flush_serialized() {
+            let job = Job {
+                batch,
+                sources: std::mem::take(&mut self.sources),
+            };
+
+            // Collect outcomes while waiting, so failures are written out
+            // promptly rather than at the end.
+            let mut job = Some(job);
+            while let Some(pending) = job.take() {
+                match self.jobs.as_ref().unwrap().try_send(pending) {
+                    Ok(()) => {}
+                    Err(mpsc::TrySendError::Full(pending)) => {
+                        job = Some(pending);
+                        let outcome = self.outcomes.recv()?;
+                        self.record(outcome)?;
+                    }
+                    Err(mpsc::TrySendError::Disconnected(_)) => bail!("import workers exited"),
+                }
+            }
+        }
+
+        while let Ok(outcome) = self.outcomes.try_recv() {
+            self.record(outcome)?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, outcome: Outcome) -> Result<(), Error> {
+        self.batches += 1;
+        match outcome.error {
+            None => self.sent += outcome.sources.len(),
+            Some(e) => {
+                eprintln!("could not send batch of {}: {}", outcome.sources.len(), e);
+                self.failed_count += outcome.sources.len();
+                self.write_failed(&outcome.sources)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_failed(&mut self, sources: &[String]) -> Result<(), Error> {
+        if self.failed.is_none() {
+            let file = File::create(&self.failed_path)
+                .with_context(|_| format!("could not create {}", self.failed_path))?;
+            let mut failed = BufWriter::new(file);
+            if let Some(ref header) = self.failed_header {
+                writeln!(failed, "{}", header)?;
+            }
+            self.failed = Some(failed);
+        }
+
+        let failed = self.failed.as_mut().unwrap();
+        for source in sources {
+            writeln!(failed, "{}", source)?;
+        }
+        Ok(())
+    }
+
+    fn progress(&mut self) -> Result<(), Error> {
+        if self.last_progress.elapsed() >= PROGRESS_INTERVAL {
+            self.last_progress = Instant::now();
+            eprintln!(
+                "read {} events, sent {}, {} failed",
+                self.read, self.sent, self.failed_count
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use analytics::file::FileClient;
+    use analytics::http::Compression;
+    use analytics::message::{Batch, Message};
+    use analytics::testing::{MockResponse, MockServer};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Write `lines` to an input file named after `test`, returning its path
+    /// and the path of its failed file.
+    fn input(test: &str, lines: &[String]) -> (PathBuf, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "analytics-import-{}-{}.ndjson",
+            test,
+            std::process::id()
+        ));
+        fs::write(&path, lines.join("\n")).unwrap();
+        let failed = PathBuf::from(format!("{}.failed", path.display()));
+        let _ = fs::remove_file(&failed);
+        (path, failed)
+    }
+
+    fn event(i: usize, padding: usize) -> String {
+        serde_json::json!({
+            "type": "track",
+            "userId": format!("user-{}", i),
+            "event": "Imported",
+            "properties": { "padding": "x".repeat(padding) },
+        })
+        .to_string()
+    }
+
+    fn import(server: &MockServer, input: &Path) -> Result<(), Error> {
+        let matches = App::new("test")
+            .subcommand(subcommand())
+            .get_matches_from(vec![
+                "test",
+                "import",
+                "--concurrency",
+                "2",
+                input.to_str().unwrap(),
+            ]);
+        run(
+            server.client(),
+            "write_key".to_owned(),
+            matches.subcommand_matches("import").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_batches() {
+        let server = MockServer::with_write_key("write_key");
+        // Large enough that they can't all fit in one batch.
+        let lines: Vec<_> = (0..40).map(|i| event(i, 20 * 1024)).collect();
+        let (path, failed) = input("batches", &lines);
+
+        import(&server, &path).unwrap();
+
+        assert!(server.received().len() > 1);
+        let mut users: Vec<_> = server
+            .batch_messages()
+            .iter()
+            .map(|msg| msg.user().user_id().unwrap().to_owned())
+            .collect();
+        users.sort_by_key(|user| user[5..].parse::<usize>().unwrap());
+        let expected: Vec<_> = (0..40).map(|i| format!("user-{}", i)).collect();
+        assert_eq!(expected, users);
+        assert!(!failed.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_input() {
+        let server = MockServer::with_write_key("write_key");
+        let dir = std::env::temp_dir().join(format!("analytics-import-gz-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        {
+            let client = FileClient::new(&dir)
+                .unwrap()
+                .with_compression(Compression::Gzip);
+            let batch = (0..3)
+                .map(|i| serde_json::from_str(&event(i, 0)).unwrap())
+                .collect();
+            let msg = Message::Batch(Batch {
+                batch,
+                ..Default::default()
+            });
+            client.send("write_key", &msg).unwrap();
+        }
+        let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        assert!(path.to_str().unwrap().ends_with(".gz"));
+
+        import(&server, &path).unwrap();
+        assert_eq!(3, server.batch_messages().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retries() {
+        let server = MockServer::with_write_key("write_key");
+        server.enqueue(MockResponse::status(500));
+        let lines: Vec<_> = (0..3).map(|i| event(i, 0)).collect();
+        let (path, failed) = input("retries", &lines);
+        fs::write(&failed, "left over from an earlier run\n").unwrap();
+
+        import(&server, &path).unwrap();
+
+        assert_eq!(2, server.received().len());
+        assert_eq!(3, server.batch_messages().len());
+        assert!(!failed.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_batches() {
+        let server = MockServer::with_write_key("write_key");
+        server.enqueue(MockResponse::status(400));
+        let lines: Vec<_> = (0..3).map(|i| event(i, 0)).collect();
+        let (path, failed) = input("failed", &lines);
+
+        let e = import(&server, &path).unwrap_err();
+        assert!(
+            e.to_string().starts_with("3 events could not be sent"),
+            "{}",
+            e
+        );
+        assert_eq!(
+            lines.join("\n") + "\n",
+            fs::read_to_string(&failed).unwrap()
+        );
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&failed).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let server = MockServer::with_write_key("write_key");
+        let lines = vec![
+            event(0, 0),
+            "not json".to_owned(),
+            r#"{"type":"unknown"}"#.to_owned(),
+            String::new(),
+            event(1, 0),
+            event(2, 40 * 1024),
+        ];
+        let (path, failed) = input("malformed", &lines);
+
+        let e = import(&server, &path).unwrap_err();
+        assert!(
+            e.to_string().starts_with("3 events could not be sent"),
+            "{}",
+            e
+        );
+        assert_eq!(2, server.batch_messages().len());
+        assert_eq!(
+            format!("not json\n{}\n{}\n", lines[2], lines[5]),
+            fs::read_to_string(&failed).unwrap()
+        );
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&failed).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Importing of CSV files, mapped onto events.
+
+use crate::import::{self, Importer};
+use analytics::client::Client;
+use analytics::csv::{CsvReader, Mapping};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use csv::{StringRecord, Writer};
+use failure::{Error, ResultExt};
+use std::fs::File;
+use std::io::{self, Read};
+
+/// The `import-csv` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    import::with_import_args(
+        SubCommand::with_name("import-csv")
+            .about("Send the rows of a CSV file as events in batches")
+            .after_help(
+                "Rows which could not be sent are written, with the header, to the failed file.",
+            )
+            .arg(
+                Arg::with_name("input")
+                    .help("CSV file to read events from, or - for stdin")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("mapping")
+                    .help("JSON file mapping columns to event fields")
+                    .takes_value(true)
+                    .short("m")
+                    .long("mapping")
+                    .required(true),
+            ),
+    )
+}
+
+/// Run the `import-csv` subcommand.
+pub fn run<C>(client: C, write_key: String, matches: &ArgMatches) -> Result<(), Error>
+where
+    C: Client + Send + Sync + 'static,
+{
+    let path = matches.value_of("mapping").unwrap();
+    let mapping: Mapping = serde_json::from_reader(
+        File::open(path).with_context(|_| format!("could not open {}", path))?,
+    )
+    .with_context(|_| format!("could not parse {}", path))?;
+
+    let input = matches.value_of("input").unwrap();
+    let reader: Box<dyn Read> = if input == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(input).with_context(|_| format!("could not open {}", input))?)
+    };
+
+    let mut reader = CsvReader::new(reader, &mapping)?;
+    let mut importer = Importer::new(client, write_key, matches)?;
+    importer.set_failed_header(to_line(reader.headers()?)?);
+
+    while let Some(msg) = reader.next() {
+        let source = to_line(reader.record())?;
+        match msg {
+            Ok(msg) => importer.push(msg, source)?,
+            Err(e) => importer.reject(source, &e.to_string())?,
+        }
+    }
+
+    importer.finish()
+}
+
+/// Encode a record as a line of CSV, without the trailing newline.
+fn to_line(record: &StringRecord) -> Result<String, Error> {
+    let mut writer = Writer::from_writer(Vec::new());
+    writer.write_record(record)?;
+    let mut line = String::from_utf8(writer.into_inner()?)?;
+    // Strip only the line terminator; a field's own trailing whitespace is
+    // part of the record.
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_line() {
+        let record = StringRecord::from(vec!["a", "b ", "c,d", "e\t"]);
+        assert_eq!("a,b ,\"c,d\",e\t", to_line(&record).unwrap());
+    }
+}
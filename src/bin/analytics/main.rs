@@ -0,0 +1,87 @@
+mod config;
+mod event;
+mod import;
+mod import_csv;
+mod serve;
+
+use analytics::client::Client;
+use analytics::http::HttpClient;
+use clap::{App, AppSettings, Arg};
+use config::Config;
+use failure::{format_err, Error};
+
+fn main() -> Result<(), Error> {
+    let matches = App::new("Analytics")
+        .version("0.1")
+        .author("Segment <friends@segment.com>")
+        .about("Sends analytics events to Segment")
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("write-key")
+                .help("Write key to send message with")
+                .takes_value(true)
+                .short("w")
+                .long("write-key")
+                .env("SEGMENT_WRITE_KEY"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .help("Configuration file [default: ~/.config/analytics/config.json]")
+                .takes_value(true)
+                .short("c")
+                .long("config"),
+        )
+        .arg(
+            Arg::with_name("host")
+                .help("Scheme, host and optional path prefix to send to")
+                .takes_value(true)
+                .long("host")
+                .conflicts_with("region"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .help("Segment region to send to [default: us]")
+                .takes_value(true)
+                .possible_values(&["us", "eu"])
+                .long("region"),
+        )
+        .subcommands(event::subcommands())
+        .subcommand(import::subcommand())
+        .subcommand(import_csv::subcommand())
+        .subcommand(serve::subcommand())
+        .get_matches();
+
+    let config = Config::load(matches.value_of("config"))?;
+
+    let write_key = matches
+        .value_of("write-key")
+        .map(str::to_owned)
+        .or_else(|| config.write_key.clone());
+    let write_key = || {
+        write_key.ok_or_else(|| {
+            format_err!("a write key is required: pass --write-key, set SEGMENT_WRITE_KEY or add write_key to the config file")
+        })
+    };
+
+    let endpoint = config.endpoint(matches.value_of("host"), matches.value_of("region"))?;
+
+    let client = HttpClient::builder().with_endpoint(endpoint).build()?;
+
+    match matches.subcommand() {
+        ("import", Some(sub_matches)) => import::run(client, write_key()?, sub_matches),
+        ("import-csv", Some(sub_matches)) => import_csv::run(client, write_key()?, sub_matches),
+        ("serve", Some(sub_matches)) => {
+            let forward = if sub_matches.is_present("forward") {
+                Some(client)
+            } else {
+                None
+            };
+            serve::run(forward, sub_matches)
+        }
+        (name, sub_matches) => {
+            let message = event::message(name, sub_matches.unwrap())?;
+            client.send(&write_key()?, &message)
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! A local server which captures what services send to Segment.
+
+use analytics::client::Client;
+use analytics::ingest;
+use analytics::message::BatchMessage;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{err_msg, Error, ResultExt};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use tiny_http::{Request, Server};
+
+/// The `serve` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("serve")
+        .about("Run a local tracking API which validates and prints the events it receives")
+        .arg(
+            Arg::with_name("port")
+                .help("Port to listen on")
+                .takes_value(true)
+                .short("p")
+                .long("port")
+                .default_value("8080"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .help("Address to listen on")
+                .takes_value(true)
+                .long("bind")
+                .default_value("127.0.0.1"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help(
+                    "Append events to this file as newline-delimited JSON instead of printing them",
+                )
+                .takes_value(true)
+                .short("o")
+                .long("output"),
+        )
+        .arg(
+            Arg::with_name("forward")
+                .help(
+                    "Forward events to --host or --region, using the write key they were sent with",
+                )
+                .long("forward"),
+        )
+}
+
+/// Where captured events are written.
+enum Output {
+    Pretty,
+    Ndjson(Box<dyn Write + Send>),
+}
+
+/// Run the `serve` subcommand. If `forward` is given, every valid message is
+/// also sent through it.
+pub fn run<C: Client>(forward: Option<C>, matches: &ArgMatches) -> Result<(), Error> {
+    let addr = format!(
+        "{}:{}",
+        matches.value_of("bind").unwrap(),
+        matches.value_of("port").unwrap()
+    );
+
+    let output = match matches.value_of("output") {
+        Some(path) => Output::Ndjson(Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|_| format!("could not open {}", path))?,
+        )),
+        None => Output::Pretty,
+    };
+    let output = Mutex::new(output);
+
+    let server =
+        Server::http(&addr).map_err(|e| err_msg(format!("could not listen on {}: {}", addr, e)))?;
+    eprintln!("listening on http://{}", addr);
+
+    for request in server.incoming_requests() {
+        if let Err(e) = handle(request, &output, forward.as_ref()) {
+            eprintln!("error: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle<C: Client>(
+    mut request: Request,
+    output: &Mutex<Output>,
+    forward: Option<&C>,
+) -> Result<(), Error> {
+    let ingest::TrackingRequest {
+        path,
+        write_key,
+        message,
+    } = ingest::read_request(&mut request);
+    let message = match message {
+        Ok(message) => message,
+        Err(e) => {
+            eprintln!("rejected {} {}: {}", request.method(), path, e);
+            return ingest::respond(request, ingest::error_status(&e), Some(&e.to_string()));
+        }
+    };
+
+    let write_key = match write_key {
+        Some(write_key) => write_key,
+        None => {
+            eprintln!("rejected {} {}: missing write key", request.method(), path);
+            return ingest::respond(request, 401, Some("missing write key"));
+        }
+    };
+
+    match *output.lock().unwrap() {
+        Output::Pretty => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            writeln!(
+                stdout,
+                "{} {} (write key {})",
+                request.method(),
+                path,
+                write_key
+            )?;
+            writeln!(stdout, "{}", serde_json::to_string_pretty(&message)?)?;
+        }
+        Output::Ndjson(ref mut file) => {
+            for msg in message.clone().into_batch_messages() {
+                writeln!(file, "{}", serde_json::to_string::<BatchMessage>(&msg)?)?;
+            }
+            file.flush()?;
+        }
+    }
+
+    if let Some(client) = forward {
+        if let Err(e) = client.send(&write_key, &message) {
+            eprintln!("could not forward {}: {}", path, e);
+            return ingest::respond(request, 502, Some(&e.to_string()));
+        }
+    }
+
+    ingest::respond(request, 200, None)
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use analytics::endpoint::Endpoint;
+    use analytics::errors::Error as AnalyticsError;
+    use analytics::http::HttpClient;
+    use analytics::message::{Message, Track, User};
+    use analytics::testing::{MemoryClient, MockResponse, MockServer};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A buffer which captured events can be written to while a test holds
+    /// on to it.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serve on an unused port, returning its address and what it captures.
+    fn start<C: Client + Send + 'static>(forward: Option<C>) -> (String, Captured) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", server.server_addr());
+        let captured = Captured::default();
+        let output = Mutex::new(Output::Ndjson(Box::new(captured.clone())));
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handle(request, &output, forward.as_ref()).unwrap();
+            }
+        });
+        (addr, captured)
+    }
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn client(addr: &str) -> HttpClient {
+        HttpClient::builder()
+            .with_endpoint(Endpoint::new(addr).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_captures_and_forwards() {
+        let forward = Arc::new(MemoryClient::new());
+        let (addr, captured) = start(Some(forward.clone()));
+
+        client(&addr).send("write_key", &track()).unwrap();
+
+        let line = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            track().into_batch_messages(),
+            vec![serde_json::from_str::<BatchMessage>(line.trim()).unwrap()]
+        );
+        assert_eq!(vec![("write_key".to_owned(), track())], forward.sent());
+    }
+
+    #[test]
+    fn test_rejects_invalid_requests() {
+        let (addr, captured) = start(None::<MemoryClient>);
+        let http = reqwest::blocking::Client::new();
+
+        let response = http
+            .post(format!("{}/v1/track", addr))
+            .basic_auth("write_key", Some(""))
+            .body("not json")
+            .send()
+            .unwrap();
+        assert_eq!(400, response.status().as_u16());
+
+        let response = http
+            .post(format!("{}/v1/track", addr))
+            .body(serde_json::to_vec(&track()).unwrap())
+            .send()
+            .unwrap();
+        assert_eq!(401, response.status().as_u16());
+
+        let response = http
+            .post(format!("{}/v1/batch", addr))
+            .basic_auth("write_key", Some(""))
+            .body(vec![b' '; ingest::MAX_BODY + 1])
+            .send()
+            .unwrap();
+        assert_eq!(413, response.status().as_u16());
+        assert!(captured.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forward_failure() {
+        let upstream = MockServer::start();
+        upstream.enqueue(MockResponse::status(500));
+        let (addr, captured) = start(Some(upstream.client()));
+
+        let e = client(&addr).send("write_key", &track()).unwrap_err();
+        match e.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::Status { status: 502, .. }) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        // The event is still captured.
+        assert!(!captured.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,444 @@
+//! Failing fast while the tracking API is unavailable.
+
+use crate::batcher::{Batcher, SerializedBatch};
+use crate::client::Client;
+use crate::errors::Error as AnalyticsError;
+use crate::hooks::{CircuitStateChange, Hooks};
+use crate::message::Message;
+use crate::retry::is_retryable;
+use crate::spool::Spool;
+use failure::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A client which stops sending through another client after several
+/// consecutive failures, rather than having every send wait to time out.
+///
+/// Once `failure_threshold` sends in a row have failed with errors which
+/// show the API is unavailable (those which
+/// [`is_retryable`](../retry/fn.is_retryable.html)), the circuit opens and
+/// sends fail immediately with `Error::CircuitOpen`. After `open_duration`,
+/// the circuit half-opens and lets a probe request through, one at a time;
+/// once enough probes succeed it closes again, and if one fails it reopens.
+///
+/// ```no_run
+/// use analytics::breaker::CircuitBreaker;
+/// use analytics::http::HttpClient;
+/// use analytics::spool::Spool;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let spool = Arc::new(Spool::open("/var/spool/analytics").unwrap());
+/// let client = CircuitBreaker::new(HttpClient::default())
+///     .with_failure_threshold(3)
+///     .with_open_duration(Duration::from_secs(10))
+///     .with_fallback(spool);
+/// ```
+///
+/// With a fallback `Spool`, which may be the application's durable queue or
+/// one opened just as a dead-letter directory, sends made while the circuit
+/// is open are written to it instead, and succeed.
+pub struct CircuitBreaker<C> {
+    client: C,
+    failure_threshold: u32,
+    open_duration: Duration,
+    probes: u32,
+    fallback: Option<Arc<Spool>>,
+    hooks: Option<Arc<dyn Hooks>>,
+    circuit: Mutex<Circuit>,
+}
+
+/// The state of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Sends are made as normal.
+    Closed,
+
+    /// Sends fail immediately, or go to the fallback.
+    Open,
+
+    /// Probe requests are being made to find out whether the API has
+    /// recovered.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Returns a short, stable name for this state, such as `half_open`, for
+    /// use as a metric label.
+    pub fn name(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CircuitState::Closed => f.write_str("closed"),
+            CircuitState::Open => f.write_str("open"),
+            CircuitState::HalfOpen => f.write_str("half-open"),
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    /// Consecutive failures while closed.
+    failures: u32,
+    /// Consecutive successful probes while half-open.
+    successes: u32,
+    probing: bool,
+    opened_at: Instant,
+}
+
+/// Clears a circuit's `probing` flag if its probe never finishes, such as
+/// when the send panics, so later calls may probe instead.
+struct Probe<'a>(Option<&'a Mutex<Circuit>>);
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        if let Some(circuit) = self.0 {
+            if let Ok(mut circuit) = circuit.lock() {
+                circuit.probing = false;
+            }
+        }
+    }
+}
+
+impl Circuit {
+    fn transition(&mut self, to: CircuitState) -> CircuitStateChange {
+        let from = self.state;
+        self.state = to;
+        self.failures = 0;
+        self.successes = 0;
+        if to == CircuitState::Open {
+            self.opened_at = Instant::now();
+        }
+        CircuitStateChange { from, to }
+    }
+}
+
+impl<C: Client> CircuitBreaker<C> {
+    /// Wrap `client` in a closed circuit breaker, which opens after 5
+    /// consecutive failures for 30 seconds, and closes after one successful
+    /// probe.
+    pub fn new(client: C) -> CircuitBreaker<C> {
+        CircuitBreaker {
+            client,
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+            probes: 1,
+            fallback: None,
+            hooks: None,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                failures: 0,
+                successes: 0,
+                probing: false,
+                opened_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Set how many consecutive failures open the circuit.
+    pub fn with_failure_threshold(mut self, failures: u32) -> CircuitBreaker<C> {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    /// Set how long the circuit stays open before probing.
+    pub fn with_open_duration(mut self, duration: Duration) -> CircuitBreaker<C> {
+        self.open_duration = duration;
+        self
+    }
+
+    /// Set how many probes in a row must succeed to close the circuit.
+    pub fn with_probes(mut self, probes: u32) -> CircuitBreaker<C> {
+        self.probes = probes.max(1);
+        self
+    }
+
+    /// Write batches sent while the circuit is open to `spool`, instead of
+    /// failing.
+    pub fn with_fallback(mut self, spool: Arc<Spool>) -> CircuitBreaker<C> {
+        self.fallback = Some(spool);
+        self
+    }
+
+    /// Report changes of state to `hooks`.
+    pub fn with_hooks(mut self, hooks: Arc<dyn Hooks>) -> CircuitBreaker<C> {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Returns the circuit's current state.
+    pub fn state(&self) -> CircuitState {
+        self.circuit.lock().unwrap().state
+    }
+
+    /// Returns the wrapped client.
+    pub fn inner(&self) -> &C {
+        &self.client
+    }
+
+    fn call<T, F, G>(&self, send: F, fallback: G) -> Result<T, Error>
+    where
+        F: FnOnce(&C) -> Result<T, Error>,
+        G: FnOnce(&Spool) -> Result<T, Error>,
+    {
+        let (probe, change) = {
+            let mut circuit = self.circuit.lock().unwrap();
+            match circuit.state {
+                CircuitState::Closed => (Some(false), None),
+                CircuitState::Open if circuit.opened_at.elapsed() >= self.open_duration => {
+                    circuit.probing = true;
+                    (Some(true), Some(circuit.transition(CircuitState::HalfOpen)))
+                }
+                CircuitState::HalfOpen if !circuit.probing => {
+                    circuit.probing = true;
+                    (Some(true), None)
+                }
+                CircuitState::Open | CircuitState::HalfOpen => (None, None),
+            }
+        };
+        self.report(change);
+
+        let probe = match probe {
+            Some(probe) => probe,
+            None => {
+                return match self.fallback {
+                    Some(ref spool) => fallback(spool),
+                    None => Err(AnalyticsError::CircuitOpen.into()),
+                };
+            }
+        };
+
+        let mut guard = Probe(if probe { Some(&self.circuit) } else { None });
+        let result = send(&self.client);
+        guard.0 = None;
+        // Errors which aren't retryable, such as a bad request, still show
+        // that the API is up.
+        let failed = result.as_ref().err().is_some_and(is_retryable);
+
+        let change = {
+            let mut circuit = self.circuit.lock().unwrap();
+            if probe {
+                circuit.probing = false;
+                if failed {
+                    Some(circuit.transition(CircuitState::Open))
+                } else {
+                    circuit.successes += 1;
+                    if circuit.successes >= self.probes {
+                        Some(circuit.transition(CircuitState::Closed))
+                    } else {
+                        None
+                    }
+                }
+            } else if circuit.state != CircuitState::Closed {
+                None
+            } else if failed {
+                circuit.failures += 1;
+                if circuit.failures >= self.failure_threshold {
+                    Some(circuit.transition(CircuitState::Open))
+                } else {
+                    None
+                }
+            } else {
+                circuit.failures = 0;
+                None
+            }
+        };
+        self.report(change);
+
+        result
+    }
+
+    fn report(&self, change: Option<CircuitStateChange>) {
+        if let (Some(change), Some(hooks)) = (change, self.hooks.as_ref()) {
+            hooks.on_circuit_state_change(&change);
+        }
+    }
+}
+
+impl<C: Client> Client for CircuitBreaker<C> {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        self.call(
+            |client| client.send(write_key, msg),
+            |spool| {
+                let mut batcher = Batcher::new(None);
+                for msg in msg.clone().into_batch_messages() {
+                    if let Some(msg) = batcher.push(msg)? {
+                        if let Some(batch) = batcher.flush_serialized() {
+                            spool.push(write_key, &batch)?;
+                        }
+                        batcher.push(msg)?;
+                    }
+                }
+                match batcher.flush_serialized() {
+                    Some(batch) => spool.push(write_key, &batch),
+                    None => Ok(()),
+                }
+            },
+        )
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        self.call(
+            |client| client.send_serialized(write_key, batch),
+            |spool| spool.push(write_key, batch),
+        )
+    }
+
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        self.call(
+            |client| client.send_serialized_with_status(write_key, batch),
+            |spool| spool.push(write_key, batch).map(|()| None),
+        )
+    }
+}
+
+#[cfg(all(test, any(feature = "reqwest", feature = "ureq", feature = "hyper")))]
+mod tests {
+    use super::*;
+    use crate::http::HttpClient;
+    use crate::message::{Track, User};
+    use crate::testing::{MockResponse, MockServer};
+    use std::fs;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[derive(Default)]
+    struct Record(Mutex<Vec<String>>);
+
+    impl Hooks for Record {
+        fn on_circuit_state_change(&self, change: &CircuitStateChange) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} -> {}", change.from, change.to));
+        }
+    }
+
+    #[test]
+    fn test_opens_and_probes() {
+        let server = MockServer::start();
+        let hooks = Arc::new(Record::default());
+        let client = CircuitBreaker::new(server.client())
+            .with_failure_threshold(2)
+            .with_open_duration(Duration::from_millis(50))
+            .with_hooks(hooks.clone());
+
+        // A client error doesn't count towards opening the circuit.
+        server.enqueue(MockResponse::status(503));
+        server.enqueue(MockResponse::status(400));
+        server.enqueue(MockResponse::status(503));
+        server.enqueue(MockResponse::status(503));
+        for _ in 0..4 {
+            assert!(client.send("write_key", &track()).is_err());
+        }
+        assert_eq!(CircuitState::Open, client.state());
+
+        match client.send("write_key", &track()) {
+            Err(e) => match e.downcast_ref::<AnalyticsError>() {
+                Some(AnalyticsError::CircuitOpen) => {}
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(()) => panic!("sent while open"),
+        }
+        assert_eq!(4, server.received().len());
+
+        thread::sleep(Duration::from_millis(60));
+        server.enqueue(MockResponse::status(503));
+        assert!(client.send("write_key", &track()).is_err());
+        assert_eq!(CircuitState::Open, client.state());
+
+        thread::sleep(Duration::from_millis(60));
+        client.send("write_key", &track()).unwrap();
+        assert_eq!(CircuitState::Closed, client.state());
+        assert_eq!(6, server.received().len());
+
+        assert_eq!(
+            vec![
+                "closed -> open",
+                "open -> half-open",
+                "half-open -> open",
+                "open -> half-open",
+                "half-open -> closed",
+            ],
+            *hooks.0.lock().unwrap()
+        );
+    }
+
+    struct PanicOnce(HttpClient, AtomicBool);
+
+    impl Client for PanicOnce {
+        fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+            if self.1.swap(false, Ordering::SeqCst) {
+                panic!("send panicked");
+            }
+            self.0.send(write_key, msg)
+        }
+    }
+
+    #[test]
+    fn test_probe_panics() {
+        let server = MockServer::start();
+        let client = CircuitBreaker::new(PanicOnce(server.client(), AtomicBool::new(false)))
+            .with_failure_threshold(1)
+            .with_open_duration(Duration::from_millis(0));
+
+        server.enqueue(MockResponse::status(503));
+        assert!(client.send("write_key", &track()).is_err());
+        assert_eq!(CircuitState::Open, client.state());
+
+        client.client.1.store(true, Ordering::SeqCst);
+        let probe = panic::catch_unwind(AssertUnwindSafe(|| client.send("write_key", &track())));
+        assert!(probe.is_err());
+        assert_eq!(CircuitState::HalfOpen, client.state());
+
+        // The panicked probe doesn't stop the next call probing.
+        client.send("write_key", &track()).unwrap();
+        assert_eq!(CircuitState::Closed, client.state());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let dir = std::env::temp_dir().join(format!("analytics-breaker-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let spool = Arc::new(Spool::open(&dir).unwrap());
+
+        let server = MockServer::start();
+        let client = CircuitBreaker::new(server.client())
+            .with_failure_threshold(1)
+            .with_fallback(spool.clone());
+
+        server.enqueue(MockResponse::status(500));
+        assert!(client.send("write_key", &track()).is_err());
+        client.send("write_key", &track()).unwrap();
+        assert_eq!(1, server.received().len());
+
+        let spooled = spool.peek().unwrap().unwrap();
+        assert_eq!("write_key", spooled.write_key);
+        assert_eq!(1, spooled.batch.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1092 @@
+//! Sending messages in the background.
+
+use crate::batcher::{self, Batcher};
+use crate::client::Client;
+use crate::errors::Error as AnalyticsError;
+use crate::hooks::{Attempt, BatchFailed, BatchSent, DropReason, Dropped, Hooks, QueueDepth};
+use crate::message::BatchMessage;
+use crate::retry::RetryPolicy;
+use failure::Error;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How a `BufferedSender` batches and sends messages.
+#[derive(Clone)]
+pub struct BufferedOptions {
+    /// The longest a message is buffered before its batch is sent.
+    pub flush_interval: Duration,
+
+    /// Send a batch as soon as it holds this many messages. Batches are also
+    /// sent when they reach the largest size Segment accepts.
+    pub max_batch_len: usize,
+
+    /// How sending each batch is retried.
+    pub retry_policy: RetryPolicy,
+
+    /// The `context` to set on every batch.
+    pub context: Option<Value>,
+
+    /// Callbacks to make as messages are sent or dropped.
+    pub hooks: Option<Arc<dyn Hooks>>,
+
+    /// The most messages to queue while waiting for earlier batches to be
+    /// sent.
+    pub max_queue_len: usize,
+
+    /// The most serialized bytes of messages to queue while waiting for
+    /// earlier batches to be sent.
+    pub max_queue_bytes: usize,
+
+    /// What to do with a message enqueued while the queue is full.
+    pub overflow_policy: OverflowPolicy,
+
+    /// The number of background threads, each sending one batch at a time.
+    pub concurrency: usize,
+
+    /// Whether to keep each user's messages in order when `concurrency` is
+    /// more than 1.
+    ///
+    /// Messages are then partitioned between threads by their anonymous ID,
+    /// or their user ID if they have none, so a user's messages are sent in
+    /// the order they were enqueued. That holds across an `identify` which
+    /// adds a user ID to an anonymous user, as long as the user's messages
+    /// keep carrying the anonymous ID; messages carrying only the user ID may
+    /// be reordered relative to those carrying the anonymous ID. Otherwise
+    /// each message goes to whichever thread has the least queued, and
+    /// messages in batches which are sent concurrently may arrive in any
+    /// order.
+    pub ordered_per_user: bool,
+}
+
+impl fmt::Debug for BufferedOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufferedOptions")
+            .field("flush_interval", &self.flush_interval)
+            .field("max_batch_len", &self.max_batch_len)
+            .field("retry_policy", &self.retry_policy)
+            .field("context", &self.context)
+            .field("hooks", &self.hooks.as_ref().map(|_| ".."))
+            .field("max_queue_len", &self.max_queue_len)
+            .field("max_queue_bytes", &self.max_queue_bytes)
+            .field("overflow_policy", &self.overflow_policy)
+            .field("concurrency", &self.concurrency)
+            .field("ordered_per_user", &self.ordered_per_user)
+            .finish()
+    }
+}
+
+impl Default for BufferedOptions {
+    fn default() -> Self {
+        BufferedOptions {
+            flush_interval: Duration::from_secs(1),
+            max_batch_len: 100,
+            retry_policy: RetryPolicy::default(),
+            context: None,
+            hooks: None,
+            max_queue_len: 10_000,
+            max_queue_bytes: 16 * 1024 * 1024,
+            overflow_policy: OverflowPolicy::default(),
+            concurrency: 1,
+            ordered_per_user: true,
+        }
+    }
+}
+
+/// What a `BufferedSender` does with a message enqueued while its queue is
+/// full, such as during an outage of the tracking API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait for room in the queue.
+    Block,
+
+    /// Wait up to the given time for room in the queue, then drop the message
+    /// and fail with `Error::QueueFull`.
+    BlockTimeout(Duration),
+
+    /// Drop the message and fail with `Error::QueueFull`.
+    #[default]
+    DropNewest,
+
+    /// Drop the oldest queued messages to make room.
+    DropOldest,
+}
+
+/// A sender which batches messages and sends them from background threads,
+/// so that enqueueing a message doesn't wait on the network.
+///
+/// ```no_run
+/// use analytics::buffered::{BufferedOptions, BufferedSender};
+/// use analytics::http::HttpClient;
+/// use analytics::message::{BatchMessage, Track, User};
+///
+/// let sender = BufferedSender::new(
+///     HttpClient::default(),
+///     "YOUR_WRITE_KEY",
+///     BufferedOptions::default(),
+/// );
+///
+/// sender
+///     .enqueue(BatchMessage::Track(Track {
+///         user: User::UserId { user_id: "some_user_id".to_owned() },
+///         event: "Example Event".to_owned(),
+///         ..Default::default()
+///     }))
+///     .unwrap();
+///
+/// // Dropping the sender sends whatever is still buffered.
+/// drop(sender);
+/// ```
+///
+/// Messages wait in a bounded queue while earlier batches are sent. When it
+/// fills up, the options' `overflow_policy` decides whether `enqueue` waits
+/// or messages are dropped.
+///
+/// By default one batch is sent at a time. Raising the options'
+/// `concurrency` sends several at once, over as many threads, which helps
+/// when sending is slow rather than failing.
+///
+/// Messages which are too large to send, batches which still fail after
+/// retrying, and messages which overflow the queue are dropped. Their number
+/// is counted by `dropped`, and registering
+/// [`Hooks`](../hooks/trait.Hooks.html) in the sender's options tells you as
+/// it happens.
+pub struct BufferedSender {
+    queue: Arc<Queue>,
+    hooks: Option<Arc<dyn Hooks>>,
+    ordered_per_user: bool,
+    workers: Vec<JoinHandle<()>>,
+}
+
+/// The queue between a `BufferedSender` and its background threads, with a
+/// partition for each thread.
+struct Queue {
+    state: Mutex<State>,
+    /// Signalled when items are pushed to each partition, or the queue is
+    /// closed.
+    pushed: Vec<Condvar>,
+    /// Signalled when messages are popped.
+    popped: Condvar,
+    dropped: AtomicU64,
+    max_len: usize,
+    max_bytes: usize,
+    policy: OverflowPolicy,
+}
+
+struct State {
+    partitions: Vec<VecDeque<Item>>,
+    /// The number and size of the messages in `partitions`.
+    len: usize,
+    bytes: usize,
+    /// The number and size of the messages in each worker's batch.
+    batched: Vec<(usize, usize)>,
+    /// The sequence number of the next message, to find the oldest.
+    seq: u64,
+    closed: bool,
+    /// Whether each partition's worker is still running. A worker which
+    /// panicked leaves its partition dead.
+    alive: Vec<bool>,
+}
+
+enum Item {
+    Message {
+        msg: Box<BatchMessage>,
+        serialized: Vec<u8>,
+        seq: u64,
+    },
+    Flush(mpsc::Sender<()>),
+}
+
+impl State {
+    fn depth(&self) -> QueueDepth {
+        QueueDepth {
+            messages: self.len + self.batched.iter().map(|b| b.0).sum::<usize>(),
+            bytes: self.bytes + self.batched.iter().map(|b| b.1).sum::<usize>(),
+        }
+    }
+
+    /// Remove the oldest message in any partition, leaving flushes in place.
+    fn pop_oldest(&mut self) {
+        let oldest = self
+            .partitions
+            .iter()
+            .enumerate()
+            .filter_map(|(p, items)| {
+                items.iter().enumerate().find_map(|(i, item)| match item {
+                    Item::Message { seq, .. } => Some((*seq, p, i)),
+                    Item::Flush(_) => None,
+                })
+            })
+            .min();
+        if let Some((_, p, i)) = oldest {
+            if let Some(Item::Message { serialized, .. }) = self.partitions[p].remove(i) {
+                self.len -= 1;
+                self.bytes -= serialized.len();
+            }
+        }
+    }
+
+    /// Returns the live partition with the fewest items waiting.
+    fn shortest(&self) -> Option<usize> {
+        (0..self.partitions.len())
+            .filter(|&p| self.alive[p])
+            .min_by_key(|&p| self.partitions[p].len())
+    }
+}
+
+impl Queue {
+    fn is_full(&self, state: &State, bytes: usize) -> bool {
+        // Always accept a message into an empty queue, however large.
+        state.len > 0 && (state.len >= self.max_len || state.bytes + bytes > self.max_bytes)
+    }
+}
+
+/// Returns the partition for a user's messages.
+///
+/// The anonymous ID takes precedence, since it's the one which stays the same
+/// when an anonymous user is identified.
+fn partition_for(msg: &BatchMessage, partitions: usize) -> usize {
+    let user = msg.user();
+    let mut hasher = DefaultHasher::new();
+    user.anonymous_id()
+        .or_else(|| user.user_id())
+        .hash(&mut hasher);
+    (hasher.finish() % partitions as u64) as usize
+}
+
+impl BufferedSender {
+    /// Start a sender which sends batches through `client` with `write_key`.
+    pub fn new<C>(client: C, write_key: &str, options: BufferedOptions) -> BufferedSender
+    where
+        C: Client + Send + Sync + 'static,
+    {
+        let concurrency = options.concurrency.max(1);
+        let queue = Arc::new(Queue {
+            state: Mutex::new(State {
+                partitions: (0..concurrency).map(|_| VecDeque::new()).collect(),
+                len: 0,
+                bytes: 0,
+                batched: vec![(0, 0); concurrency],
+                seq: 0,
+                closed: false,
+                alive: vec![true; concurrency],
+            }),
+            pushed: (0..concurrency).map(|_| Condvar::new()).collect(),
+            popped: Condvar::new(),
+            dropped: AtomicU64::new(0),
+            max_len: options.max_queue_len.max(1),
+            max_bytes: options.max_queue_bytes,
+            policy: options.overflow_policy,
+        });
+        let client = Arc::new(client);
+        let workers = (0..concurrency)
+            .map(|partition| {
+                let worker = Worker {
+                    client: Arc::clone(&client),
+                    write_key: write_key.to_owned(),
+                    batcher: Batcher::new(options.context.clone()),
+                    deadline: None,
+                    queue: Arc::clone(&queue),
+                    partition,
+                    options: options.clone(),
+                };
+                thread::spawn(move || worker.run())
+            })
+            .collect();
+
+        BufferedSender {
+            queue,
+            hooks: options.hooks,
+            ordered_per_user: options.ordered_per_user,
+            workers,
+        }
+    }
+
+    /// Queue a message to be sent.
+    ///
+    /// If the queue is full, this waits or drops messages according to the
+    /// sender's `OverflowPolicy`. Returns `Error::QueueFull` if the message
+    /// itself is dropped for lack of room, `Error::MessageTooLarge` if it is
+    /// too large to send, or `Error::Closed` if the background threads have
+    /// stopped.
+    pub fn enqueue(&self, msg: BatchMessage) -> Result<(), Error> {
+        self.push(msg, true)
+    }
+
+    /// Queue a message to be sent, without ever waiting for room.
+    ///
+    /// This is `enqueue`, except that when the queue is full under the
+    /// `Block` and `BlockTimeout` policies the message is dropped at once,
+    /// as under `DropNewest`.
+    pub fn try_enqueue(&self, msg: BatchMessage) -> Result<(), Error> {
+        self.push(msg, false)
+    }
+
+    fn push(&self, msg: BatchMessage, wait: bool) -> Result<(), Error> {
+        if let Some(ref hooks) = self.hooks {
+            hooks.on_enqueue(&msg);
+        }
+
+        let serialized = match batcher::serialize(&msg) {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                let reason = match e.downcast_ref::<AnalyticsError>() {
+                    Some(AnalyticsError::MessageTooLarge) => DropReason::TooLarge,
+                    _ => DropReason::Invalid(e.to_string()),
+                };
+                self.record_drop(1, reason);
+                return Err(e);
+            }
+        };
+
+        let queue = &self.queue;
+        let mut state = queue.state.lock().unwrap();
+        let mut evicted = 0;
+        if !state.closed && queue.is_full(&state, serialized.len()) {
+            match queue.policy {
+                OverflowPolicy::Block if wait => {
+                    state = queue
+                        .popped
+                        .wait_while(state, |state| {
+                            !state.closed && queue.is_full(state, serialized.len())
+                        })
+                        .unwrap();
+                }
+                OverflowPolicy::BlockTimeout(timeout) if wait => {
+                    state = queue
+                        .popped
+                        .wait_timeout_while(state, timeout, |state| {
+                            !state.closed && queue.is_full(state, serialized.len())
+                        })
+                        .unwrap()
+                        .0;
+                }
+                OverflowPolicy::DropOldest => {
+                    while queue.is_full(&state, serialized.len()) {
+                        state.pop_oldest();
+                        evicted += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let partition = if self.ordered_per_user {
+            Some(partition_for(&msg, state.partitions.len())).filter(|&p| state.alive[p])
+        } else {
+            state.shortest()
+        };
+        let result = if state.closed {
+            Err(AnalyticsError::Closed)
+        } else if queue.is_full(&state, serialized.len()) {
+            Err(AnalyticsError::QueueFull)
+        } else if let Some(partition) = partition {
+            state.len += 1;
+            state.bytes += serialized.len();
+            state.seq += 1;
+            let seq = state.seq;
+            state.partitions[partition].push_back(Item::Message {
+                msg: Box::new(msg),
+                serialized,
+                seq,
+            });
+            queue.pushed[partition].notify_one();
+            Ok(())
+        } else {
+            // The partition's worker has died.
+            Err(AnalyticsError::Closed)
+        };
+        let depth = state.depth();
+        drop(state);
+
+        if evicted > 0 {
+            self.record_drop(evicted, DropReason::QueueFull);
+        }
+        match result {
+            Ok(()) => {
+                if let Some(ref hooks) = self.hooks {
+                    hooks.on_queue_depth(&depth);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let reason = match e {
+                    AnalyticsError::Closed => DropReason::Closed,
+                    _ => DropReason::QueueFull,
+                };
+                self.record_drop(1, reason);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Send every message queued so far, waiting until they have been sent or
+    /// dropped.
+    ///
+    /// Returns `Error::Closed` if the sender has stopped, or if a background
+    /// thread has died, such as from a panicking client or hook.
+    pub fn flush(&self) -> Result<(), Error> {
+        let (done, wait) = mpsc::channel();
+        let partitions = {
+            let mut state = self.queue.state.lock().unwrap();
+            if state.closed || state.alive.contains(&false) {
+                return Err(AnalyticsError::Closed.into());
+            }
+            for (items, pushed) in state.partitions.iter_mut().zip(&self.queue.pushed) {
+                items.push_back(Item::Flush(done.clone()));
+                pushed.notify_one();
+            }
+            state.partitions.len()
+        };
+        // Only the workers hold senders now, so a worker which dies without
+        // answering ends the wait.
+        drop(done);
+        for _ in 0..partitions {
+            wait.recv().map_err(|_| AnalyticsError::Closed)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of messages this sender has dropped, for any
+    /// reason.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record_drop(&self, count: usize, reason: DropReason) {
+        dropped(&self.queue, &self.hooks, count, reason);
+    }
+}
+
+impl Drop for BufferedSender {
+    fn drop(&mut self) {
+        // Closing the queue tells the workers to send what they have and stop.
+        self.queue.state.lock().unwrap().closed = true;
+        for pushed in &self.queue.pushed {
+            pushed.notify_all();
+        }
+        self.queue.popped.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Count dropped messages, and tell the hooks about them.
+fn dropped(queue: &Queue, hooks: &Option<Arc<dyn Hooks>>, count: usize, reason: DropReason) {
+    queue.dropped.fetch_add(count as u64, Ordering::Relaxed);
+    if let Some(ref hooks) = hooks {
+        hooks.on_drop(&Dropped { count, reason });
+    }
+}
+
+/// What the worker should do next.
+enum Next {
+    Item(Item),
+    Timeout,
+    Closed,
+}
+
+/// One of the background threads of a `BufferedSender`, which sends the
+/// messages in one partition of the queue.
+struct Worker<C> {
+    client: Arc<C>,
+    write_key: String,
+    batcher: Batcher,
+    deadline: Option<Instant>,
+    queue: Arc<Queue>,
+    partition: usize,
+    options: BufferedOptions,
+}
+
+/// Marks a worker's partition dead when the worker stops, dropping whatever
+/// was left in it, so that a panic doesn't leave messages and flushes
+/// waiting on a thread which is gone.
+struct Alive {
+    queue: Arc<Queue>,
+    partition: usize,
+    hooks: Option<Arc<dyn Hooks>>,
+    /// The flush being answered, dropped only once the partition is marked
+    /// dead, so the flush can't see it still alive.
+    flushing: Option<mpsc::Sender<()>>,
+}
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        let lost = {
+            let mut state = match self.queue.state.lock() {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            state.alive[self.partition] = false;
+            let mut lost = std::mem::take(&mut state.batched[self.partition]).0;
+            for item in std::mem::take(&mut state.partitions[self.partition]) {
+                if let Item::Message { serialized, .. } = item {
+                    state.len -= 1;
+                    state.bytes -= serialized.len();
+                    lost += 1;
+                }
+            }
+            lost
+        };
+        self.queue.popped.notify_all();
+
+        if lost > 0 {
+            // A hook may be what panicked, so don't call it again.
+            let hooks = if thread::panicking() {
+                &None
+            } else {
+                &self.hooks
+            };
+            dropped(&self.queue, hooks, lost, DropReason::Closed);
+        }
+    }
+}
+
+impl<C: Client> Worker<C> {
+    fn run(mut self) {
+        let mut alive = Alive {
+            queue: Arc::clone(&self.queue),
+            partition: self.partition,
+            hooks: self.options.hooks.clone(),
+            flushing: None,
+        };
+        loop {
+            match self.next() {
+                Next::Item(Item::Message {
+                    msg, serialized, ..
+                }) => self.push(*msg, &serialized),
+                Next::Item(Item::Flush(done)) => {
+                    alive.flushing = Some(done);
+                    self.send();
+                    if let Some(done) = alive.flushing.take() {
+                        let _ = done.send(());
+                    }
+                }
+                Next::Timeout => self.send(),
+                Next::Closed => {
+                    self.send();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Wait for the next item in the worker's partition, or for the current
+    /// batch's deadline to pass.
+    fn next(&self) -> Next {
+        let pushed = &self.queue.pushed[self.partition];
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.partitions[self.partition].pop_front() {
+                if let Item::Message { ref serialized, .. } = item {
+                    state.len -= 1;
+                    state.bytes -= serialized.len();
+                    self.queue.popped.notify_all();
+                }
+                return Next::Item(item);
+            }
+            if state.closed {
+                return Next::Closed;
+            }
+
+            state = match self.deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Next::Timeout;
+                    }
+                    pushed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => pushed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn push(&mut self, msg: BatchMessage, serialized: &[u8]) {
+        match self.batcher.push_serialized(msg, serialized) {
+            Ok(None) => {}
+            Ok(Some(msg)) => {
+                self.send();
+                let _ = self.batcher.push_serialized(msg, serialized);
+            }
+            Err(_) => {
+                // `enqueue` has already checked the message's size.
+                self.record_drop(1, DropReason::TooLarge);
+                return;
+            }
+        }
+
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.options.flush_interval);
+        }
+        if self.batcher.len() >= self.options.max_batch_len {
+            self.send();
+        } else {
+            self.queue_depth();
+        }
+    }
+
+    fn send(&mut self) {
+        self.deadline = None;
+        let batch = match self.batcher.flush_serialized() {
+            Some(batch) => batch,
+            None => return,
+        };
+
+        let start = Instant::now();
+        let result = self.options.retry_policy.run(|number| {
+            let start = Instant::now();
+            let result = self
+                .client
+                .send_serialized_with_status(&self.write_key, &batch);
+            if let Some(ref hooks) = self.options.hooks {
+                let status = match result {
+                    Ok(status) => status,
+                    Err(ref e) => match e.downcast_ref::<AnalyticsError>() {
+                        Some(AnalyticsError::Status { status, .. }) => Some(*status),
+                        _ => None,
+                    },
+                };
+                hooks.on_attempt(&Attempt {
+                    number,
+                    count: batch.len(),
+                    latency: start.elapsed(),
+                    status,
+                    error: result.as_ref().err(),
+                });
+            }
+            result.map(|_| ())
+        });
+        self.queue_depth();
+
+        match result {
+            Ok(()) => {
+                if let Some(ref hooks) = self.options.hooks {
+                    hooks.on_batch_sent(&BatchSent {
+                        count: batch.len(),
+                        bytes: batch.as_bytes().len(),
+                        latency: start.elapsed(),
+                    });
+                }
+            }
+            Err((error, attempts)) => {
+                if let Some(ref hooks) = self.options.hooks {
+                    hooks.on_batch_failed(&BatchFailed {
+                        count: batch.len(),
+                        error: &error,
+                        attempts,
+                    });
+                }
+                self.record_drop(batch.len(), DropReason::SendFailed);
+            }
+        }
+    }
+
+    fn queue_depth(&self) {
+        let depth = {
+            let mut state = self.queue.state.lock().unwrap();
+            state.batched[self.partition] = (self.batcher.len(), self.batcher.byte_size());
+            state.depth()
+        };
+        if let Some(ref hooks) = self.options.hooks {
+            hooks.on_queue_depth(&depth);
+        }
+    }
+
+    fn record_drop(&self, count: usize, reason: DropReason) {
+        dropped(&self.queue, &self.options.hooks, count, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    use crate::hooks::BatchSent;
+    use crate::message::{Message, Track, User};
+    use crate::testing::MemoryClient;
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    use crate::testing::{MockResponse, MockServer};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    fn track(i: usize) -> BatchMessage {
+        BatchMessage::Track(Track {
+            user: User::UserId {
+                user_id: format!("user-{}", i),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_batches_by_len_and_flush() {
+        let client = Arc::new(MemoryClient::new());
+        let sender = BufferedSender::new(
+            client.clone(),
+            "write_key",
+            BufferedOptions {
+                flush_interval: Duration::from_secs(60),
+                max_batch_len: 2,
+                ..Default::default()
+            },
+        );
+
+        for i in 0..5 {
+            sender.enqueue(track(i)).unwrap();
+        }
+        sender.flush().unwrap();
+
+        let sent = client.sent();
+        assert_eq!(3, sent.len());
+        assert!(sent.iter().all(|(write_key, _)| write_key == "write_key"));
+        assert_eq!(
+            (0..5).map(track).collect::<Vec<_>>(),
+            client.messages_for("write_key")
+        );
+    }
+
+    #[test]
+    fn test_flushes_on_interval_and_drop() {
+        let client = Arc::new(MemoryClient::new());
+        let sender = BufferedSender::new(
+            client.clone(),
+            "write_key",
+            BufferedOptions {
+                flush_interval: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+
+        sender.enqueue(track(0)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(1, client.len());
+
+        sender.enqueue(track(1)).unwrap();
+        drop(sender);
+        assert_eq!(2, client.sent().len());
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[derive(Default)]
+    struct Record(Mutex<Vec<String>>);
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    impl Hooks for Record {
+        fn on_enqueue(&self, _msg: &BatchMessage) {
+            self.0.lock().unwrap().push("enqueue".to_owned());
+        }
+
+        fn on_attempt(&self, attempt: &Attempt) {
+            self.0.lock().unwrap().push(format!(
+                "attempt {}: {} {:?}",
+                attempt.number,
+                attempt.outcome(),
+                attempt.status
+            ));
+        }
+
+        fn on_batch_sent(&self, sent: &BatchSent) {
+            assert!(sent.bytes > 0);
+            self.0.lock().unwrap().push(format!("sent {}", sent.count));
+        }
+
+        fn on_batch_failed(&self, failed: &BatchFailed) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("failed {} after {}", failed.count, failed.attempts));
+        }
+
+        fn on_drop(&self, dropped: &Dropped) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("dropped {}: {}", dropped.count, dropped.reason));
+        }
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[test]
+    fn test_hooks() {
+        let server = MockServer::start();
+        let hooks = Arc::new(Record::default());
+        let sender = BufferedSender::new(
+            server.client(),
+            "write_key",
+            BufferedOptions {
+                flush_interval: Duration::from_secs(60),
+                retry_policy: RetryPolicy {
+                    max_attempts: 2,
+                    initial_backoff: Duration::from_millis(1),
+                    ..Default::default()
+                },
+                hooks: Some(hooks.clone()),
+                ..Default::default()
+            },
+        );
+
+        sender.enqueue(track(0)).unwrap();
+        sender.enqueue(track(1)).unwrap();
+        sender.flush().unwrap();
+
+        server.enqueue(MockResponse::status(500));
+        server.enqueue(MockResponse::status(500));
+        sender.enqueue(track(2)).unwrap();
+        assert!(sender
+            .enqueue(BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: "a".repeat(1024 * 33),
+                },
+                ..Default::default()
+            }))
+            .is_err());
+        sender.flush().unwrap();
+
+        assert_eq!(
+            vec![
+                "enqueue",
+                "enqueue",
+                "attempt 1: success Some(200)",
+                "sent 2",
+                "enqueue",
+                "enqueue",
+                "dropped 1: message too large",
+                "attempt 1: http_error Some(500)",
+                "attempt 2: http_error Some(500)",
+                "failed 1 after 2",
+                "dropped 1: send failed",
+            ],
+            *hooks.0.lock().unwrap()
+        );
+        assert_eq!(2, sender.dropped());
+    }
+
+    /// A client which takes a while to send, and records how many batches it
+    /// was sending at once.
+    #[derive(Default)]
+    struct Slow {
+        memory: MemoryClient,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl Client for Slow {
+        fn send(&self, write_key: &str, msg: &crate::message::Message) -> Result<(), Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            let result = self.memory.send(write_key, msg);
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    #[test]
+    fn test_concurrency() {
+        for &ordered_per_user in &[true, false] {
+            let client = Arc::new(Slow::default());
+            let sender = BufferedSender::new(
+                client.clone(),
+                "write_key",
+                BufferedOptions {
+                    max_batch_len: 1,
+                    concurrency: 4,
+                    ordered_per_user,
+                    ..Default::default()
+                },
+            );
+
+            let msgs: Vec<_> = (0..64)
+                .map(|i| {
+                    BatchMessage::Track(Track {
+                        user: User::UserId {
+                            user_id: format!("user-{}", i % 16),
+                        },
+                        event: (i / 16).to_string(),
+                        ..Default::default()
+                    })
+                })
+                .collect();
+            for msg in &msgs {
+                sender.enqueue(msg.clone()).unwrap();
+            }
+            sender.flush().unwrap();
+
+            let mut sent = client.memory.messages_for("write_key");
+            assert!(client.max_in_flight.load(Ordering::SeqCst) > 1);
+            if ordered_per_user {
+                // Each user's events arrive in order.
+                sent.sort_by_key(|msg| msg.user().user_id().map(str::to_owned));
+                let mut expected = msgs.clone();
+                expected.sort_by_key(|msg| msg.user().user_id().map(str::to_owned));
+                assert_eq!(expected, sent);
+            } else {
+                assert_eq!(msgs.len(), sent.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_ordered_across_identify() {
+        let client = Arc::new(Slow::default());
+        let sender = BufferedSender::new(
+            client.clone(),
+            "write_key",
+            BufferedOptions {
+                max_batch_len: 1,
+                concurrency: 4,
+                ..Default::default()
+            },
+        );
+
+        // Each anonymous user is identified half-way through their events.
+        let msgs: Vec<_> = (0..64)
+            .map(|i| {
+                let anonymous_id = format!("anonymous-{}", i % 16);
+                let user = if i < 32 {
+                    User::AnonymousId { anonymous_id }
+                } else {
+                    User::Both {
+                        user_id: format!("user-{}", i % 16),
+                        anonymous_id,
+                    }
+                };
+                BatchMessage::Track(Track {
+                    user,
+                    event: (i / 16).to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        for msg in &msgs {
+            sender.enqueue(msg.clone()).unwrap();
+        }
+        sender.flush().unwrap();
+
+        let key = |msg: &BatchMessage| msg.user().anonymous_id().map(str::to_owned);
+        let mut sent = client.memory.messages_for("write_key");
+        sent.sort_by_key(key);
+        let mut expected = msgs.clone();
+        expected.sort_by_key(key);
+        assert_eq!(expected, sent);
+    }
+
+    /// A client which holds up sending until `gate` is unlocked.
+    struct Stall {
+        memory: MemoryClient,
+        entered: Mutex<mpsc::Sender<()>>,
+        gate: Arc<Mutex<()>>,
+    }
+
+    impl Client for Stall {
+        fn send(&self, write_key: &str, msg: &crate::message::Message) -> Result<(), Error> {
+            let _ = self.entered.lock().unwrap().send(());
+            let _gate = self.gate.lock().unwrap();
+            self.memory.send(write_key, msg)
+        }
+    }
+
+    /// Send `track(0)`, then enqueue three more messages into a queue with
+    /// room for two while the first is stuck being sent.
+    fn overflow(policy: OverflowPolicy) -> (Vec<bool>, Vec<BatchMessage>, u64) {
+        let (entered, wait) = mpsc::channel();
+        let gate = Arc::new(Mutex::new(()));
+        let client = Arc::new(Stall {
+            memory: MemoryClient::new(),
+            entered: Mutex::new(entered),
+            gate: gate.clone(),
+        });
+        let sender = BufferedSender::new(
+            client.clone(),
+            "write_key",
+            BufferedOptions {
+                max_batch_len: 1,
+                max_queue_len: 2,
+                overflow_policy: policy,
+                ..Default::default()
+            },
+        );
+
+        let held = gate.lock().unwrap();
+        sender.enqueue(track(0)).unwrap();
+        wait.recv().unwrap();
+        let results = (1..4).map(|i| sender.enqueue(track(i)).is_ok()).collect();
+        drop(held);
+        sender.flush().unwrap();
+
+        let dropped = sender.dropped();
+        (results, client.memory.messages_for("write_key"), dropped)
+    }
+
+    #[test]
+    fn test_overflow_drop_newest() {
+        let (results, sent, dropped) = overflow(OverflowPolicy::DropNewest);
+        assert_eq!(vec![true, true, false], results);
+        assert_eq!((0..3).map(track).collect::<Vec<_>>(), sent);
+        assert_eq!(1, dropped);
+    }
+
+    #[test]
+    fn test_overflow_drop_oldest() {
+        let (results, sent, dropped) = overflow(OverflowPolicy::DropOldest);
+        assert_eq!(vec![true, true, true], results);
+        assert_eq!(vec![track(0), track(2), track(3)], sent);
+        assert_eq!(1, dropped);
+    }
+
+    #[test]
+    fn test_overflow_block_timeout() {
+        let start = Instant::now();
+        let (results, sent, dropped) =
+            overflow(OverflowPolicy::BlockTimeout(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(vec![true, true, false], results);
+        assert_eq!((0..3).map(track).collect::<Vec<_>>(), sent);
+        assert_eq!(1, dropped);
+    }
+
+    struct Panics;
+
+    impl Client for Panics {
+        fn send(&self, _: &str, _: &Message) -> Result<(), Error> {
+            panic!("send panicked");
+        }
+    }
+
+    #[test]
+    fn test_worker_panics() {
+        let sender = BufferedSender::new(
+            Panics,
+            "write_key",
+            BufferedOptions {
+                flush_interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+
+        sender.enqueue(track(0)).unwrap();
+        let closed = |result: Result<(), Error>| match result {
+            Err(e) => match e.downcast_ref::<AnalyticsError>() {
+                Some(AnalyticsError::Closed) => {}
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(()) => panic!("succeeded with a dead worker"),
+        };
+        // The worker panics sending the batch, and the flush doesn't wait
+        // for it forever.
+        closed(sender.flush());
+        closed(sender.enqueue(track(1)));
+        closed(sender.flush());
+        assert_eq!(2, sender.dropped());
+    }
+}
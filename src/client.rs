@@ -1,7 +1,9 @@
 //! Interfaces to the Segment tracking API.
 
+use crate::batcher::SerializedBatch;
 use crate::message::Message;
 use failure::Error;
+use std::sync::Arc;
 
 /// `Client` is a trait representing the HTTP transport layer of the analytics library.
 pub trait Client {
@@ -11,4 +13,82 @@ pub trait Client {
     /// documentation](https://segment.com/docs/guides/setup/how-do-i-find-my-write-key/)
     /// for how to find this value.
     fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error>;
+
+    /// Send a batch which has already been serialized by a
+    /// [`Batcher`](../batcher/struct.Batcher.html).
+    ///
+    /// The default implementation decodes the batch and hands it to `send`.
+    /// Transports which can send the serialized body as-is should override
+    /// this.
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        self.send(write_key, &batch.to_message()?)
+    }
+
+    /// Send a serialized batch like `send_serialized`, returning the HTTP
+    /// status of the response if there was one.
+    ///
+    /// The default implementation calls `send_serialized` and returns
+    /// `None`. HTTP transports override this so that hooks can record the
+    /// status of attempts which succeeded.
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        self.send_serialized(write_key, batch).map(|()| None)
+    }
+}
+
+impl<C: Client + ?Sized> Client for &C {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        (**self).send(write_key, msg)
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        (**self).send_serialized(write_key, batch)
+    }
+
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        (**self).send_serialized_with_status(write_key, batch)
+    }
+}
+
+impl<C: Client + ?Sized> Client for Box<C> {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        (**self).send(write_key, msg)
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        (**self).send_serialized(write_key, batch)
+    }
+
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        (**self).send_serialized_with_status(write_key, batch)
+    }
+}
+
+impl<C: Client + ?Sized> Client for Arc<C> {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        (**self).send(write_key, msg)
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        (**self).send_serialized(write_key, batch)
+    }
+
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        (**self).send_serialized_with_status(write_key, batch)
+    }
 }
@@ -0,0 +1,495 @@
+//! Conversion of CSV files into messages.
+//!
+//! A [`Mapping`](struct.Mapping.html) describes which columns of a CSV file
+//! hold which fields of a message. It is usually read from a JSON file:
+//!
+//! ```json
+//! {
+//!     "type": "track",
+//!     "userId": "user_id",
+//!     "event": "action",
+//!     "timestamp": "created_at",
+//!     "properties": { "plan": "plan_name", "seats": "seat_count" },
+//!     "types": { "seat_count": "number" }
+//! }
+//! ```
+//!
+//! Columns mapped to properties or traits are typed automatically: cells
+//! holding `true` or `false` become booleans, cells holding numbers become
+//! numbers, empty cells are left out and everything else is kept as a string.
+//! `types` overrides this for individual columns.
+//!
+//! A [`CsvReader`](struct.CsvReader.html) applies a mapping to each row,
+//! producing `BatchMessage`s ready to be pushed into a
+//! [`Batcher`](../batcher/struct.Batcher.html):
+//!
+//! ```
+//! use analytics::batcher::Batcher;
+//! use analytics::csv::{CsvReader, Mapping};
+//!
+//! let data = "user_id,action,plan_name\nfoo,Signed Up,pro\nbar,Signed Up,free\n";
+//! let mapping: Mapping = serde_json::from_str(
+//!     r#"{ "userId": "user_id", "event": "action", "properties": ["plan_name"] }"#,
+//! )
+//! .unwrap();
+//!
+//! let mut batcher = Batcher::new(None);
+//! for msg in CsvReader::new(data.as_bytes(), &mapping).unwrap() {
+//!     assert!(batcher.push(msg.unwrap()).unwrap().is_none());
+//! }
+//! assert_eq!(2, batcher.len());
+//! ```
+
+use crate::errors::Error as AnalyticsError;
+use crate::message::{Alias, BatchMessage, Group, Identify, Page, Screen, Track, User};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use failure::Error;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// A description of how the columns of a CSV file map onto messages.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Mapping {
+    /// The type of message each row becomes. Defaults to `track`.
+    #[serde(rename = "type", default)]
+    pub message_type: MessageType,
+
+    /// The column holding the user ID.
+    pub user_id: Option<String>,
+
+    /// The column holding the anonymous ID.
+    pub anonymous_id: Option<String>,
+
+    /// The column holding the event name of `track` messages.
+    pub event: Option<String>,
+
+    /// The column holding the name of `page` and `screen` messages.
+    pub name: Option<String>,
+
+    /// The column holding the group ID of `group` messages.
+    pub group_id: Option<String>,
+
+    /// The column holding the previous ID of `alias` messages.
+    pub previous_id: Option<String>,
+
+    /// The column holding the timestamp. RFC 3339 timestamps are accepted, as
+    /// are `YYYY-MM-DD HH:MM:SS` timestamps, which are taken to be UTC.
+    pub timestamp: Option<String>,
+
+    /// The columns which become properties of `track`, `page` and `screen`
+    /// messages.
+    #[serde(default)]
+    pub properties: Columns,
+
+    /// The columns which become traits of `identify` and `group` messages.
+    #[serde(default)]
+    pub traits: Columns,
+
+    /// How to type the values of individual columns, by column name.
+    #[serde(default)]
+    pub types: BTreeMap<String, ColumnType>,
+}
+
+/// The type of message a row of CSV becomes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    Identify,
+    #[default]
+    Track,
+    Page,
+    Screen,
+    Group,
+    Alias,
+}
+
+/// A set of columns which become properties or traits.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Columns {
+    /// Columns whose names are used as the property or trait name.
+    Names(Vec<String>),
+
+    /// Property or trait names, each mapped to the column holding its value.
+    Renamed(BTreeMap<String, String>),
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns::Names(Vec::new())
+    }
+}
+
+impl Columns {
+    fn pairs(&self) -> Vec<(&str, &str)> {
+        match self {
+            Columns::Names(names) => names.iter().map(|n| (n.as_str(), n.as_str())).collect(),
+            Columns::Renamed(map) => map.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+        }
+    }
+}
+
+/// How the value of a column is typed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    /// Infer booleans and numbers, falling back to strings.
+    #[default]
+    Auto,
+    String,
+    Number,
+    Boolean,
+}
+
+/// A `Mapping` whose column names have been resolved to column indexes.
+struct Resolved {
+    message_type: MessageType,
+    user_id: Option<usize>,
+    anonymous_id: Option<usize>,
+    field: Option<(&'static str, usize)>,
+    timestamp: Option<usize>,
+    values: Vec<(String, usize, ColumnType)>,
+}
+
+/// Reads messages out of CSV data according to a `Mapping`.
+///
+/// The first row of the data must be a header naming each column.
+pub struct CsvReader<R> {
+    reader: ::csv::Reader<R>,
+    resolved: Resolved,
+    record: ::csv::StringRecord,
+}
+
+impl<R: Read> CsvReader<R> {
+    /// Construct a reader which applies `mapping` to `reader`.
+    ///
+    /// Returns an error if the data has no header, or if the mapping refers
+    /// to a column which the header does not name.
+    pub fn new(reader: R, mapping: &Mapping) -> Result<CsvReader<R>, Error> {
+        let mut reader = ::csv::Reader::from_reader(reader);
+        let resolved = resolve(reader.headers()?, mapping)?;
+
+        Ok(CsvReader {
+            reader,
+            resolved,
+            record: ::csv::StringRecord::new(),
+        })
+    }
+
+    /// Returns the header row.
+    pub fn headers(&mut self) -> Result<&::csv::StringRecord, Error> {
+        Ok(self.reader.headers()?)
+    }
+
+    /// Returns the row most recently read, such as to report a row which
+    /// could not be converted.
+    pub fn record(&self) -> &::csv::StringRecord {
+        &self.record
+    }
+}
+
+impl<R: Read> Iterator for CsvReader<R> {
+    type Item = Result<BatchMessage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => Some(self.resolved.message(&self.record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+fn resolve(headers: &::csv::StringRecord, mapping: &Mapping) -> Result<Resolved, Error> {
+    let index = |column: &str| {
+        headers
+            .iter()
+            .position(|h| h == column)
+            .ok_or_else(|| AnalyticsError::InvalidMapping(format!("no column named {:?}", column)))
+    };
+    let optional = |column: &Option<String>| column.as_deref().map(index).transpose();
+    let required = |field: &'static str, column: &Option<String>| match column {
+        Some(column) => Ok(Some((field, index(column)?))),
+        None => Err(AnalyticsError::InvalidMapping(format!(
+            "{} messages require a {} column",
+            format!("{:?}", mapping.message_type).to_lowercase(),
+            field
+        ))),
+    };
+
+    let (field, values) = match mapping.message_type {
+        MessageType::Identify => (None, &mapping.traits),
+        MessageType::Track => (required("event", &mapping.event)?, &mapping.properties),
+        MessageType::Page | MessageType::Screen => {
+            (required("name", &mapping.name)?, &mapping.properties)
+        }
+        MessageType::Group => (required("groupId", &mapping.group_id)?, &mapping.traits),
+        MessageType::Alias => (
+            required("previousId", &mapping.previous_id)?,
+            &Columns::default(),
+        ),
+    };
+
+    if mapping.user_id.is_none() && mapping.anonymous_id.is_none() {
+        return Err(AnalyticsError::InvalidMapping(
+            "a userId or anonymousId column is required".to_owned(),
+        )
+        .into());
+    }
+
+    let values = values
+        .pairs()
+        .into_iter()
+        .map(|(name, column)| {
+            let column_type = mapping.types.get(column).cloned().unwrap_or_default();
+            Ok((name.to_owned(), index(column)?, column_type))
+        })
+        .collect::<Result<_, AnalyticsError>>()?;
+
+    Ok(Resolved {
+        message_type: mapping.message_type,
+        user_id: optional(&mapping.user_id)?,
+        anonymous_id: optional(&mapping.anonymous_id)?,
+        field,
+        timestamp: optional(&mapping.timestamp)?,
+        values,
+    })
+}
+
+impl Resolved {
+    fn message(&self, record: &::csv::StringRecord) -> Result<BatchMessage, Error> {
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let invalid = |reason: String| AnalyticsError::InvalidRecord { line, reason };
+        let cell = |i: Option<usize>| {
+            i.and_then(|i| record.get(i))
+                .filter(|cell| !cell.is_empty())
+                .map(str::to_owned)
+        };
+
+        let user = match (cell(self.user_id), cell(self.anonymous_id)) {
+            (Some(user_id), Some(anonymous_id)) => User::Both {
+                user_id,
+                anonymous_id,
+            },
+            (Some(user_id), None) => User::UserId { user_id },
+            (None, Some(anonymous_id)) => User::AnonymousId { anonymous_id },
+            (None, None) => return Err(invalid("missing userId and anonymousId".to_owned()).into()),
+        };
+
+        let field = match self.field {
+            Some((name, i)) => cell(Some(i)).ok_or_else(|| invalid(format!("missing {}", name)))?,
+            None => String::new(),
+        };
+
+        let timestamp = cell(self.timestamp)
+            .map(|t| {
+                parse_timestamp(&t).ok_or_else(|| invalid(format!("invalid timestamp {:?}", t)))
+            })
+            .transpose()?;
+
+        let mut values = Map::new();
+        for (name, i, column_type) in &self.values {
+            if let Some(value) = cell(Some(*i)) {
+                let value = typed(&value, *column_type)
+                    .ok_or_else(|| invalid(format!("{:?} is not a {:?}", value, column_type)))?;
+                values.insert(name.clone(), value);
+            }
+        }
+        let values = Value::Object(values);
+
+        Ok(match self.message_type {
+            MessageType::Identify => BatchMessage::Identify(Identify {
+                user,
+                traits: values,
+                timestamp,
+                ..Default::default()
+            }),
+            MessageType::Track => BatchMessage::Track(Track {
+                user,
+                event: field,
+                properties: values,
+                timestamp,
+                ..Default::default()
+            }),
+            MessageType::Page => BatchMessage::Page(Page {
+                user,
+                name: field,
+                properties: values,
+                timestamp,
+                ..Default::default()
+            }),
+            MessageType::Screen => BatchMessage::Screen(Screen {
+                user,
+                name: field,
+                properties: values,
+                timestamp,
+                ..Default::default()
+            }),
+            MessageType::Group => BatchMessage::Group(Group {
+                user,
+                group_id: field,
+                traits: values,
+                timestamp,
+                ..Default::default()
+            }),
+            MessageType::Alias => BatchMessage::Alias(Alias {
+                user,
+                previous_id: field,
+                timestamp,
+                ..Default::default()
+            }),
+        })
+    }
+}
+
+fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").map(|t| t.and_utc()))
+        .ok()
+}
+
+fn typed(s: &str, column_type: ColumnType) -> Option<Value> {
+    match column_type {
+        ColumnType::String => Some(Value::String(s.to_owned())),
+        ColumnType::Number => number(s),
+        ColumnType::Boolean => boolean(s),
+        ColumnType::Auto => boolean(s)
+            .or_else(|| number(s))
+            .or_else(|| Some(Value::String(s.to_owned()))),
+    }
+}
+
+fn boolean(s: &str) -> Option<Value> {
+    match s {
+        "true" | "TRUE" | "True" => Some(Value::Bool(true)),
+        "false" | "FALSE" | "False" => Some(Value::Bool(false)),
+        _ => None,
+    }
+}
+
+fn number(s: &str) -> Option<Value> {
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(Value::Number(n.into()));
+    }
+
+    s.parse::<f64>()
+        .ok()
+        .and_then(Number::from_f64)
+        .map(Value::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn read(data: &str, mapping: &str) -> Vec<Result<BatchMessage, Error>> {
+        let mapping: Mapping = serde_json::from_str(mapping).unwrap();
+        CsvReader::new(data.as_bytes(), &mapping).unwrap().collect()
+    }
+
+    #[test]
+    fn test_track() {
+        let messages = read(
+            "id,anon,action,at,plan,seats,trial,zip\n\
+             foo,,Signed Up,2019-06-01T12:00:00Z,pro,3,false,02134\n\
+             ,bar,Signed Up,2019-06-01 12:00:00,,2.5,TRUE,\n",
+            r#"{
+                "userId": "id",
+                "anonymousId": "anon",
+                "event": "action",
+                "timestamp": "at",
+                "properties": { "plan": "plan", "seats": "seats", "trial": "trial", "zip": "zip" },
+                "types": { "zip": "string" }
+            }"#,
+        );
+
+        let timestamp = Some(Utc.with_ymd_and_hms(2019, 6, 1, 12, 0, 0).unwrap());
+        assert_eq!(
+            BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned()
+                },
+                event: "Signed Up".to_owned(),
+                properties: json!({ "plan": "pro", "seats": 3, "trial": false, "zip": "02134" }),
+                timestamp,
+                ..Default::default()
+            }),
+            *messages[0].as_ref().unwrap()
+        );
+        assert_eq!(
+            BatchMessage::Track(Track {
+                user: User::AnonymousId {
+                    anonymous_id: "bar".to_owned()
+                },
+                event: "Signed Up".to_owned(),
+                properties: json!({ "seats": 2.5, "trial": true }),
+                timestamp,
+                ..Default::default()
+            }),
+            *messages[1].as_ref().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_identify_names() {
+        let messages = read(
+            "id,plan\nfoo,pro\n",
+            r#"{ "type": "identify", "userId": "id", "traits": ["plan"] }"#,
+        );
+
+        assert_eq!(
+            BatchMessage::Identify(Identify {
+                user: User::UserId {
+                    user_id: "foo".to_owned()
+                },
+                traits: json!({ "plan": "pro" }),
+                ..Default::default()
+            }),
+            *messages[0].as_ref().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        let messages = read(
+            "id,action,seats\nfoo,,1\n,Signed Up,1\nfoo,Signed Up,many\n",
+            r#"{ "userId": "id", "event": "action", "properties": ["seats"], "types": { "seats": "number" } }"#,
+        );
+
+        let lines: Vec<u64> = messages
+            .into_iter()
+            .map(
+                |m| match m.err().unwrap().downcast::<AnalyticsError>().unwrap() {
+                    AnalyticsError::InvalidRecord { line, .. } => line,
+                    e => panic!("unexpected error: {}", e),
+                },
+            )
+            .collect();
+        assert_eq!(vec![2, 3, 4], lines);
+    }
+
+    #[test]
+    fn test_invalid_mapping() {
+        let invalid = |mapping: &str| {
+            let mapping: Mapping = serde_json::from_str(mapping).unwrap();
+            let err = CsvReader::new("id,action\n".as_bytes(), &mapping)
+                .err()
+                .unwrap();
+            match err.downcast::<AnalyticsError>().unwrap() {
+                AnalyticsError::InvalidMapping(_) => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        };
+
+        invalid(r#"{ "userId": "id" }"#);
+        invalid(r#"{ "event": "action" }"#);
+        invalid(r#"{ "userId": "missing", "event": "action" }"#);
+        invalid(r#"{ "userId": "id", "event": "action", "properties": ["missing"] }"#);
+    }
+}
@@ -0,0 +1,209 @@
+//! Configuration of where to send messages.
+//!
+//! Segment operates its tracking API in multiple regions; a workspace's data
+//! must be sent to the region that workspace lives in. See [Segment's
+//! documentation](https://segment.com/docs/guides/regional-segment/) for which
+//! endpoint to use.
+
+use crate::errors::Error as AnalyticsError;
+use failure::Error;
+use std::fmt;
+use std::str::FromStr;
+use url::Url;
+
+/// A region in which Segment operates its tracking API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The United States, Segment's default region.
+    Us,
+
+    /// The European Union.
+    Eu,
+}
+
+impl Region {
+    /// Returns the base URL of the tracking API in this region.
+    pub fn base_url(self) -> &'static str {
+        match self {
+            Region::Us => "https://api.segment.io",
+            Region::Eu => "https://events.eu1.segmentapis.com",
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "us" => Ok(Region::Us),
+            "eu" => Ok(Region::Eu),
+            _ => Err(AnalyticsError::InvalidEndpoint(format!("unknown region: {}", s)).into()),
+        }
+    }
+}
+
+/// The base URL of a Segment-compatible tracking API.
+///
+/// An endpoint is either one of Segment's [`Region`](enum.Region.html)s, or a
+/// custom base URL such as a proxy or self-hosted collector. Custom base URLs
+/// may include a path prefix, which is kept in front of the tracking API's
+/// paths:
+///
+/// ```
+/// use analytics::endpoint::{Endpoint, Region};
+///
+/// let endpoint = Endpoint::from(Region::Eu);
+/// assert_eq!(
+///     "https://events.eu1.segmentapis.com/v1/track",
+///     endpoint.url("/v1/track"),
+/// );
+///
+/// let endpoint = Endpoint::new("https://collector.example.com/segment/").unwrap();
+/// assert_eq!(
+///     "https://collector.example.com/segment/v1/track",
+///     endpoint.url("/v1/track"),
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    base: String,
+}
+
+impl Endpoint {
+    /// Construct an endpoint from a custom base URL.
+    ///
+    /// Returns an error unless `base` is an absolute `http` or `https` URL
+    /// with a host and without a query string or fragment.
+    pub fn new(base: &str) -> Result<Endpoint, Error> {
+        let invalid =
+            |reason: &str| AnalyticsError::InvalidEndpoint(format!("{}: {}", reason, base));
+
+        let url = Url::parse(base).map_err(|e| invalid(&e.to_string()))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(invalid("scheme must be http or https").into());
+        }
+        if url.host().is_none() {
+            return Err(invalid("missing host").into());
+        }
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(invalid("query strings and fragments are not supported").into());
+        }
+
+        Ok(Endpoint {
+            base: url.as_str().trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Returns the full URL of the given tracking API path, such as
+    /// `/v1/batch`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    /// Returns the base URL of this endpoint, without a trailing slash.
+    pub fn as_str(&self) -> &str {
+        &self.base
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Region::Us.into()
+    }
+}
+
+impl From<Region> for Endpoint {
+    fn from(region: Region) -> Self {
+        Endpoint {
+            base: region.base_url().to_owned(),
+        }
+    }
+}
+
+/// Use a base URL as an endpoint as-is.
+///
+/// Unlike [`Endpoint::new`](struct.Endpoint.html#method.new), the URL isn't
+/// validated; only a trailing slash is removed. This keeps passing a plain
+/// host such as `"https://api.segment.io".to_owned()` to
+/// [`HttpClient::new`](../http/struct.HttpClient.html#method.new) working.
+impl From<String> for Endpoint {
+    fn from(base: String) -> Self {
+        Endpoint {
+            base: base.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+/// Use a base URL as an endpoint as-is; see `From<String>`.
+impl From<&str> for Endpoint {
+    fn from(base: &str) -> Self {
+        Endpoint::from(base.to_owned())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Endpoint::new(s)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions() {
+        assert_eq!(Endpoint::default(), Endpoint::from(Region::Us));
+        assert_eq!(
+            "https://api.segment.io/v1/batch",
+            Endpoint::from(Region::Us).url("/v1/batch")
+        );
+        assert_eq!(Region::Eu, "EU".parse().unwrap());
+        assert!("mars".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn test_custom() {
+        let endpoint = Endpoint::new("http://localhost:8080").unwrap();
+        assert_eq!("http://localhost:8080/v1/track", endpoint.url("/v1/track"));
+
+        let endpoint = Endpoint::new("https://example.com/a/b/").unwrap();
+        assert_eq!(
+            "https://example.com/a/b/v1/track",
+            endpoint.url("/v1/track")
+        );
+    }
+
+    #[test]
+    fn test_from_string() {
+        let endpoint = Endpoint::from("http://localhost:8080/".to_owned());
+        assert_eq!("http://localhost:8080/v1/track", endpoint.url("/v1/track"));
+        assert_eq!(endpoint, Endpoint::from("http://localhost:8080"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for base in &[
+            "api.segment.io",
+            "ftp://api.segment.io",
+            "https://api.segment.io?foo=bar",
+            "https://api.segment.io#foo",
+            "unix:/tmp/socket",
+        ] {
+            let err = Endpoint::new(base).err().unwrap();
+            match err.as_fail().downcast_ref().unwrap() {
+                AnalyticsError::InvalidEndpoint(_) => {}
+                _ => panic!("unexpected error: {}", err),
+            }
+        }
+    }
+}
@@ -1,12 +1,92 @@
 //! Errors which may arise from this crate.
 
+// `failure`'s derive expands to impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
+use crate::fanout::TargetErrors;
+use crate::http::ApiResponse;
 use failure::Fail;
 
 /// An enum of errors this crate may produce. These are compatible with
 /// `failure` errors.
+///
+/// More variants may be added in future releases.
 #[derive(Debug, Fail)]
+#[non_exhaustive]
 pub enum Error {
     /// The given message is too large to be sent to Segment's API.
     #[fail(display = "message too large")]
     MessageTooLarge,
+
+    /// A global analytics backend has already been installed.
+    #[fail(display = "an analytics backend is already installed")]
+    AlreadyInstalled,
+
+    /// A `BufferedSender`'s background threads have stopped.
+    #[fail(display = "sender is closed")]
+    Closed,
+
+    /// A `BufferedSender`'s queue was full, so a message was dropped.
+    #[fail(display = "queue is full")]
+    QueueFull,
+
+    /// A `CircuitBreaker` is open, so a message was not sent.
+    #[fail(display = "circuit breaker is open")]
+    CircuitOpen,
+
+    /// The given endpoint is not a valid base URL for the tracking API.
+    #[fail(display = "invalid endpoint: {}", _0)]
+    InvalidEndpoint(String),
+
+    /// The given CSV mapping does not fit the CSV data it was applied to.
+    #[fail(display = "invalid mapping: {}", _0)]
+    InvalidMapping(String),
+
+    /// A row of CSV data could not be converted into a message.
+    #[fail(display = "invalid record on line {}: {}", line, reason)]
+    InvalidRecord { line: u64, reason: String },
+
+    /// A line of an archive file could not be parsed as a message.
+    #[fail(display = "invalid line {} of {}: {}", line, path, reason)]
+    InvalidArchive {
+        path: String,
+        line: u64,
+        reason: String,
+    },
+
+    /// A `FanoutClient` failed to send to one or more of its targets, and
+    /// sent to `delivered` others.
+    #[fail(display = "fan-out failed: {}", errors)]
+    FanoutFailed {
+        errors: TargetErrors,
+        delivered: usize,
+    },
+
+    /// The tracking API responded with a status other than `2xx`, or
+    /// rejected the whole request.
+    #[fail(
+        display = "tracking API responded with status {}: {}",
+        status, response
+    )]
+    Status { status: u16, response: ApiResponse },
+
+    /// A header given to an `HttpClientBuilder` is not a valid HTTP header.
+    #[fail(display = "invalid header: {}", _0)]
+    InvalidHeader(String),
+
+    /// An `HttpBackend` doesn't support an option it was configured with.
+    #[fail(display = "{} is not supported by the {} backend", option, backend)]
+    Unsupported {
+        option: &'static str,
+        backend: &'static str,
+    },
+
+    /// A request made to a tracking API server could not be understood.
+    #[fail(display = "invalid request: {}", _0)]
+    InvalidRequest(String),
+
+    /// The body of a request made to a tracking API server was larger than
+    /// `ingest::MAX_BODY`, before or after decompression.
+    #[fail(display = "request body too large")]
+    BodyTooLarge,
 }
@@ -0,0 +1,392 @@
+//! Sending every message to several destinations.
+
+use crate::batcher::SerializedBatch;
+use crate::client::Client;
+use crate::errors::Error as AnalyticsError;
+use crate::message::Message;
+use crate::retry::RetryPolicy;
+use failure::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+
+/// A client which sends each message to several targets, such as two Segment
+/// sources being dual-written during a migration and a self-hosted
+/// collector.
+///
+/// Messages are sent to all targets concurrently, and each target retries
+/// according to its own `RetryPolicy`. The first target is the primary:
+///
+/// ```no_run
+/// use analytics::endpoint::{Endpoint, Region};
+/// use analytics::fanout::{FanoutClient, Mode, Target};
+/// use analytics::http::HttpClient;
+/// use analytics::retry::RetryPolicy;
+///
+/// let eu = HttpClient::builder()
+///     .with_endpoint(Region::Eu.into())
+///     .build()
+///     .unwrap();
+/// let collector = HttpClient::builder()
+///     .with_endpoint(Endpoint::new("https://collector.internal").unwrap())
+///     .build()
+///     .unwrap();
+///
+/// let client = FanoutClient::new(Target::new("old", HttpClient::default()))
+///     .with_target(Target::new("new", eu).with_write_key("NEW_WRITE_KEY"))
+///     .with_target(Target::new("collector", collector).with_retry_policy(RetryPolicy::none()))
+///     .with_mode(Mode::PrimaryOnly);
+/// ```
+pub struct FanoutClient {
+    targets: Vec<Target>,
+    mode: Mode,
+}
+
+/// Which failures cause a `FanoutClient` to fail a send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Fail if any target fails.
+    #[default]
+    All,
+
+    /// Fail only if the primary target fails. Failures of other targets are
+    /// still recorded in their `TargetStats`.
+    PrimaryOnly,
+}
+
+/// A destination of a `FanoutClient`.
+pub struct Target {
+    name: String,
+    client: Box<dyn Client + Send + Sync>,
+    write_key: Option<String>,
+    policy: RetryPolicy,
+    stats: Mutex<TargetStats>,
+}
+
+/// Counts of what a `FanoutClient` has sent to one of its targets.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TargetStats {
+    /// The name of the target.
+    pub name: String,
+
+    /// The number of sends which succeeded.
+    pub sent: u64,
+
+    /// The number of sends which failed, after any retries.
+    pub failed: u64,
+
+    /// The number of retries made.
+    pub retries: u64,
+
+    /// The error from the most recent failed send.
+    pub last_error: Option<String>,
+}
+
+/// The errors of the targets a `FanoutClient` failed to send to, each with
+/// the target's name.
+#[derive(Debug)]
+pub struct TargetErrors(pub Vec<(String, Error)>);
+
+impl fmt::Display for TargetErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, e)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}: {}", name, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Target {
+    /// Construct a target named `name`, used in errors and stats, which
+    /// sends through `client`.
+    ///
+    /// By default, messages are sent with the write key they were given to
+    /// the `FanoutClient` with, and retried with the default `RetryPolicy`.
+    pub fn new<C: Client + Send + Sync + 'static>(name: &str, client: C) -> Target {
+        Target {
+            name: name.to_owned(),
+            client: Box::new(client),
+            write_key: None,
+            policy: RetryPolicy::default(),
+            stats: Mutex::new(TargetStats {
+                name: name.to_owned(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Send to this target with `write_key`, instead of the write key
+    /// messages are given with.
+    pub fn with_write_key(mut self, write_key: &str) -> Target {
+        self.write_key = Some(write_key.to_owned());
+        self
+    }
+
+    /// Set how sends to this target are retried.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Target {
+        self.policy = policy;
+        self
+    }
+
+    fn send<F>(&self, write_key: &str, send: F) -> Result<(), Error>
+    where
+        F: Fn(&dyn Client, &str) -> Result<(), Error>,
+    {
+        let write_key = self.write_key.as_deref().unwrap_or(write_key);
+        let mut attempts = 0;
+        let result = self.policy.run(|attempt| {
+            attempts = attempt;
+            send(&*self.client, write_key)
+        });
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.retries += u64::from(attempts - 1);
+        match result {
+            Ok(()) => {
+                stats.sent += 1;
+                Ok(())
+            }
+            Err((e, _)) => {
+                stats.failed += 1;
+                stats.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+impl FanoutClient {
+    /// Construct a client which sends to `primary`, and any targets added
+    /// with `with_target`.
+    pub fn new(primary: Target) -> FanoutClient {
+        FanoutClient {
+            targets: vec![primary],
+            mode: Mode::default(),
+        }
+    }
+
+    /// Also send to `target`.
+    pub fn with_target(mut self, target: Target) -> FanoutClient {
+        self.targets.push(target);
+        self
+    }
+
+    /// Set which failures fail a send. Defaults to `Mode::All`.
+    pub fn with_mode(mut self, mode: Mode) -> FanoutClient {
+        self.mode = mode;
+        self
+    }
+
+    /// Returns counts of what has been sent to each target, primary first.
+    pub fn stats(&self) -> Vec<TargetStats> {
+        self.targets
+            .iter()
+            .map(|target| target.stats.lock().unwrap().clone())
+            .collect()
+    }
+
+    fn fanout<F>(&self, write_key: &str, send: F) -> Result<(), Error>
+    where
+        F: Fn(&dyn Client, &str) -> Result<(), Error> + Sync,
+    {
+        let send = &send;
+        let mut results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .targets
+                .iter()
+                .map(|target| scope.spawn(move || target.send(write_key, send)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        match self.mode {
+            Mode::PrimaryOnly => results.swap_remove(0),
+            Mode::All => {
+                let failures: Vec<_> = self
+                    .targets
+                    .iter()
+                    .zip(results)
+                    .filter_map(|(target, result)| result.err().map(|e| (target.name.clone(), e)))
+                    .collect();
+
+                if failures.is_empty() {
+                    Ok(())
+                } else {
+                    Err(AnalyticsError::FanoutFailed {
+                        delivered: self.targets.len() - failures.len(),
+                        errors: TargetErrors(failures),
+                    }
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+impl Client for FanoutClient {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        self.fanout(write_key, |client, write_key| client.send(write_key, msg))
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        self.fanout(write_key, |client, write_key| {
+            client.send_serialized(write_key, batch)
+        })
+    }
+}
+
+#[cfg(all(test, any(feature = "reqwest", feature = "ureq", feature = "hyper")))]
+mod tests {
+    use super::*;
+    use crate::buffered::{BufferedOptions, BufferedSender};
+    use crate::message::{Track, User};
+    use crate::testing::{MemoryClient, MockResponse, MockServer};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sends_to_all_targets() {
+        let primary = Arc::new(MemoryClient::new());
+        let secondary = MockServer::with_write_key("other_key");
+        secondary.enqueue(MockResponse::status(503));
+
+        let client = FanoutClient::new(Target::new("primary", primary.clone())).with_target(
+            Target::new("secondary", secondary.client())
+                .with_write_key("other_key")
+                .with_retry_policy(policy()),
+        );
+        client.send("write_key", &track()).unwrap();
+
+        assert_eq!(vec![("write_key".to_owned(), track())], primary.sent());
+        assert_eq!(vec![track()], secondary.messages());
+
+        let stats = client.stats();
+        assert_eq!(1, stats[0].sent);
+        assert_eq!(
+            TargetStats {
+                name: "secondary".to_owned(),
+                sent: 1,
+                failed: 0,
+                retries: 1,
+                last_error: None,
+            },
+            stats[1]
+        );
+    }
+
+    #[test]
+    fn test_modes() {
+        let primary = MockServer::start();
+        let secondary = MockServer::start();
+
+        let fanout = |mode| {
+            FanoutClient::new(Target::new("primary", primary.client()).with_retry_policy(policy()))
+                .with_target(
+                    Target::new("secondary", secondary.client()).with_retry_policy(policy()),
+                )
+                .with_mode(mode)
+        };
+
+        let client = fanout(Mode::All);
+        secondary.enqueue(MockResponse::status(400));
+        let err = client.send("write_key", &track()).unwrap_err();
+        match err.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::FanoutFailed {
+                errors,
+                delivered: 1,
+            }) => {
+                assert_eq!(1, errors.0.len());
+                assert_eq!("secondary", errors.0[0].0);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(!crate::retry::is_retryable(&err));
+
+        // Retrying would send to the primary again.
+        for _ in 0..3 {
+            secondary.enqueue(MockResponse::status(503));
+        }
+        let err = client.send("write_key", &track()).unwrap_err();
+        assert!(!crate::retry::is_retryable(&err), "{}", err);
+
+        for _ in 0..3 {
+            primary.enqueue(MockResponse::status(503));
+            secondary.enqueue(MockResponse::status(503));
+        }
+        let err = client.send("write_key", &track()).unwrap_err();
+        assert!(crate::retry::is_retryable(&err), "{}", err);
+
+        let client = fanout(Mode::PrimaryOnly);
+        for _ in 0..3 {
+            secondary.enqueue(MockResponse::status(500));
+        }
+        client.send("write_key", &track()).unwrap();
+
+        let stats = client.stats();
+        assert_eq!((1, 0), (stats[0].sent, stats[0].failed));
+        assert_eq!(
+            (0, 1, 2),
+            (stats[1].sent, stats[1].failed, stats[1].retries)
+        );
+        assert!(stats[1].last_error.is_some());
+
+        primary.enqueue(MockResponse::status(400));
+        assert!(client.send("write_key", &track()).is_err());
+    }
+
+    #[test]
+    fn test_no_duplicates_when_retried() {
+        let primary = Arc::new(MemoryClient::new());
+        let secondary = MockServer::start();
+        for _ in 0..3 {
+            secondary.enqueue(MockResponse::status(503));
+        }
+
+        let client = FanoutClient::new(Target::new("primary", primary.clone())).with_target(
+            Target::new("secondary", secondary.client()).with_retry_policy(RetryPolicy::none()),
+        );
+        let sender = BufferedSender::new(
+            client,
+            "write_key",
+            BufferedOptions {
+                retry_policy: policy(),
+                ..Default::default()
+            },
+        );
+        sender
+            .enqueue(track().into_batch_messages().remove(0))
+            .unwrap();
+        sender.flush().unwrap();
+
+        // The secondary's failure isn't retried by the sender, since that
+        // would send to the primary twice.
+        assert_eq!(1, primary.len());
+        assert_eq!(1, secondary.received().len());
+        assert_eq!(1, sender.dropped());
+    }
+}
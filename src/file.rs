@@ -0,0 +1,363 @@
+//! A client which writes messages to files instead of sending them.
+
+use crate::client::Client;
+use crate::http::Compression;
+use crate::message::{BatchMessage, Message};
+use chrono::Utc;
+use failure::Error;
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A client which appends every message to newline-delimited JSON files in a
+/// directory, for environments which ship data by file rather than over the
+/// network.
+///
+/// Each line is a single message tagged with its `type`, with batches
+/// flattened into the messages they contain. This is the format `analytics
+/// import` reads, so files can be replayed to Segment later; `import`
+/// decompresses files ending in `.gz`, or `.zst` with the `zstd` feature.
+/// Write keys are not written out.
+///
+/// Files are named `<prefix>-<timestamp>-<sequence>.ndjson`, and a new file
+/// is started once the current one reaches `with_max_bytes` of uncompressed
+/// data or is older than `with_max_age`:
+///
+/// ```
+/// use analytics::client::Client;
+/// use analytics::file::FileClient;
+/// use analytics::http::Compression;
+/// use std::time::Duration;
+///
+/// # let dir = std::env::temp_dir().join(format!("file-client-doc-{}", std::process::id()));
+/// let client = FileClient::new(&dir)
+///     .unwrap()
+///     .with_max_bytes(64 * 1024 * 1024)
+///     .with_max_age(Duration::from_secs(60 * 60))
+///     .with_compression(Compression::Gzip);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+///
+/// Every call to `send` flushes what it wrote to the operating system. For
+/// compressed files, that is only what the compressor has output so far;
+/// they are only complete once they have been rotated away from or the
+/// client has been dropped.
+pub struct FileClient {
+    dir: PathBuf,
+    prefix: String,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    compression: Compression,
+    current: Mutex<Option<Current>>,
+}
+
+/// The file currently being written to.
+struct Current {
+    writer: Encoder,
+    bytes: u64,
+    opened: Instant,
+}
+
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl FileClient {
+    /// Construct a client which writes to files in `dir`, creating it if it
+    /// does not exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<FileClient, Error> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(FileClient {
+            dir: dir.as_ref().to_owned(),
+            prefix: "events".to_owned(),
+            max_bytes: None,
+            max_age: None,
+            compression: Compression::None,
+            current: Mutex::new(None),
+        })
+    }
+
+    /// Set the prefix of file names. Defaults to `events`.
+    pub fn with_prefix(mut self, prefix: &str) -> FileClient {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Start a new file once the current one holds this many bytes of
+    /// uncompressed data.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> FileClient {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Start a new file once the current one was opened this long ago.
+    pub fn with_max_age(mut self, max_age: Duration) -> FileClient {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Compress files as they are written. Compressed files are given a
+    /// `.gz` or `.zst` extension.
+    pub fn with_compression(mut self, compression: Compression) -> FileClient {
+        self.compression = compression;
+        self
+    }
+
+    /// Finish the current file, so that the next message starts a new one.
+    pub fn rotate(&self) -> Result<(), Error> {
+        if let Some(current) = self.current.lock().unwrap().take() {
+            current.writer.finish()?;
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Current, Error> {
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%S");
+        let extension = match self.compression {
+            Compression::None => "ndjson",
+            Compression::Gzip => "ndjson.gz",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "ndjson.zst",
+        };
+
+        // Never reuse a file name, even when rotating more than once a second.
+        let mut sequence = 0;
+        let file = loop {
+            let path = self.dir.join(format!(
+                "{}-{}-{:04}.{}",
+                self.prefix, timestamp, sequence, extension
+            ));
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => break file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => sequence += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let file = BufWriter::new(file);
+
+        let writer = match self.compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+
+        Ok(Current {
+            writer,
+            bytes: 0,
+            opened: Instant::now(),
+        })
+    }
+}
+
+impl Client for FileClient {
+    fn send(&self, _write_key: &str, msg: &Message) -> Result<(), Error> {
+        let lines = to_lines(msg)?;
+
+        let mut current = self.current.lock().unwrap();
+        if let Some(ref c) = *current {
+            let too_big = self
+                .max_bytes
+                .is_some_and(|max| c.bytes > 0 && c.bytes + lines.len() as u64 > max);
+            let too_old = self.max_age.is_some_and(|max| c.opened.elapsed() >= max);
+            if too_big || too_old {
+                current.take().unwrap().writer.finish()?;
+            }
+        }
+
+        if current.is_none() {
+            *current = Some(self.open()?);
+        }
+
+        let c = current.as_mut().unwrap();
+        c.writer.write_all(&lines)?;
+        c.writer.flush()?;
+        c.bytes += lines.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for FileClient {
+    fn drop(&mut self) {
+        let _ = self.rotate();
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.write(buf),
+        }
+    }
+
+    /// Flush the file, but not the compressor: flushing it on every send
+    /// would end a compressed block each time, and ruin the compression.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(w) => w.flush(),
+            Encoder::Gzip(w) => w.get_mut().flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.get_mut().flush(),
+        }
+    }
+}
+
+impl Encoder {
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Encoder::Plain(w) => w,
+            Encoder::Gzip(w) => w.finish()?,
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.finish()?,
+        };
+        file.flush()?;
+        file.get_ref().sync_all()
+    }
+}
+
+/// Serialize a message as newline-delimited `BatchMessage`s.
+pub(crate) fn to_lines(msg: &Message) -> Result<Vec<u8>, Error> {
+    let mut lines = Vec::new();
+    for msg in msg.clone().into_batch_messages() {
+        serde_json::to_writer::<_, BatchMessage>(&mut lines, &msg)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Batch, Track, User};
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read};
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("analytics-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn track(i: usize) -> BatchMessage {
+        BatchMessage::Track(Track {
+            user: User::UserId {
+                user_id: format!("user-{}", i),
+            },
+            event: "Foo".to_owned(),
+            properties: json!({ "i": i }),
+            ..Default::default()
+        })
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn read(path: &Path) -> Vec<BatchMessage> {
+        let file = File::open(path).unwrap();
+        let reader: Box<dyn Read> = if path.extension().unwrap() == "gz" {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        BufReader::new(reader)
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rotates_by_size() {
+        let dir = dir("file-size");
+        let line_len = to_lines(&Message::Track(match track(0) {
+            BatchMessage::Track(t) => t,
+            _ => unreachable!(),
+        }))
+        .unwrap()
+        .len() as u64;
+
+        let client = FileClient::new(&dir).unwrap().with_max_bytes(line_len * 2);
+        client
+            .send(
+                "write_key",
+                &Message::Batch(Batch {
+                    batch: vec![track(0), track(1)],
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+        client
+            .send(
+                "write_key",
+                &Message::Batch(Batch {
+                    batch: vec![track(2)],
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+        drop(client);
+
+        let files = files(&dir);
+        assert_eq!(2, files.len());
+        assert_eq!(vec![track(0), track(1)], read(&files[0]));
+        assert_eq!(vec![track(2)], read(&files[1]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_by_age_with_gzip() {
+        let dir = dir("file-age");
+        let client = FileClient::new(&dir)
+            .unwrap()
+            .with_prefix("archive")
+            .with_max_age(Duration::from_millis(50))
+            .with_compression(Compression::Gzip);
+
+        let batch = |i| {
+            Message::Batch(Batch {
+                batch: vec![track(i)],
+                ..Default::default()
+            })
+        };
+        client.send("write_key", &batch(0)).unwrap();
+        client.send("write_key", &batch(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        client.send("write_key", &batch(2)).unwrap();
+        drop(client);
+
+        let files = files(&dir);
+        assert_eq!(2, files.len());
+        assert!(files[0]
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("archive-"));
+        assert_eq!(vec![track(0), track(1)], read(&files[0]));
+        assert_eq!(vec![track(2)], read(&files[1]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+//! A global analytics backend, in the style of the `log` crate.
+//!
+//! An application installs a backend once at startup, after which the
+//! [`track!`](../macro.track.html) and [`identify!`](../macro.identify.html)
+//! macros can be used anywhere, without passing a client and write key
+//! around:
+//!
+//! ```no_run
+//! use analytics::buffered::{BufferedOptions, BufferedSender};
+//! use analytics::http::HttpClient;
+//!
+//! analytics::global::install(BufferedSender::new(
+//!     HttpClient::default(),
+//!     "YOUR_WRITE_KEY",
+//!     BufferedOptions::default(),
+//! ))
+//! .unwrap();
+//!
+//! let user_id = "some_user_id";
+//! analytics::identify!(user_id, { "plan": "pro" });
+//! analytics::track!(user_id, "Upgraded", { "from": "free", "seats": 3 });
+//!
+//! // The backend is never dropped, so send what it has buffered before
+//! // exiting.
+//! analytics::global::flush();
+//! ```
+//!
+//! Until a backend is installed, the macros do nothing and don't evaluate
+//! their properties, so libraries can instrument themselves safely.
+
+use crate::buffered::BufferedSender;
+use crate::errors::Error as AnalyticsError;
+use crate::message::{BatchMessage, Identify, Track, User};
+use chrono::Utc;
+use failure::Error;
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
+
+static BACKEND: OnceLock<Box<dyn Backend>> = OnceLock::new();
+
+/// Somewhere for globally tracked messages to go.
+pub trait Backend: Send + Sync {
+    /// Accept a message. This should not block.
+    fn enqueue(&self, msg: BatchMessage);
+
+    /// Send any messages which have been accepted but not yet sent.
+    fn flush(&self) {}
+}
+
+impl Backend for BufferedSender {
+    fn enqueue(&self, msg: BatchMessage) {
+        let _ = BufferedSender::enqueue(self, msg);
+    }
+
+    fn flush(&self) {
+        let _ = BufferedSender::flush(self);
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for Arc<B> {
+    fn enqueue(&self, msg: BatchMessage) {
+        (**self).enqueue(msg)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+}
+
+/// Install the global backend.
+///
+/// Returns an error if a backend has already been installed.
+pub fn install<B: Backend + 'static>(backend: B) -> Result<(), Error> {
+    BACKEND
+        .set(Box::new(backend))
+        .map_err(|_| AnalyticsError::AlreadyInstalled.into())
+}
+
+/// Returns whether a global backend has been installed.
+pub fn is_installed() -> bool {
+    BACKEND.get().is_some()
+}
+
+/// Send a message to the global backend, if one is installed.
+pub fn enqueue(msg: BatchMessage) {
+    if let Some(backend) = BACKEND.get() {
+        backend.enqueue(msg);
+    }
+}
+
+/// Flush the global backend, if one is installed.
+pub fn flush() {
+    if let Some(backend) = BACKEND.get() {
+        backend.flush();
+    }
+}
+
+/// Send a `track` event to the global backend. See
+/// [`track!`](../macro.track.html).
+pub fn track<U: AsRef<str>>(user_id: U, event: &str, properties: Value) {
+    enqueue(BatchMessage::Track(Track {
+        user: User::UserId {
+            user_id: user_id.as_ref().to_owned(),
+        },
+        event: event.to_owned(),
+        properties,
+        timestamp: Some(Utc::now()),
+        ..Default::default()
+    }));
+}
+
+/// Send an `identify` event to the global backend. See
+/// [`identify!`](../macro.identify.html).
+pub fn identify<U: AsRef<str>>(user_id: U, traits: Value) {
+    enqueue(BatchMessage::Identify(Identify {
+        user: User::UserId {
+            user_id: user_id.as_ref().to_owned(),
+        },
+        traits,
+        timestamp: Some(Utc::now()),
+        ..Default::default()
+    }));
+}
+
+/// Track an event for a user through the global backend.
+///
+/// Properties are given as a JSON object, with the same syntax as
+/// `serde_json::json!`, and may be omitted:
+///
+/// ```
+/// # let (user_id, total) = ("some_user_id", 42.0);
+/// analytics::track!(user_id, "Order Completed", { "total": total });
+/// analytics::track!(user_id, "Logged Out");
+/// ```
+///
+/// Does nothing if no backend has been [installed](global/fn.install.html).
+#[macro_export]
+macro_rules! track {
+    ($user_id:expr, $event:expr $(,)?) => {
+        $crate::track!($user_id, $event, {})
+    };
+    ($user_id:expr, $event:expr, $properties:tt $(,)?) => {
+        if $crate::global::is_installed() {
+            $crate::global::track(&$user_id, $event, $crate::__serde_json::json!($properties));
+        }
+    };
+}
+
+/// Identify a user, and set their traits, through the global backend.
+///
+/// Traits are given as a JSON object, with the same syntax as
+/// `serde_json::json!`, and may be omitted:
+///
+/// ```
+/// # let user_id = "some_user_id";
+/// analytics::identify!(user_id, { "email": "user@example.com" });
+/// ```
+///
+/// Does nothing if no backend has been [installed](global/fn.install.html).
+#[macro_export]
+macro_rules! identify {
+    ($user_id:expr $(,)?) => {
+        $crate::identify!($user_id, {})
+    };
+    ($user_id:expr, $traits:tt $(,)?) => {
+        if $crate::global::is_installed() {
+            $crate::global::identify(&$user_id, $crate::__serde_json::json!($traits));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<BatchMessage>>);
+
+    impl Backend for Collect {
+        fn enqueue(&self, msg: BatchMessage) {
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    // The backend is global, so everything is tested in one test.
+    #[test]
+    fn test_global() {
+        let evaluated = std::cell::Cell::new(false);
+        let mark = || {
+            evaluated.set(true);
+            1
+        };
+        crate::track!("foo", "Before", { "x": mark() });
+        assert!(!evaluated.get());
+        assert!(!is_installed());
+
+        let backend = Arc::new(Collect::default());
+        install(backend.clone()).unwrap();
+        assert!(install(Collect::default()).is_err());
+
+        let user_id = String::from("foo");
+        let seats = 3;
+        crate::track!(user_id, "Upgraded", { "seats": seats });
+        crate::track!("bar", "Logged Out");
+        crate::identify!(user_id, { "plan": "pro" });
+        flush();
+
+        let msgs = backend.0.lock().unwrap();
+        assert_eq!(3, msgs.len());
+        match &msgs[0] {
+            BatchMessage::Track(track) => {
+                assert_eq!(Some("foo"), track.user.user_id());
+                assert_eq!("Upgraded", track.event);
+                assert_eq!(json!({ "seats": 3 }), track.properties);
+                assert!(track.timestamp.is_some());
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        match &msgs[1] {
+            BatchMessage::Track(track) => assert_eq!(json!({}), track.properties),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        match &msgs[2] {
+            BatchMessage::Identify(identify) => {
+                assert_eq!(json!({ "plan": "pro" }), identify.traits)
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Callbacks for observing what is sent and dropped.
+
+use crate::breaker::CircuitState;
+use crate::message::BatchMessage;
+use failure::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Callbacks made by a [`BufferedSender`](../buffered/struct.BufferedSender.html)
+/// as it sends messages, by an [`HttpClient`](../http/struct.HttpClient.html)
+/// when the API rejects messages, and by a
+/// [`CircuitBreaker`](../breaker/struct.CircuitBreaker.html) as its state
+/// changes, for logging, metrics and alerting.
+///
+/// Every method does nothing by default. Hooks are called from whichever
+/// thread causes the event: `on_enqueue`, and `on_queue_depth` and `on_drop`
+/// for messages enqueued or evicted, run on the application's thread as it
+/// enqueues, while the rest mostly run on the sender's background threads.
+/// Any hook may therefore hold up the application, so they should be quick
+/// and must not block.
+///
+/// ```
+/// use analytics::hooks::{BatchFailed, Dropped, Hooks};
+///
+/// struct Log;
+///
+/// impl Hooks for Log {
+///     fn on_batch_failed(&self, failed: &BatchFailed) {
+///         eprintln!(
+///             "could not send {} messages after {} attempts: {}",
+///             failed.count, failed.attempts, failed.error
+///         );
+///     }
+///
+///     fn on_drop(&self, dropped: &Dropped) {
+///         eprintln!("dropped {} messages: {}", dropped.count, dropped.reason);
+///     }
+/// }
+/// ```
+pub trait Hooks: Send + Sync {
+    /// Called on the application's thread as a message is enqueued.
+    fn on_enqueue(&self, _msg: &BatchMessage) {}
+
+    /// Called when the number of messages waiting to be sent changes, on the
+    /// application's thread when a message is enqueued.
+    fn on_queue_depth(&self, _depth: &QueueDepth) {}
+
+    /// Called after each attempt at sending a batch, including retries.
+    fn on_attempt(&self, _attempt: &Attempt) {}
+
+    /// Called when a batch has been sent.
+    fn on_batch_sent(&self, _sent: &BatchSent) {}
+
+    /// Called when a batch could not be sent, after any retries. The batch's
+    /// messages are then dropped.
+    fn on_batch_failed(&self, _failed: &BatchFailed) {}
+
+    /// Called when messages are dropped without being sent, on the
+    /// application's thread when they are rejected or evicted on enqueue.
+    fn on_drop(&self, _dropped: &Dropped) {}
+
+    /// Called when a circuit breaker opens, half-opens or closes, on the
+    /// thread whose send caused the change.
+    fn on_circuit_state_change(&self, _change: &CircuitStateChange) {}
+}
+
+impl<H: Hooks + ?Sized> Hooks for Arc<H> {
+    fn on_enqueue(&self, msg: &BatchMessage) {
+        (**self).on_enqueue(msg)
+    }
+
+    fn on_queue_depth(&self, depth: &QueueDepth) {
+        (**self).on_queue_depth(depth)
+    }
+
+    fn on_attempt(&self, attempt: &Attempt) {
+        (**self).on_attempt(attempt)
+    }
+
+    fn on_batch_sent(&self, sent: &BatchSent) {
+        (**self).on_batch_sent(sent)
+    }
+
+    fn on_batch_failed(&self, failed: &BatchFailed) {
+        (**self).on_batch_failed(failed)
+    }
+
+    fn on_drop(&self, dropped: &Dropped) {
+        (**self).on_drop(dropped)
+    }
+
+    fn on_circuit_state_change(&self, change: &CircuitStateChange) {
+        (**self).on_circuit_state_change(change)
+    }
+}
+
+/// The messages waiting to be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueDepth {
+    /// The number of messages.
+    pub messages: usize,
+
+    /// The approximate serialized size of the messages, in bytes.
+    pub bytes: usize,
+}
+
+/// An attempt at sending a batch.
+#[derive(Debug)]
+pub struct Attempt<'a> {
+    /// The number of the attempt, counting from 1.
+    pub number: u32,
+
+    /// The number of messages in the batch.
+    pub count: usize,
+
+    /// How long the attempt took.
+    pub latency: Duration,
+
+    /// The HTTP status of the response, if the server responded and the
+    /// client reports it.
+    pub status: Option<u16>,
+
+    /// The error the attempt failed with, if it failed.
+    pub error: Option<&'a Error>,
+}
+
+impl Attempt<'_> {
+    /// Returns the outcome of this attempt as a metric label: `success`,
+    /// `http_error` if the server responded with a failure, or
+    /// `transport_error` if it didn't respond.
+    pub fn outcome(&self) -> &'static str {
+        match (self.error, self.status) {
+            (None, _) => "success",
+            (Some(_), Some(_)) => "http_error",
+            (Some(_), None) => "transport_error",
+        }
+    }
+}
+
+/// A batch which was sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSent {
+    /// The number of messages in the batch.
+    pub count: usize,
+
+    /// The size of the batch's body, in bytes, before any compression.
+    pub bytes: usize,
+
+    /// How long sending the batch took, including any retries.
+    pub latency: Duration,
+}
+
+/// A batch which could not be sent.
+#[derive(Debug)]
+pub struct BatchFailed<'a> {
+    /// The number of messages in the batch.
+    pub count: usize,
+
+    /// The error from the last attempt.
+    pub error: &'a Error,
+
+    /// The number of attempts made.
+    pub attempts: u32,
+}
+
+/// Messages which were dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dropped {
+    /// The number of messages dropped.
+    pub count: usize,
+
+    /// Why they were dropped.
+    pub reason: DropReason,
+}
+
+/// A change in the state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitStateChange {
+    /// The state before the change.
+    pub from: CircuitState,
+
+    /// The state after the change.
+    pub to: CircuitState,
+}
+
+/// Why messages were dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DropReason {
+    /// The message was too large to send.
+    TooLarge,
+
+    /// The message could not be serialized.
+    Invalid(String),
+
+    /// The batch containing the messages could not be sent.
+    SendFailed,
+
+    /// The message was enqueued after the sender stopped.
+    Closed,
+
+    /// The sender's queue was full.
+    QueueFull,
+
+    /// The tracking API accepted the batch containing the messages, but
+    /// rejected the messages themselves.
+    Rejected,
+}
+
+impl DropReason {
+    /// Returns a short, stable name for this reason, such as `too_large`,
+    /// for use as a metric label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropReason::TooLarge => "too_large",
+            DropReason::Invalid(_) => "invalid",
+            DropReason::SendFailed => "send_failed",
+            DropReason::Closed => "closed",
+            DropReason::QueueFull => "queue_full",
+            DropReason::Rejected => "rejected",
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DropReason::TooLarge => f.write_str("message too large"),
+            DropReason::Invalid(reason) => write!(f, "invalid message: {}", reason),
+            DropReason::SendFailed => f.write_str("send failed"),
+            DropReason::Closed => f.write_str("sender closed"),
+            DropReason::QueueFull => f.write_str("queue full"),
+            DropReason::Rejected => f.write_str("rejected by the tracking API"),
+        }
+    }
+}
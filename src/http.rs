@@ -1,63 +1,783 @@
 //! Low-level HTTP bindings to the Segment tracking API.
 
+use crate::backend::{ConnectionOptions, HttpBackend, Request};
+use crate::batcher::SerializedBatch;
 use crate::client::Client;
+use crate::endpoint::Endpoint;
+use crate::errors::Error as AnalyticsError;
+use crate::hooks::{DropReason, Dropped, Hooks};
 use crate::message::Message;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
 use failure::Error;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
 use std::time::Duration;
 
+const USER_AGENT: &str = concat!("analytics-rust/", env!("CARGO_PKG_VERSION"));
+
 /// A client which synchronously sends single messages to the Segment tracking
 /// API.
 ///
 /// `HttpClient` implements [`Client`](../client/trait.Client.html); see the
 /// documentation for `Client` for more on how to send events to Segment.
+///
+/// Requests are made through an [`HttpBackend`](../backend/index.html),
+/// `reqwest` by default. Responses with a status other than `2xx` fail with
+/// `Error::Status`, carrying the API's explanation from the response body,
+/// as do responses which reject every message sent. Use `send_with_report`
+/// to find out which messages of a batch the API rejected, or `with_hooks`
+/// to be told about them whichever way messages are sent.
 pub struct HttpClient {
-    client: reqwest::blocking::Client,
-    host: String,
+    backend: Box<dyn HttpBackend>,
+    endpoint: Endpoint,
+    compression: Compression,
+    headers: Vec<(String, String)>,
+    hooks: Option<Arc<dyn Hooks>>,
 }
 
+#[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
 impl Default for HttpClient {
+    /// Construct a client with the default settings of an
+    /// [`HttpClientBuilder`](struct.HttpClientBuilder.html).
     fn default() -> Self {
-        HttpClient {
-            client: reqwest::blocking::Client::builder()
-                .connect_timeout(Duration::new(10, 0))
-                .build()
-                .unwrap(),
-            host: "https://api.segment.io".to_owned(),
-        }
+        HttpClient::builder().build().unwrap()
     }
 }
 
 impl HttpClient {
-    /// Construct a new `HttpClient` from a `reqwest::Client` and a Segment API
-    /// scheme and host.
+    /// Returns a builder for configuring an `HttpClient`'s timeouts,
+    /// connections and headers.
+    pub fn builder() -> HttpClientBuilder {
+        HttpClientBuilder::default()
+    }
+
+    /// Construct a new `HttpClient` from a `reqwest::Client` and the
+    /// [`Endpoint`](../endpoint/struct.Endpoint.html) to send events to, which
+    /// may also be given as a base URL such as `"https://api.segment.io"`.
     ///
     /// If you don't care to re-use an existing `reqwest::Client`, you can use
     /// the `Default::default` value, which will send events to
     /// `https://api.segment.io`.
-    pub fn new(client: reqwest::blocking::Client, host: String) -> HttpClient {
-        HttpClient { client, host }
+    #[cfg(feature = "reqwest")]
+    pub fn new(client: reqwest::blocking::Client, endpoint: impl Into<Endpoint>) -> HttpClient {
+        HttpClient::with_backend(crate::backend::ReqwestBackend::from(client), endpoint)
+    }
+
+    /// Construct a new `HttpClient` which makes requests through `backend`,
+    /// to the given [`Endpoint`](../endpoint/struct.Endpoint.html).
+    pub fn with_backend<B: HttpBackend + 'static>(
+        backend: B,
+        endpoint: impl Into<Endpoint>,
+    ) -> HttpClient {
+        HttpClient {
+            backend: Box::new(backend),
+            endpoint: endpoint.into(),
+            compression: Compression::None,
+            headers: vec![("User-Agent".to_owned(), USER_AGENT.to_owned())],
+            hooks: None,
+        }
+    }
+
+    /// Compress request bodies sent by this client.
+    ///
+    /// Request bodies are sent uncompressed by default. Compression does not
+    /// change how large a message or batch may be: Segment's size limits, and
+    /// the [`Batcher`](../batcher/struct.Batcher.html)'s accounting of them,
+    /// apply to the uncompressed JSON.
+    pub fn with_compression(mut self, compression: Compression) -> HttpClient {
+        self.compression = compression;
+        self
+    }
+
+    /// Tell `hooks` about messages which the API rejected despite accepting
+    /// the request, through `on_drop` with `DropReason::Rejected`. Requests
+    /// which fail outright are left to the caller to report.
+    pub fn with_hooks(mut self, hooks: Arc<dyn Hooks>) -> HttpClient {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Send a message, returning a report of which of its messages the API
+    /// accepted.
+    ///
+    /// A `2xx` response may still reject some messages of a batch; they are
+    /// listed in the report and told to the client's hooks, while `send`
+    /// treats the response as a success. A response rejecting every message
+    /// is an error.
+    pub fn send_with_report(
+        &self,
+        write_key: &str,
+        msg: &Message,
+    ) -> Result<DeliveryReport, Error> {
+        let (path, sent) = match msg {
+            Message::Identify(_) => ("/v1/identify", 1),
+            Message::Track(_) => ("/v1/track", 1),
+            Message::Page(_) => ("/v1/page", 1),
+            Message::Screen(_) => ("/v1/screen", 1),
+            Message::Group(_) => ("/v1/group", 1),
+            Message::Alias(_) => ("/v1/alias", 1),
+            Message::Batch(batch) => ("/v1/batch", batch.batch.len()),
+        };
+
+        self.post(write_key, path, serde_json::to_vec(msg)?.into(), sent)
+    }
+
+    /// Send a serialized batch, returning a report of which of its messages
+    /// the API accepted. See `send_with_report`.
+    pub fn send_serialized_with_report(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<DeliveryReport, Error> {
+        self.post(write_key, "/v1/batch", batch.body(), batch.len())
+    }
+
+    fn post(
+        &self,
+        write_key: &str,
+        path: &str,
+        body: Bytes,
+        sent: usize,
+    ) -> Result<DeliveryReport, Error> {
+        let mut headers = self.headers.clone();
+        headers.push((
+            "Authorization".to_owned(),
+            format!("Basic {}", STANDARD.encode(format!("{}:", write_key))),
+        ));
+        headers.push(("Content-Type".to_owned(), "application/json".to_owned()));
+        if let Some(encoding) = self.compression.content_encoding() {
+            headers.push(("Content-Encoding".to_owned(), encoding.to_owned()));
+        }
+
+        let response = self.backend.post(Request {
+            url: self.endpoint.url(path).to_string(),
+            headers,
+            body: self.compression.encode(body)?,
+        })?;
+
+        let status = response.status;
+        let response = ApiResponse::parse(&response.body);
+        // A `2xx` which rejects the request without saying which messages
+        // were at fault rejected all of them.
+        let rejected = response.success == Some(false)
+            && (response.errors.is_empty() || response.errors.len() >= sent);
+        if !(200..300).contains(&status) || rejected {
+            return Err(AnalyticsError::Status { status, response }.into());
+        }
+        if let Some(ref hooks) = self.hooks {
+            if !response.errors.is_empty() {
+                hooks.on_drop(&Dropped {
+                    count: response.errors.len(),
+                    reason: DropReason::Rejected,
+                });
+            }
+        }
+        Ok(DeliveryReport {
+            status,
+            sent,
+            rejected: response.errors,
+        })
     }
 }
 
 impl Client for HttpClient {
     fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
-        let path = match msg {
-            Message::Identify(_) => "/v1/identify",
-            Message::Track(_) => "/v1/track",
-            Message::Page(_) => "/v1/page",
-            Message::Screen(_) => "/v1/screen",
-            Message::Group(_) => "/v1/group",
-            Message::Alias(_) => "/v1/alias",
-            Message::Batch(_) => "/v1/batch",
-        };
+        self.send_with_report(write_key, msg).map(|_| ())
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        self.send_serialized_with_report(write_key, batch)
+            .map(|_| ())
+    }
+
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        self.send_serialized_with_report(write_key, batch)
+            .map(|report| Some(report.status))
+    }
+}
+
+/// The body of a response from the tracking API, explaining why a request
+/// failed or which of its messages were rejected.
+///
+/// Fields the API didn't send are `None` or empty. A body which isn't JSON,
+/// such as an error page from a proxy, is kept as `message`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiResponse {
+    /// Whether the API accepted the request.
+    pub success: Option<bool>,
+
+    /// A machine-readable reason for the failure, such as `invalid_request`.
+    pub code: Option<String>,
+
+    /// A human-readable reason for the failure.
+    pub message: Option<String>,
+
+    /// The messages of a batch which were rejected.
+    pub errors: Vec<RejectedMessage>,
+}
+
+/// The longest non-JSON body kept as an `ApiResponse`'s message, in
+/// characters.
+const MAX_TEXT_BODY: usize = 256;
 
-        self.client
-            .post(&format!("{}{}", self.host, path))
-            .basic_auth(write_key, Some(""))
-            .json(msg)
-            .send()?
-            .error_for_status()?;
+impl ApiResponse {
+    fn parse(body: &[u8]) -> ApiResponse {
+        if let Ok(response) = serde_json::from_slice(body) {
+            return response;
+        }
 
+        let text = String::from_utf8_lossy(body);
+        let text = text.trim();
+        ApiResponse {
+            message: if text.is_empty() {
+                None
+            } else {
+                Some(text.chars().take(MAX_TEXT_BODY).collect())
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for ApiResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.message, &self.code) {
+            (Some(message), Some(code)) => write!(f, "{} ({})", message, code)?,
+            (Some(reason), None) | (None, Some(reason)) => f.write_str(reason)?,
+            (None, None) => f.write_str("no reason given")?,
+        }
+        if !self.errors.is_empty() {
+            write!(f, "; {} messages rejected", self.errors.len())?;
+        }
         Ok(())
     }
 }
+
+/// A message of a batch which the tracking API rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct RejectedMessage {
+    /// The position of the message in the batch.
+    pub index: Option<usize>,
+
+    /// The message's `messageId`.
+    #[serde(rename = "messageId")]
+    pub message_id: Option<String>,
+
+    /// A machine-readable reason the message was rejected.
+    pub code: Option<String>,
+
+    /// A human-readable reason the message was rejected.
+    pub message: Option<String>,
+}
+
+/// The outcome of a request which the tracking API accepted, returned by
+/// `HttpClient::send_with_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReport {
+    /// The response's HTTP status.
+    pub status: u16,
+
+    /// The number of messages sent.
+    pub sent: usize,
+
+    /// The messages which the API rejected, despite accepting the request.
+    pub rejected: Vec<RejectedMessage>,
+}
+
+impl DeliveryReport {
+    /// Returns the number of messages the API accepted.
+    pub fn accepted(&self) -> usize {
+        self.sent.saturating_sub(self.rejected.len())
+    }
+
+    /// Returns `true` if every message was accepted.
+    pub fn is_complete(&self) -> bool {
+        self.rejected.is_empty()
+    }
+}
+
+/// A builder for an `HttpClient`, for configuring the underlying connections
+/// without building a `reqwest::Client` by hand.
+///
+/// ```no_run
+/// use analytics::endpoint::Region;
+/// use analytics::http::{Compression, HttpClient};
+/// use std::time::Duration;
+///
+/// let client = HttpClient::builder()
+///     .with_endpoint(Region::Eu.into())
+///     .with_compression(Compression::Gzip)
+///     .with_timeout(Duration::from_secs(5))
+///     .with_proxy("http://proxy.internal:3128")
+///     .with_header("X-Team", "growth")
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// By default, requests time out after 30 seconds, of which connecting may
+/// take at most 10.
+#[derive(Debug, Clone)]
+pub struct HttpClientBuilder {
+    endpoint: Endpoint,
+    compression: Compression,
+    connection: ConnectionOptions,
+    user_agent: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Default for HttpClientBuilder {
+    fn default() -> Self {
+        HttpClientBuilder {
+            endpoint: Endpoint::default(),
+            compression: Compression::None,
+            connection: ConnectionOptions::default(),
+            user_agent: USER_AGENT.to_owned(),
+            headers: Vec::new(),
+        }
+    }
+}
+
+impl HttpClientBuilder {
+    /// Set the endpoint to send events to.
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> HttpClientBuilder {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Set the compression applied to request bodies. See
+    /// [`HttpClient::with_compression`](struct.HttpClient.html#method.with_compression).
+    pub fn with_compression(mut self, compression: Compression) -> HttpClientBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Set how long a whole request may take, from connecting until the
+    /// response has been read, or `None` to wait forever.
+    pub fn with_timeout<T: Into<Option<Duration>>>(mut self, timeout: T) -> HttpClientBuilder {
+        self.connection.timeout = timeout.into();
+        self
+    }
+
+    /// Set how long connecting may take, or `None` to wait forever.
+    pub fn with_connect_timeout<T: Into<Option<Duration>>>(
+        mut self,
+        timeout: T,
+    ) -> HttpClientBuilder {
+        self.connection.connect_timeout = timeout.into();
+        self
+    }
+
+    /// Set how long an idle connection is kept open for reuse.
+    pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> HttpClientBuilder {
+        self.connection.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the most idle connections to keep open for reuse.
+    pub fn with_pool_max_idle(mut self, max: usize) -> HttpClientBuilder {
+        self.connection.pool_max_idle = Some(max);
+        self
+    }
+
+    /// Enable TCP keepalive on connections, probing idle connections at the
+    /// given interval.
+    pub fn with_tcp_keepalive(mut self, interval: Duration) -> HttpClientBuilder {
+        self.connection.tcp_keepalive = Some(interval);
+        self
+    }
+
+    /// Send every request through the proxy at `url`, such as
+    /// `http://proxy.internal:3128`.
+    ///
+    /// Without a proxy, the `HTTP_PROXY` and `HTTPS_PROXY` environment
+    /// variables are respected by the `reqwest` and `ureq` backends.
+    pub fn with_proxy(mut self, url: &str) -> HttpClientBuilder {
+        self.connection.proxy = Some(url.to_owned());
+        self
+    }
+
+    /// Trust the PEM-encoded certificate `pem` as a root certificate, in
+    /// addition to the system's, such as for a collector behind a private
+    /// certificate authority. The `ureq` backend doesn't support this.
+    pub fn with_root_certificate(mut self, pem: &[u8]) -> HttpClientBuilder {
+        self.connection.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Set the `User-Agent` header sent with every request. It defaults to
+    /// `analytics-rust/` followed by this crate's version.
+    pub fn with_user_agent(mut self, user_agent: &str) -> HttpClientBuilder {
+        self.user_agent = user_agent.to_owned();
+        self
+    }
+
+    /// Send an extra header with every request.
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpClientBuilder {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Build the client, with the first of the `reqwest`, `ureq` and `hyper`
+    /// backends which is enabled.
+    ///
+    /// Returns an error if the proxy URL, a certificate or a header is
+    /// invalid, an option isn't supported by the backend, or TLS could not be
+    /// initialized.
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    pub fn build(self) -> Result<HttpClient, Error> {
+        #[cfg(feature = "reqwest")]
+        return self.build_with(crate::backend::ReqwestBackend::new);
+        #[cfg(all(not(feature = "reqwest"), feature = "ureq"))]
+        return self.build_with(crate::backend::UreqBackend::new);
+        #[cfg(all(not(feature = "reqwest"), not(feature = "ureq")))]
+        return self.build_with(crate::backend::HyperBackend::new);
+    }
+
+    /// Build the client, with the backend returned by `backend` for the
+    /// builder's connection options.
+    pub fn build_with<B, F>(self, backend: F) -> Result<HttpClient, Error>
+    where
+        B: HttpBackend + 'static,
+        F: FnOnce(&ConnectionOptions) -> Result<B, Error>,
+    {
+        let mut headers = vec![("User-Agent".to_owned(), self.user_agent)];
+        for (name, value) in self.headers {
+            let valid_name = !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+            let valid_value = value
+                .bytes()
+                .all(|b| b == b'\t' || (b >= b' ' && b != 0x7f));
+            if !valid_name || !valid_value {
+                return Err(AnalyticsError::InvalidHeader(name).into());
+            }
+            headers.push((name, value));
+        }
+
+        let mut client = HttpClient::with_backend(backend(&self.connection)?, self.endpoint)
+            .with_compression(self.compression);
+        client.headers = headers;
+        Ok(client)
+    }
+}
+
+/// The compression applied to request bodies sent by an `HttpClient`.
+///
+/// Which variants exist depends on the enabled features, so matches on this
+/// enum must have a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Send request bodies uncompressed.
+    #[default]
+    None,
+
+    /// Compress request bodies with gzip. Segment's tracking API accepts
+    /// gzipped requests.
+    Gzip,
+
+    /// Compress request bodies with zstd. Segment's tracking API does not
+    /// accept zstd; this is only useful with collectors which do.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    fn encode(self, body: Bytes) -> Result<Bytes, Error> {
+        match self {
+            Compression::None => Ok(body),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body)?;
+                Ok(encoder.finish()?.into())
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::encode_all(&body[..], 0)?.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    use crate::message::{Track, User};
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    use crate::testing::{MockResponse, MockServer};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    use std::thread;
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    fn track() -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[test]
+    fn test_builder_headers() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::new(&format!("http://{}", server.server_addr())).unwrap();
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let headers: Vec<_> = request
+                .headers()
+                .iter()
+                .map(|h| (h.field.to_string().to_lowercase(), h.value.to_string()))
+                .collect();
+            request.respond(tiny_http::Response::empty(200)).unwrap();
+            headers
+        });
+
+        let client = HttpClient::builder()
+            .with_endpoint(endpoint)
+            .with_compression(Compression::Gzip)
+            .with_user_agent("test-agent/1.0")
+            .with_header("X-Team", "growth")
+            .build()
+            .unwrap();
+        client.send("write_key", &track()).unwrap();
+
+        let headers = handle.join().unwrap();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(Some("test-agent/1.0"), header("user-agent"));
+        assert_eq!(Some("growth"), header("x-team"));
+        assert_eq!(Some("gzip"), header("content-encoding"));
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[test]
+    fn test_builder_timeout() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::delay(Duration::from_millis(500)));
+
+        let client = HttpClient::builder()
+            .with_endpoint(server.endpoint())
+            .with_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let e = client.send("write_key", &track()).unwrap_err();
+        assert!(crate::retry::is_retryable(&e), "unexpected error: {}", e);
+
+        assert!(HttpClient::builder()
+            .with_proxy("not a url")
+            .build()
+            .is_err());
+        assert!(HttpClient::builder()
+            .with_header("bad header", "value")
+            .build()
+            .is_err());
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn test_new_with_host() {
+        let server = MockServer::start();
+        let host = format!("http://{}/", server.addr());
+        let client = HttpClient::new(reqwest::blocking::Client::new(), host);
+
+        server.enqueue(MockResponse::status(200));
+        client.send("write_key", &track()).unwrap();
+        assert_eq!("/v1/track", server.received()[0].path);
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[test]
+    fn test_error_response() {
+        let server = MockServer::start();
+        let client = server.client();
+
+        server.enqueue(MockResponse::status(400).with_body(
+            r#"{"success":false,"code":"invalid_request","message":"event is required"}"#,
+        ));
+        let e = client.send("write_key", &track()).unwrap_err();
+        assert_eq!(
+            "tracking API responded with status 400: event is required (invalid_request)",
+            e.to_string()
+        );
+        match e.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::Status { status, response }) => {
+                assert_eq!(400, *status);
+                assert_eq!(Some("invalid_request"), response.code.as_deref());
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        server.enqueue(MockResponse::status(502).with_body("<html>Bad Gateway</html>\n"));
+        let e = client.send("write_key", &track()).unwrap_err();
+        assert_eq!(
+            "tracking API responded with status 502: <html>Bad Gateway</html>",
+            e.to_string()
+        );
+
+        server.enqueue(MockResponse::status(200).with_body(r#"{"success":false}"#));
+        let e = client.send("write_key", &track()).unwrap_err();
+        assert!(!crate::retry::is_retryable(&e), "unexpected error: {}", e);
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[derive(Default)]
+    struct Drops(std::sync::Mutex<Vec<Dropped>>);
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    impl Hooks for Drops {
+        fn on_drop(&self, dropped: &Dropped) {
+            self.0.lock().unwrap().push(dropped.clone());
+        }
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[test]
+    fn test_delivery_report() {
+        let server = MockServer::start();
+        let drops = Arc::new(Drops::default());
+        let client = server.client().with_hooks(drops.clone());
+        let batch = Message::Batch(crate::message::Batch {
+            batch: vec![
+                crate::message::BatchMessage::Track(Track {
+                    user: User::UserId {
+                        user_id: "foo".to_owned(),
+                    },
+                    event: "Foo".to_owned(),
+                    ..Default::default()
+                });
+                3
+            ],
+            ..Default::default()
+        });
+
+        let report = client.send_with_report("write_key", &batch).unwrap();
+        assert_eq!(3, report.accepted());
+        assert!(report.is_complete());
+
+        server.enqueue(MockResponse::status(200).with_body(
+            r#"{"success":false,"errors":[{"index":1,"messageId":"abc","code":"too_large","message":"message is too large"}]}"#,
+        ));
+        let report = client.send_with_report("write_key", &batch).unwrap();
+        assert_eq!(2, report.accepted());
+        assert!(!report.is_complete());
+        assert_eq!(
+            vec![RejectedMessage {
+                index: Some(1),
+                message_id: Some("abc".to_owned()),
+                code: Some("too_large".to_owned()),
+                message: Some("message is too large".to_owned()),
+            }],
+            report.rejected
+        );
+        assert_eq!(
+            vec![Dropped {
+                count: 1,
+                reason: DropReason::Rejected,
+            }],
+            *drops.0.lock().unwrap()
+        );
+
+        // Rejecting every message fails the request, leaving the hooks to
+        // the caller.
+        server.enqueue(
+            MockResponse::status(200)
+                .with_body(r#"{"success":false,"errors":[{"index":0},{"index":1},{"index":2}]}"#),
+        );
+        let e = client.send("write_key", &batch).unwrap_err();
+        match e.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::Status { status, response }) => {
+                assert_eq!(200, *status);
+                assert_eq!(3, response.errors.len());
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(1, drops.0.lock().unwrap().len());
+    }
+
+    /// A backend which keeps the body of the last request it was given.
+    #[derive(Default)]
+    struct Capture(std::sync::Mutex<Option<Bytes>>);
+
+    impl HttpBackend for Capture {
+        fn post(&self, request: Request) -> Result<crate::backend::Response, Error> {
+            *self.0.lock().unwrap() = Some(request.body);
+            Ok(crate::backend::Response {
+                status: 200,
+                body: b"{}".to_vec(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_serialized_not_copied() {
+        let backend = Arc::new(Capture::default());
+        let client = HttpClient::with_backend(backend.clone(), Endpoint::default());
+        let mut batcher = crate::batcher::Batcher::new(None);
+        batcher
+            .push(crate::message::BatchMessage::Track(Default::default()))
+            .unwrap();
+        let batch = batcher.flush_serialized().unwrap();
+
+        client.send_serialized("write_key", &batch).unwrap();
+        let body = backend.0.lock().unwrap().take().unwrap();
+        assert_eq!(batch.as_bytes().as_ptr(), body.as_ptr());
+    }
+
+    #[test]
+    fn test_encode_none() {
+        let body = Bytes::from_static(br#"{"batch":[]}"#);
+        assert_eq!(None, Compression::None.content_encoding());
+        assert_eq!(body, Compression::None.encode(body.clone()).unwrap());
+    }
+
+    #[test]
+    fn test_encode_gzip() {
+        let body = Bytes::from_static(br#"{"batch":[]}"#);
+        let encoded = Compression::Gzip.encode(body.clone()).unwrap();
+        assert_eq!(Some("gzip"), Compression::Gzip.content_encoding());
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(body, decoded);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encode_zstd() {
+        let body = Bytes::from_static(br#"{"batch":[]}"#);
+        let encoded = Compression::Zstd.encode(body.clone()).unwrap();
+        assert_eq!(Some("zstd"), Compression::Zstd.content_encoding());
+        assert_eq!(body, zstd::decode_all(&encoded[..]).unwrap());
+    }
+}
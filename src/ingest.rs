@@ -0,0 +1,241 @@
+//! Parsing of requests made to the Segment tracking API.
+//!
+//! These are the building blocks of servers which accept the same requests as
+//! Segment's tracking API, such as test doubles, debugging tools and proxies.
+//! With `tiny_http`, which the `cli`, `proxy` and `testing` features enable,
+//! `read_request` and `respond` handle a whole request.
+//!
+//! Bodies are read, and decompressed, up to `MAX_BODY` bytes, so that a
+//! server facing the network can't be made to buffer more.
+
+use crate::errors::Error as AnalyticsError;
+use crate::message::Message;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use failure::Error;
+use flate2::read::GzDecoder;
+use std::io::Read;
+
+/// The largest request body accepted, in bytes, before or after
+/// decompression: twice the largest batch the tracking API accepts, leaving
+/// room for a full batch's `context` and the JSON around it.
+pub const MAX_BODY: usize = 1024 * 1024;
+
+/// Parse the body of a request to the tracking API `path`, such as
+/// `/v1/track`, into the message it carries.
+///
+/// Returns an error if `path` is not one of the tracking API's paths, or if
+/// `body` is not a valid message of the kind `path` accepts.
+pub fn parse(path: &str, body: &[u8]) -> Result<Message, Error> {
+    let invalid = |e: serde_json::Error| AnalyticsError::InvalidRequest(e.to_string());
+
+    Ok(match path.trim_end_matches('/') {
+        "/v1/identify" => Message::Identify(serde_json::from_slice(body).map_err(invalid)?),
+        "/v1/track" => Message::Track(serde_json::from_slice(body).map_err(invalid)?),
+        "/v1/page" => Message::Page(serde_json::from_slice(body).map_err(invalid)?),
+        "/v1/screen" => Message::Screen(serde_json::from_slice(body).map_err(invalid)?),
+        "/v1/group" => Message::Group(serde_json::from_slice(body).map_err(invalid)?),
+        "/v1/alias" => Message::Alias(serde_json::from_slice(body).map_err(invalid)?),
+        "/v1/batch" | "/v1/import" => {
+            Message::Batch(serde_json::from_slice(body).map_err(invalid)?)
+        }
+        _ => {
+            return Err(AnalyticsError::InvalidRequest(format!("unknown path: {}", path)).into());
+        }
+    })
+}
+
+/// Extract the write key from the value of an `Authorization` header.
+///
+/// The tracking API takes the write key as the username of HTTP basic
+/// authentication, with an empty password. Returns `None` if the header is
+/// not valid basic authentication.
+pub fn write_key(authorization: &str) -> Option<String> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    if !parts.next()?.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = STANDARD.decode(parts.next()?.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    Some(decoded.split(':').next()?.to_owned())
+}
+
+/// Returns the HTTP status to respond with when handling a request failed
+/// with `e`: `413` if its body was too large, `400` if it was otherwise
+/// invalid, and `503` for anything else, which is the server's fault.
+pub fn error_status(e: &Error) -> u16 {
+    match e.downcast_ref::<AnalyticsError>() {
+        Some(AnalyticsError::BodyTooLarge) => 413,
+        Some(AnalyticsError::InvalidRequest(_)) | Some(AnalyticsError::MessageTooLarge) => 400,
+        _ => 503,
+    }
+}
+
+/// Read all of `reader`, failing with `Error::BodyTooLarge` if it holds more
+/// than `MAX_BODY` bytes.
+fn read_body<R: Read>(reader: R) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    reader
+        .take(MAX_BODY as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| AnalyticsError::InvalidRequest(e.to_string()))?;
+    if body.len() > MAX_BODY {
+        return Err(AnalyticsError::BodyTooLarge.into());
+    }
+    Ok(body)
+}
+
+/// Undo the `Content-Encoding` of a request body.
+///
+/// `gzip` is always supported, and `zstd` is supported with the `zstd`
+/// feature. Fails with `Error::BodyTooLarge` if the decoded body would be
+/// larger than `MAX_BODY`.
+pub fn decode(content_encoding: Option<&str>, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match content_encoding.map(str::trim) {
+        None | Some("") | Some("identity") => Ok(body),
+        Some("gzip") => read_body(GzDecoder::new(&body[..])),
+        #[cfg(feature = "zstd")]
+        Some("zstd") => read_body(
+            zstd::Decoder::new(&body[..])
+                .map_err(|e| AnalyticsError::InvalidRequest(e.to_string()))?,
+        ),
+        Some(encoding) => Err(AnalyticsError::InvalidRequest(format!(
+            "unsupported content encoding: {}",
+            encoding
+        ))
+        .into()),
+    }
+}
+
+/// A request to the tracking API, as read by `read_request`.
+#[cfg(any(test, feature = "tiny_http"))]
+#[derive(Debug)]
+pub struct TrackingRequest {
+    /// The path the request was made to, without any query string.
+    pub path: String,
+
+    /// The write key the request was authenticated with, if any.
+    pub write_key: Option<String>,
+
+    /// The message the request carried, or why it was invalid.
+    pub message: Result<Message, Error>,
+}
+
+/// Read the path, write key and message of a request to the tracking API.
+///
+/// The request's body is consumed, so `request` can only be responded to
+/// afterwards. Failing to read the body counts as an invalid message, and a
+/// body larger than `MAX_BODY` fails with `Error::BodyTooLarge`.
+#[cfg(any(test, feature = "tiny_http"))]
+pub fn read_request(request: &mut tiny_http::Request) -> TrackingRequest {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str().to_owned())
+    };
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let write_key = header("Authorization").and_then(|h| write_key(&h));
+    let encoding = header("Content-Encoding");
+
+    let message = read_body(request.as_reader())
+        .and_then(|body| decode(encoding.as_deref(), body))
+        .and_then(|body| parse(&path, &body));
+
+    TrackingRequest {
+        path,
+        write_key,
+        message,
+    }
+}
+
+/// Respond to `request` as the tracking API does: with `{"success":true}`,
+/// or `{"success":false}` and the reason when `error` is given.
+#[cfg(any(test, feature = "tiny_http"))]
+pub fn respond(request: tiny_http::Request, status: u16, error: Option<&str>) -> Result<(), Error> {
+    let body = match error {
+        None => serde_json::json!({ "success": true }),
+        Some(error) => serde_json::json!({ "success": false, "message": error }),
+    };
+    respond_with(request, status, &body.to_string())
+}
+
+/// Respond to `request` with `status` and the JSON `body`.
+#[cfg(any(test, feature = "tiny_http"))]
+pub fn respond_with(request: tiny_http::Request, status: u16, body: &str) -> Result<(), Error> {
+    let content_type =
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    request.respond(
+        tiny_http::Response::from_string(body)
+            .with_status_code(status)
+            .with_header(content_type),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Track, User};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let msg = parse(
+            "/v1/track",
+            br#"{"userId":"foo","event":"Foo","properties":{}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Message::Track(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned()
+                },
+                event: "Foo".to_owned(),
+                properties: json!({}),
+                ..Default::default()
+            }),
+            msg
+        );
+
+        assert!(parse("/v1/track", br#"{"userId":"foo"}"#).is_err());
+        assert!(parse("/v1/unknown", b"{}").is_err());
+    }
+
+    #[test]
+    fn test_write_key() {
+        assert_eq!(Some("foo".to_owned()), write_key("Basic Zm9vOg=="));
+        assert_eq!(Some("foo".to_owned()), write_key("basic Zm9v"));
+        assert_eq!(None, write_key("Bearer Zm9vOg=="));
+        assert_eq!(None, write_key("Basic !!!"));
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        let gzip = |body: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, body).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let body = vec![b' '; MAX_BODY];
+        assert_eq!(body, decode(Some("gzip"), gzip(&body)).unwrap());
+
+        // A small body which decompresses to more than the limit.
+        let bomb = gzip(&vec![b' '; MAX_BODY + 1]);
+        assert!(bomb.len() < MAX_BODY / 100);
+        let e = decode(Some("gzip"), bomb).unwrap_err();
+        match e.downcast_ref::<AnalyticsError>() {
+            Some(AnalyticsError::BodyTooLarge) => assert_eq!(413, error_status(&e)),
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+}
@@ -6,7 +6,7 @@
 //! ## Examples
 //!
 //! ### Simple
-//! ```rust
+//! ```rust,no_run
 //! use analytics::http::HttpClient;
 //! use analytics::client::Client;
 //! use analytics::message::{Track, Message, User};
@@ -29,7 +29,7 @@
 //! ```
 //!
 //! ### ETL-Like
-//! ```rust
+//! ```rust,no_run
 //! use analytics::http::HttpClient;
 //! use analytics::client::Client;
 //! use analytics::message::{BatchMessage, Track, User};
@@ -58,17 +58,51 @@
 //!         // would probably want to put this message in a deadletter queue or some
 //!         // equivalent.
 //!         if let Some(msg) = batcher.push(msg).unwrap() {
-//!             client.send(write_key, &batcher.into_message()).unwrap();
+//!             if let Some(batch) = batcher.flush() {
+//!                 client.send(write_key, &batch).unwrap();
+//!             }
 //!
-//!             batcher = Batcher::new(None);
 //!             batcher.push(msg).unwrap(); // Same error condition as above.
 //!         }
 //!     }
+//!
+//!     // Send whatever is left over in the batcher.
+//!     if let Some(batch) = batcher.flush() {
+//!         client.send(write_key, &batch).unwrap();
+//!     }
 //! }
 //! ```
 
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod backend;
 pub mod batcher;
+pub mod breaker;
+pub mod buffered;
 pub mod client;
+#[cfg(feature = "csv")]
+pub mod csv;
+pub mod endpoint;
 pub mod errors;
+pub mod fanout;
+pub mod file;
+pub mod global;
+pub mod hooks;
 pub mod http;
+pub mod ingest;
 pub mod message;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "opentelemetry")]
+pub mod opentelemetry;
+pub mod retry;
+pub mod spool;
+pub mod stdout;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "tracing")]
+pub mod tracing;
+
+// Used by the `track!` and `identify!` macros.
+#[doc(hidden)]
+pub use serde_json as __serde_json;
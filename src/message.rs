@@ -29,6 +29,7 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use std::convert::TryFrom;
 
 /// An enum containing all values which may be sent to Segment's tracking API.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -278,6 +279,104 @@ pub enum BatchMessage {
     Alias(Alias),
 }
 
+impl Message {
+    /// Converts this message into the messages it would be sent as in a
+    /// batch.
+    ///
+    /// A batch is flattened into the messages it contains. The batch's
+    /// `context` and `integrations` are copied onto any of its messages which
+    /// don't have their own, so the messages can be re-batched without losing
+    /// them.
+    pub fn into_batch_messages(self) -> Vec<BatchMessage> {
+        match self {
+            Message::Identify(m) => vec![BatchMessage::Identify(m)],
+            Message::Track(m) => vec![BatchMessage::Track(m)],
+            Message::Page(m) => vec![BatchMessage::Page(m)],
+            Message::Screen(m) => vec![BatchMessage::Screen(m)],
+            Message::Group(m) => vec![BatchMessage::Group(m)],
+            Message::Alias(m) => vec![BatchMessage::Alias(m)],
+            Message::Batch(b) => {
+                let (context, integrations) = (b.context, b.integrations);
+                b.batch
+                    .into_iter()
+                    .map(|mut msg| {
+                        let (msg_context, msg_integrations) = msg.common_mut();
+                        if msg_context.is_none() {
+                            *msg_context = context.clone();
+                        }
+                        if msg_integrations.is_none() {
+                            *msg_integrations = integrations.clone();
+                        }
+                        msg
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl BatchMessage {
+    /// Returns the user associated with this message.
+    pub fn user(&self) -> &User {
+        match self {
+            BatchMessage::Identify(m) => &m.user,
+            BatchMessage::Track(m) => &m.user,
+            BatchMessage::Page(m) => &m.user,
+            BatchMessage::Screen(m) => &m.user,
+            BatchMessage::Group(m) => &m.user,
+            BatchMessage::Alias(m) => &m.user,
+        }
+    }
+
+    /// Returns the timestamp of this message, if it has one.
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        match self {
+            BatchMessage::Identify(m) => m.timestamp,
+            BatchMessage::Track(m) => m.timestamp,
+            BatchMessage::Page(m) => m.timestamp,
+            BatchMessage::Screen(m) => m.timestamp,
+            BatchMessage::Group(m) => m.timestamp,
+            BatchMessage::Alias(m) => m.timestamp,
+        }
+    }
+
+    /// Returns the extra top-level fields of this message.
+    pub fn extra_mut(&mut self) -> &mut Map<String, Value> {
+        match self {
+            BatchMessage::Identify(m) => &mut m.extra,
+            BatchMessage::Track(m) => &mut m.extra,
+            BatchMessage::Page(m) => &mut m.extra,
+            BatchMessage::Screen(m) => &mut m.extra,
+            BatchMessage::Group(m) => &mut m.extra,
+            BatchMessage::Alias(m) => &mut m.extra,
+        }
+    }
+
+    fn common_mut(&mut self) -> (&mut Option<Value>, &mut Option<Value>) {
+        match self {
+            BatchMessage::Identify(m) => (&mut m.context, &mut m.integrations),
+            BatchMessage::Track(m) => (&mut m.context, &mut m.integrations),
+            BatchMessage::Page(m) => (&mut m.context, &mut m.integrations),
+            BatchMessage::Screen(m) => (&mut m.context, &mut m.integrations),
+            BatchMessage::Group(m) => (&mut m.context, &mut m.integrations),
+            BatchMessage::Alias(m) => (&mut m.context, &mut m.integrations),
+        }
+    }
+
+    /// Returns the type of this message, as it appears in the `type` field of
+    /// a batched message, such as `"track"`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            BatchMessage::Identify(_) => "identify",
+            BatchMessage::Track(_) => "track",
+            BatchMessage::Page(_) => "page",
+            BatchMessage::Screen(_) => "screen",
+            BatchMessage::Group(_) => "group",
+            BatchMessage::Alias(_) => "alias",
+        }
+    }
+}
+
 /// User ID information.
 ///
 /// All Segment tracking API calls require a user ID, an anonymous ID, or both.
@@ -285,7 +384,7 @@ pub enum BatchMessage {
 /// documentation](https://segment.com/docs/spec/identify/#identities) for how
 /// user IDs and anonymous IDs should be used.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(untagged, try_from = "UserFields")]
 pub enum User {
     /// The user is identified only by a user ID.
     UserId {
@@ -309,6 +408,26 @@ pub enum User {
     },
 }
 
+impl User {
+    /// Returns the user ID, if there is one.
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            User::UserId { user_id } | User::Both { user_id, .. } => Some(user_id),
+            User::AnonymousId { .. } => None,
+        }
+    }
+
+    /// Returns the anonymous ID, if there is one.
+    pub fn anonymous_id(&self) -> Option<&str> {
+        match self {
+            User::AnonymousId { anonymous_id } | User::Both { anonymous_id, .. } => {
+                Some(anonymous_id)
+            }
+            User::UserId { .. } => None,
+        }
+    }
+}
+
 impl Default for User {
     fn default() -> Self {
         User::AnonymousId {
@@ -317,6 +436,36 @@ impl Default for User {
     }
 }
 
+/// The identity fields of a message, as they appear on the wire.
+///
+/// `User` is deserialized through this struct rather than as an untagged enum
+/// so that messages carrying both IDs are not mistaken for `User::UserId`, and
+/// so that the ID fields are not also captured by a message's `extra` fields.
+#[derive(Deserialize)]
+struct UserFields {
+    #[serde(rename = "userId")]
+    user_id: Option<String>,
+
+    #[serde(rename = "anonymousId")]
+    anonymous_id: Option<String>,
+}
+
+impl TryFrom<UserFields> for User {
+    type Error = &'static str;
+
+    fn try_from(fields: UserFields) -> Result<Self, Self::Error> {
+        match (fields.user_id, fields.anonymous_id) {
+            (Some(user_id), Some(anonymous_id)) => Ok(User::Both {
+                user_id,
+                anonymous_id,
+            }),
+            (Some(user_id), None) => Ok(User::UserId { user_id }),
+            (None, Some(anonymous_id)) => Ok(User::AnonymousId { anonymous_id }),
+            (None, None) => Err("missing userId or anonymousId"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -462,4 +611,70 @@ mod tests {
                 .to_owned(),
         );
     }
+
+    #[test]
+    fn into_batch_messages() {
+        let track = |context: Option<Value>| {
+            BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned(),
+                },
+                event: "Foo".to_owned(),
+                properties: json!({}),
+                context,
+                ..Default::default()
+            })
+        };
+
+        let batch = Message::Batch(Batch {
+            batch: vec![track(None), track(Some(json!({ "ip": "1.2.3.4" })))],
+            context: Some(json!({ "library": "foo" })),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            vec![
+                track(Some(json!({ "library": "foo" }))),
+                track(Some(json!({ "ip": "1.2.3.4" }))),
+            ],
+            batch.into_batch_messages()
+        );
+    }
+
+    #[test]
+    fn deserialize_users() {
+        let users = vec![
+            User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            User::AnonymousId {
+                anonymous_id: "bar".to_owned(),
+            },
+            User::Both {
+                user_id: "foo".to_owned(),
+                anonymous_id: "bar".to_owned(),
+            },
+        ];
+
+        for user in users {
+            let msg = BatchMessage::Track(Track {
+                user,
+                event: "Foo".to_owned(),
+                properties: json!({}),
+                extra: [("messageId".to_owned(), json!("123"))]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                ..Default::default()
+            });
+
+            let json = serde_json::to_string(&msg).unwrap();
+            assert_eq!(msg, serde_json::from_str(&json).unwrap());
+        }
+
+        assert!(serde_json::from_str::<BatchMessage>(
+            r#"{"type":"track","event":"Foo","properties":{}}"#
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,256 @@
+//! Reporting delivery metrics through the `metrics` crate.
+//!
+//! [`MetricsHooks`](struct.MetricsHooks.html) records what a
+//! `BufferedSender` sends and drops with whichever `metrics` recorder the
+//! application installs, such as `metrics-exporter-prometheus`:
+//!
+//! ```no_run
+//! use analytics::buffered::{BufferedOptions, BufferedSender};
+//! use analytics::http::HttpClient;
+//! use analytics::metrics::MetricsHooks;
+//! use std::sync::Arc;
+//!
+//! let sender = BufferedSender::new(
+//!     HttpClient::default(),
+//!     "YOUR_WRITE_KEY",
+//!     BufferedOptions {
+//!         hooks: Some(Arc::new(MetricsHooks::new())),
+//!         ..Default::default()
+//!     },
+//! );
+//! ```
+//!
+//! The following metrics are recorded:
+//!
+//! | Name | Type | Labels |
+//! |------|------|--------|
+//! | `analytics_queued_messages` | gauge | |
+//! | `analytics_queued_bytes` | gauge | |
+//! | `analytics_batches_sent_total` | counter | |
+//! | `analytics_events_delivered_total` | counter | |
+//! | `analytics_retries_total` | counter | |
+//! | `analytics_requests_total` | counter | `outcome`, `status` |
+//! | `analytics_request_duration_seconds` | histogram | |
+//! | `analytics_events_dropped_total` | counter | `reason` |
+//! | `analytics_circuit_state_changes_total` | counter | `state` |
+//!
+//! `outcome` is an attempt's
+//! [outcome](../hooks/struct.Attempt.html#method.outcome), `status` is the
+//! HTTP status of its response, left out if the server didn't respond,
+//! `reason` is the [name](../hooks/enum.DropReason.html#method.name) of a
+//! drop reason, and `state` is the
+//! [name](../breaker/enum.CircuitState.html#method.name) of the state a
+//! circuit breaker changed to.
+
+use crate::hooks::{Attempt, BatchSent, CircuitStateChange, Dropped, Hooks, QueueDepth};
+use metrics::{Label, Unit};
+
+const QUEUED_MESSAGES: &str = "analytics_queued_messages";
+const QUEUED_BYTES: &str = "analytics_queued_bytes";
+const BATCHES_SENT: &str = "analytics_batches_sent_total";
+const EVENTS_DELIVERED: &str = "analytics_events_delivered_total";
+const RETRIES: &str = "analytics_retries_total";
+const REQUESTS: &str = "analytics_requests_total";
+const REQUEST_DURATION: &str = "analytics_request_duration_seconds";
+const EVENTS_DROPPED: &str = "analytics_events_dropped_total";
+const CIRCUIT_STATE_CHANGES: &str = "analytics_circuit_state_changes_total";
+
+/// `Hooks` which record metrics through the `metrics` crate.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsHooks {
+    labels: Vec<Label>,
+}
+
+impl MetricsHooks {
+    /// Construct hooks which record unlabelled metrics, and describe those
+    /// metrics to the installed recorder.
+    pub fn new() -> MetricsHooks {
+        metrics::describe_gauge!(QUEUED_MESSAGES, "Messages waiting to be sent");
+        metrics::describe_gauge!(QUEUED_BYTES, Unit::Bytes, "Bytes waiting to be sent");
+        metrics::describe_counter!(BATCHES_SENT, "Batches sent");
+        metrics::describe_counter!(EVENTS_DELIVERED, "Messages sent");
+        metrics::describe_counter!(RETRIES, "Requests retried");
+        metrics::describe_counter!(REQUESTS, "Requests made, by outcome and status");
+        metrics::describe_histogram!(REQUEST_DURATION, Unit::Seconds, "Request latency");
+        metrics::describe_counter!(EVENTS_DROPPED, "Messages dropped, by reason");
+        metrics::describe_counter!(
+            CIRCUIT_STATE_CHANGES,
+            "Circuit breaker state changes, by new state"
+        );
+
+        MetricsHooks::default()
+    }
+
+    /// Add a label to every metric, such as to tell several senders apart.
+    pub fn with_label(mut self, key: &str, value: &str) -> MetricsHooks {
+        self.labels
+            .push(Label::new(key.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn labels_with(&self, key: &'static str, value: String) -> Vec<Label> {
+        let mut labels = self.labels.clone();
+        labels.push(Label::new(key, value));
+        labels
+    }
+}
+
+impl Hooks for MetricsHooks {
+    fn on_queue_depth(&self, depth: &QueueDepth) {
+        metrics::gauge!(QUEUED_MESSAGES, self.labels.clone()).set(depth.messages as f64);
+        metrics::gauge!(QUEUED_BYTES, self.labels.clone()).set(depth.bytes as f64);
+    }
+
+    fn on_attempt(&self, attempt: &Attempt) {
+        let mut labels = self.labels_with("outcome", attempt.outcome().to_owned());
+        if let Some(status) = attempt.status {
+            labels.push(Label::new("status", status.to_string()));
+        }
+        metrics::counter!(REQUESTS, labels).increment(1);
+        metrics::histogram!(REQUEST_DURATION, self.labels.clone())
+            .record(attempt.latency.as_secs_f64());
+        if attempt.number > 1 {
+            metrics::counter!(RETRIES, self.labels.clone()).increment(1);
+        }
+    }
+
+    fn on_batch_sent(&self, sent: &BatchSent) {
+        metrics::counter!(BATCHES_SENT, self.labels.clone()).increment(1);
+        metrics::counter!(EVENTS_DELIVERED, self.labels.clone()).increment(sent.count as u64);
+    }
+
+    fn on_drop(&self, dropped: &Dropped) {
+        let labels = self.labels_with("reason", dropped.reason.name().to_owned());
+        metrics::counter!(EVENTS_DROPPED, labels).increment(dropped.count as u64);
+    }
+
+    fn on_circuit_state_change(&self, change: &CircuitStateChange) {
+        let labels = self.labels_with("state", change.to.name().to_owned());
+        metrics::counter!(CIRCUIT_STATE_CHANGES, labels).increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error as AnalyticsError;
+    use crate::hooks::DropReason;
+    use failure::Error;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+    use std::time::Duration;
+
+    #[test]
+    fn test_records_metrics() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let error: Error = AnalyticsError::Status {
+            status: 503,
+            response: Default::default(),
+        }
+        .into();
+        let transport_error = failure::err_msg("connection refused");
+
+        // The hooks are called directly, so a recorder local to this thread
+        // sees every measurement.
+        metrics::with_local_recorder(&recorder, || {
+            let hooks = MetricsHooks::new().with_label("sender", "test");
+            hooks.on_queue_depth(&QueueDepth {
+                messages: 2,
+                bytes: 100,
+            });
+            hooks.on_attempt(&Attempt {
+                number: 1,
+                count: 2,
+                latency: Duration::from_millis(5),
+                status: None,
+                error: Some(&transport_error),
+            });
+            hooks.on_attempt(&Attempt {
+                number: 2,
+                count: 2,
+                latency: Duration::from_millis(5),
+                status: Some(503),
+                error: Some(&error),
+            });
+            hooks.on_attempt(&Attempt {
+                number: 3,
+                count: 2,
+                latency: Duration::from_millis(5),
+                status: Some(200),
+                error: None,
+            });
+            hooks.on_batch_sent(&BatchSent {
+                count: 2,
+                bytes: 100,
+                latency: Duration::from_millis(10),
+            });
+            hooks.on_queue_depth(&QueueDepth::default());
+            hooks.on_drop(&Dropped {
+                count: 1,
+                reason: DropReason::TooLarge,
+            });
+        });
+
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (kind, key) = key.into_parts();
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|l| format!("{}={}", l.key(), l.value()))
+                    .collect();
+                (kind, key.name().to_owned(), labels.join(","), value)
+            })
+            .collect();
+
+        let find = |kind: MetricKind, name: &str, labels: &str| {
+            metrics
+                .iter()
+                .find(|m| m.0 == kind && m.1 == name && m.2 == labels)
+                .map(|m| &m.3)
+                .unwrap_or_else(|| panic!("missing metric {} {{{}}}", name, labels))
+        };
+
+        assert_eq!(
+            &DebugValue::Counter(1),
+            find(MetricKind::Counter, BATCHES_SENT, "sender=test")
+        );
+        assert_eq!(
+            &DebugValue::Counter(2),
+            find(MetricKind::Counter, EVENTS_DELIVERED, "sender=test")
+        );
+        assert_eq!(
+            &DebugValue::Counter(2),
+            find(MetricKind::Counter, RETRIES, "sender=test")
+        );
+        for labels in &[
+            "sender=test,outcome=transport_error",
+            "sender=test,outcome=http_error,status=503",
+            "sender=test,outcome=success,status=200",
+        ] {
+            assert_eq!(
+                &DebugValue::Counter(1),
+                find(MetricKind::Counter, REQUESTS, labels)
+            );
+        }
+        match find(MetricKind::Histogram, REQUEST_DURATION, "sender=test") {
+            DebugValue::Histogram(values) => assert_eq!(3, values.len()),
+            value => panic!("unexpected value: {:?}", value),
+        }
+        assert_eq!(
+            &DebugValue::Gauge(0.0.into()),
+            find(MetricKind::Gauge, QUEUED_MESSAGES, "sender=test")
+        );
+        assert_eq!(
+            &DebugValue::Counter(1),
+            find(
+                MetricKind::Counter,
+                EVENTS_DROPPED,
+                "sender=test,reason=too_large"
+            )
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Reporting delivery metrics through OpenTelemetry.
+//!
+//! [`OpenTelemetryHooks`](struct.OpenTelemetryHooks.html) records the same
+//! measurements as [`MetricsHooks`](../metrics/struct.MetricsHooks.html),
+//! using instruments from an OpenTelemetry `Meter`:
+//!
+//! ```no_run
+//! use analytics::buffered::{BufferedOptions, BufferedSender};
+//! use analytics::http::HttpClient;
+//! use analytics::opentelemetry::OpenTelemetryHooks;
+//! use std::sync::Arc;
+//!
+//! let meter = opentelemetry::global::meter("analytics");
+//! let sender = BufferedSender::new(
+//!     HttpClient::default(),
+//!     "YOUR_WRITE_KEY",
+//!     BufferedOptions {
+//!         hooks: Some(Arc::new(OpenTelemetryHooks::new(&meter))),
+//!         ..Default::default()
+//!     },
+//! );
+//! ```
+//!
+//! Instruments are named following OpenTelemetry's conventions:
+//!
+//! | Name | Instrument | Attributes |
+//! |------|------------|------------|
+//! | `analytics.queue.messages` | gauge | |
+//! | `analytics.queue.bytes` | gauge | |
+//! | `analytics.batches.sent` | counter | |
+//! | `analytics.events.delivered` | counter | |
+//! | `analytics.retries` | counter | |
+//! | `analytics.requests` | counter | `outcome`, `status` |
+//! | `analytics.request.duration` | histogram | |
+//! | `analytics.events.dropped` | counter | `reason` |
+//! | `analytics.circuit.state_changes` | counter | `state` |
+
+use crate::hooks::{Attempt, BatchSent, CircuitStateChange, Dropped, Hooks, QueueDepth};
+use ::opentelemetry::metrics::{Counter, Gauge, Histogram, Meter};
+use ::opentelemetry::KeyValue;
+
+/// `Hooks` which record metrics with OpenTelemetry instruments.
+pub struct OpenTelemetryHooks {
+    attributes: Vec<KeyValue>,
+    queued_messages: Gauge<u64>,
+    queued_bytes: Gauge<u64>,
+    batches_sent: Counter<u64>,
+    events_delivered: Counter<u64>,
+    retries: Counter<u64>,
+    requests: Counter<u64>,
+    request_duration: Histogram<f64>,
+    events_dropped: Counter<u64>,
+    circuit_state_changes: Counter<u64>,
+}
+
+impl OpenTelemetryHooks {
+    /// Construct hooks which record with instruments created from `meter`.
+    pub fn new(meter: &Meter) -> OpenTelemetryHooks {
+        OpenTelemetryHooks {
+            attributes: Vec::new(),
+            queued_messages: meter
+                .u64_gauge("analytics.queue.messages")
+                .with_description("Messages waiting to be sent")
+                .build(),
+            queued_bytes: meter
+                .u64_gauge("analytics.queue.bytes")
+                .with_description("Bytes waiting to be sent")
+                .with_unit("By")
+                .build(),
+            batches_sent: meter
+                .u64_counter("analytics.batches.sent")
+                .with_description("Batches sent")
+                .build(),
+            events_delivered: meter
+                .u64_counter("analytics.events.delivered")
+                .with_description("Messages sent")
+                .build(),
+            retries: meter
+                .u64_counter("analytics.retries")
+                .with_description("Requests retried")
+                .build(),
+            requests: meter
+                .u64_counter("analytics.requests")
+                .with_description("Requests made, by outcome and status")
+                .build(),
+            request_duration: meter
+                .f64_histogram("analytics.request.duration")
+                .with_description("Request latency")
+                .with_unit("s")
+                .build(),
+            events_dropped: meter
+                .u64_counter("analytics.events.dropped")
+                .with_description("Messages dropped, by reason")
+                .build(),
+            circuit_state_changes: meter
+                .u64_counter("analytics.circuit.state_changes")
+                .with_description("Circuit breaker state changes, by new state")
+                .build(),
+        }
+    }
+
+    /// Add an attribute to every measurement, such as to tell several
+    /// senders apart.
+    pub fn with_attribute(mut self, key: &'static str, value: &str) -> OpenTelemetryHooks {
+        self.attributes.push(KeyValue::new(key, value.to_owned()));
+        self
+    }
+
+    fn attributes_with(&self, key: &'static str, value: String) -> Vec<KeyValue> {
+        let mut attributes = self.attributes.clone();
+        attributes.push(KeyValue::new(key, value));
+        attributes
+    }
+}
+
+impl Hooks for OpenTelemetryHooks {
+    fn on_queue_depth(&self, depth: &QueueDepth) {
+        self.queued_messages
+            .record(depth.messages as u64, &self.attributes);
+        self.queued_bytes
+            .record(depth.bytes as u64, &self.attributes);
+    }
+
+    fn on_attempt(&self, attempt: &Attempt) {
+        let mut attributes = self.attributes_with("outcome", attempt.outcome().to_owned());
+        if let Some(status) = attempt.status {
+            attributes.push(KeyValue::new("status", i64::from(status)));
+        }
+        self.requests.add(1, &attributes);
+        self.request_duration
+            .record(attempt.latency.as_secs_f64(), &self.attributes);
+        if attempt.number > 1 {
+            self.retries.add(1, &self.attributes);
+        }
+    }
+
+    fn on_batch_sent(&self, sent: &BatchSent) {
+        self.batches_sent.add(1, &self.attributes);
+        self.events_delivered
+            .add(sent.count as u64, &self.attributes);
+    }
+
+    fn on_drop(&self, dropped: &Dropped) {
+        let attributes = self.attributes_with("reason", dropped.reason.name().to_owned());
+        self.events_dropped.add(dropped.count as u64, &attributes);
+    }
+
+    fn on_circuit_state_change(&self, change: &CircuitStateChange) {
+        let attributes = self.attributes_with("state", change.to.name().to_owned());
+        self.circuit_state_changes.add(1, &attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitState;
+    use crate::errors::Error as AnalyticsError;
+    use crate::hooks::DropReason;
+    use ::opentelemetry::metrics::MeterProvider;
+    use failure::Error;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+    use std::time::Duration;
+
+    #[test]
+    fn test_records_metrics() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        let hooks =
+            OpenTelemetryHooks::new(&provider.meter("analytics")).with_attribute("sender", "test");
+        let error: Error = AnalyticsError::Status {
+            status: 503,
+            response: Default::default(),
+        }
+        .into();
+        let transport_error = failure::err_msg("connection refused");
+
+        hooks.on_queue_depth(&QueueDepth {
+            messages: 2,
+            bytes: 100,
+        });
+        hooks.on_attempt(&Attempt {
+            number: 1,
+            count: 2,
+            latency: Duration::from_millis(5),
+            status: None,
+            error: Some(&transport_error),
+        });
+        hooks.on_attempt(&Attempt {
+            number: 2,
+            count: 2,
+            latency: Duration::from_millis(5),
+            status: Some(503),
+            error: Some(&error),
+        });
+        hooks.on_attempt(&Attempt {
+            number: 3,
+            count: 2,
+            latency: Duration::from_millis(5),
+            status: Some(200),
+            error: None,
+        });
+        hooks.on_batch_sent(&BatchSent {
+            count: 2,
+            bytes: 100,
+            latency: Duration::from_millis(10),
+        });
+        hooks.on_queue_depth(&QueueDepth::default());
+        hooks.on_drop(&Dropped {
+            count: 1,
+            reason: DropReason::TooLarge,
+        });
+        hooks.on_circuit_state_change(&CircuitStateChange {
+            from: CircuitState::Closed,
+            to: CircuitState::Open,
+        });
+        provider.force_flush().unwrap();
+
+        // Every data point, as its instrument's name, its attributes and its
+        // value, or count for a histogram.
+        let mut points = Vec::new();
+        for resource in exporter.get_finished_metrics().unwrap() {
+            for metric in resource.scope_metrics().flat_map(|scope| scope.metrics()) {
+                let name = metric.name().to_owned();
+                let attributes = |attributes: &mut dyn Iterator<Item = &KeyValue>| {
+                    let mut attributes: Vec<_> = attributes
+                        .map(|kv| format!("{}={}", kv.key, kv.value))
+                        .collect();
+                    attributes.sort();
+                    attributes.join(",")
+                };
+                match metric.data() {
+                    AggregatedMetrics::U64(MetricData::Sum(sum)) => {
+                        for point in sum.data_points() {
+                            let labels = attributes(&mut point.attributes());
+                            points.push((name.clone(), labels, point.value()));
+                        }
+                    }
+                    AggregatedMetrics::U64(MetricData::Gauge(gauge)) => {
+                        for point in gauge.data_points() {
+                            let labels = attributes(&mut point.attributes());
+                            points.push((name.clone(), labels, point.value()));
+                        }
+                    }
+                    AggregatedMetrics::F64(MetricData::Histogram(histogram)) => {
+                        for point in histogram.data_points() {
+                            let labels = attributes(&mut point.attributes());
+                            points.push((name.clone(), labels, point.count()));
+                        }
+                    }
+                    data => panic!("unexpected data for {}: {:?}", name, data),
+                }
+            }
+        }
+        points.sort();
+
+        let expected = vec![
+            ("analytics.batches.sent", "sender=test", 1),
+            (
+                "analytics.circuit.state_changes",
+                "sender=test,state=open",
+                1,
+            ),
+            ("analytics.events.delivered", "sender=test", 2),
+            (
+                "analytics.events.dropped",
+                "reason=too_large,sender=test",
+                1,
+            ),
+            ("analytics.queue.bytes", "sender=test", 0),
+            ("analytics.queue.messages", "sender=test", 0),
+            ("analytics.request.duration", "sender=test", 3),
+            (
+                "analytics.requests",
+                "outcome=http_error,sender=test,status=503",
+                1,
+            ),
+            (
+                "analytics.requests",
+                "outcome=success,sender=test,status=200",
+                1,
+            ),
+            (
+                "analytics.requests",
+                "outcome=transport_error,sender=test",
+                1,
+            ),
+            ("analytics.retries", "sender=test", 2),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(name, labels, value)| (name.to_owned(), labels.to_owned(), value))
+            .collect();
+        assert_eq!(expected, points);
+    }
+}
@@ -0,0 +1,189 @@
+//! Retrying of failed sends.
+
+use crate::backend;
+use crate::errors::Error as AnalyticsError;
+use failure::Error;
+use std::thread;
+use std::time::Duration;
+
+/// How to retry an operation which failed with a retryable error.
+///
+/// Retries back off exponentially, starting at `initial_backoff` and growing by
+/// `multiplier` up to at most `max_backoff` between attempts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first. `1` disables
+    /// retries.
+    pub max_attempts: u32,
+
+    /// How long to wait before the first retry.
+    pub initial_backoff: Duration,
+
+    /// The longest to wait between any two attempts.
+    pub max_backoff: Duration,
+
+    /// How much longer to wait before each successive retry.
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns how long to wait after the given attempt, counting from 1,
+    /// before making the next one.
+    ///
+    /// Backoffs which can't be represented, such as ones which overflow after
+    /// many attempts, are capped at `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        Duration::try_from_secs_f64(backoff)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Run `op` until it succeeds, fails with an error which is not
+    /// retryable, or `max_attempts` is reached. `op` is passed the number of
+    /// the attempt, counting from 1.
+    ///
+    /// On failure, returns the last error along with the number of attempts
+    /// made.
+    pub fn run<T, F>(&self, mut op: F) -> Result<T, (Error, u32)>
+    where
+        F: FnMut(u32) -> Result<T, Error>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op(attempt) {
+                Ok(t) => return Ok(t),
+                Err(e) if attempt < self.max_attempts && is_retryable(&e) => {
+                    thread::sleep(self.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err((e, attempt)),
+            }
+        }
+    }
+}
+
+/// Returns whether an error from sending a message is worth retrying.
+///
+/// Connection failures, timeouts, `429 Too Many Requests` and server errors
+/// are retryable. Other client errors, such as an invalid write key or a
+/// malformed message, will fail the same way if retried and are not. A
+/// `FanoutClient`'s failure is retryable only if no target accepted the
+/// message and all of the targets' failures are.
+pub fn is_retryable(e: &Error) -> bool {
+    match e.downcast_ref::<AnalyticsError>() {
+        Some(AnalyticsError::Status { status, .. }) => {
+            *status == 429 || (500..600).contains(status)
+        }
+        // Retrying sends to every target again, which would duplicate the
+        // message on targets which accepted it, and is only worthwhile if it
+        // could fix every failure.
+        Some(AnalyticsError::FanoutFailed { errors, delivered }) => {
+            *delivered == 0 && errors.0.iter().all(|(_, e)| is_retryable(e))
+        }
+        Some(_) => false,
+        None => backend::is_transient(e),
+    }
+}
+
+#[cfg(all(test, any(feature = "reqwest", feature = "ureq", feature = "hyper")))]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::message::{Message, Track, User};
+    use crate::testing::{MockResponse, MockServer};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            multiplier: 2.0,
+        };
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(200), policy.backoff(2));
+        assert_eq!(Duration::from_millis(400), policy.backoff(3));
+        assert_eq!(Duration::from_millis(500), policy.backoff(4));
+        assert_eq!(Duration::from_millis(500), policy.backoff(u32::MAX));
+
+        let policy = RetryPolicy {
+            max_backoff: Duration::MAX,
+            ..policy
+        };
+        assert_eq!(Duration::MAX, policy.backoff(u32::MAX));
+    }
+
+    #[test]
+    fn test_retries_server_errors() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::status(503));
+        server.enqueue(MockResponse::status(429));
+
+        let client = server.client();
+        policy()
+            .run(|_| client.send("write_key", &track()))
+            .unwrap();
+        assert_eq!(3, server.received().len());
+
+        server.enqueue(MockResponse::status(500));
+        server.enqueue(MockResponse::status(500));
+        server.enqueue(MockResponse::status(500));
+        let (_, attempts) = policy()
+            .run(|_| client.send("write_key", &track()))
+            .err()
+            .unwrap();
+        assert_eq!(3, attempts);
+    }
+
+    #[test]
+    fn test_does_not_retry_client_errors() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::status(400));
+
+        let client = server.client();
+        let (_, attempts) = policy()
+            .run(|_| client.send("write_key", &track()))
+            .err()
+            .unwrap();
+        assert_eq!(1, attempts);
+        assert_eq!(1, server.received().len());
+    }
+}
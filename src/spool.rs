@@ -0,0 +1,370 @@
+//! A durable, on-disk queue of batches awaiting delivery.
+
+use crate::batcher::SerializedBatch;
+use crate::errors::Error as AnalyticsError;
+use failure::Error;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const EXTENSION: &str = "batch";
+const TMP: &str = "tmp";
+const DEAD: &str = "dead";
+
+/// A first-in, first-out queue of serialized batches, each stored as a file in
+/// a directory so that it survives restarts.
+///
+/// Batches are written to a temporary file and synced before being renamed
+/// into place, so a crash never leaves a partially-written batch in the
+/// queue; temporary files left behind by a crash are deleted when the spool is
+/// next opened. A directory must only be opened by one `Spool` at a time.
+///
+/// ```
+/// use analytics::batcher::Batcher;
+/// use analytics::message::{BatchMessage, Track, User};
+/// use analytics::spool::Spool;
+///
+/// # let dir = std::env::temp_dir().join(format!("spool-doc-{}", std::process::id()));
+/// let spool = Spool::open(&dir).unwrap();
+///
+/// let mut batcher = Batcher::new(None);
+/// batcher.push(BatchMessage::Track(Track {
+///     user: User::UserId { user_id: "foo".to_owned() },
+///     event: "Foo".to_owned(),
+///     ..Default::default()
+/// })).unwrap();
+/// spool.push("write_key", &batcher.flush_serialized().unwrap()).unwrap();
+///
+/// let spooled = spool.peek().unwrap().unwrap();
+/// assert_eq!("write_key", spooled.write_key);
+/// spool.remove(&spooled).unwrap();
+/// assert!(spool.is_empty().unwrap());
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The ids of the batches in the queue, in order.
+    ids: VecDeque<u64>,
+    /// The id of the next batch pushed.
+    next: u64,
+}
+
+/// A batch read back out of a `Spool`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spooled {
+    id: u64,
+
+    /// The write key the batch is to be sent with.
+    pub write_key: String,
+
+    /// The batch itself.
+    pub batch: SerializedBatch,
+}
+
+impl Spool {
+    /// Open the queue stored in `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Spool, Error> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        // A batch still in a temporary file was never acknowledged, and may
+        // be incomplete.
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(TMP) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let ids: VecDeque<_> = Spool::ids(&dir)?.into();
+        // Buried batches keep their ids, so new ids must follow theirs too.
+        let dead = dir.join(DEAD);
+        let last_dead = if dead.is_dir() {
+            Spool::ids(&dead)?.last().copied()
+        } else {
+            None
+        };
+        let next = ids
+            .back()
+            .copied()
+            .max(last_dead)
+            .map(|id| id + 1)
+            .unwrap_or(0);
+
+        Ok(Spool {
+            dir,
+            state: Mutex::new(State { ids, next }),
+        })
+    }
+
+    /// Append a batch to the end of the queue.
+    pub fn push(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        if write_key.contains('\n') {
+            return Err(
+                AnalyticsError::InvalidRequest("write key contains a newline".to_owned()).into(),
+            );
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.next;
+        let tmp = self.dir.join(format!("{:020}.{}", id, TMP));
+
+        let written = File::create(&tmp).and_then(|mut file| {
+            writeln!(file, "{}", write_key)?;
+            writeln!(file, "{}", batch.len())?;
+            file.write_all(batch.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+
+        fs::rename(&tmp, self.path(id))?;
+        sync_dir(&self.dir)?;
+        state.ids.push_back(id);
+        state.next += 1;
+        Ok(())
+    }
+
+    /// Returns the batch at the front of the queue, without removing it.
+    pub fn peek(&self) -> Result<Option<Spooled>, Error> {
+        let id = self.state.lock().unwrap().ids.front().copied();
+        match id {
+            Some(id) => Ok(Some(self.read(id)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a batch returned by `peek` from the queue.
+    pub fn remove(&self, spooled: &Spooled) -> Result<(), Error> {
+        fs::remove_file(self.path(spooled.id))?;
+        self.forget(spooled.id);
+        Ok(())
+    }
+
+    /// Move a batch returned by `peek` out of the queue and into the `dead`
+    /// subdirectory, for batches which can never be delivered.
+    pub fn bury(&self, spooled: &Spooled) -> Result<(), Error> {
+        let dead = self.dir.join(DEAD);
+        fs::create_dir_all(&dead)?;
+        fs::rename(
+            self.path(spooled.id),
+            dead.join(format!("{:020}.{}", spooled.id, EXTENSION)),
+        )?;
+        self.forget(spooled.id);
+        Ok(())
+    }
+
+    /// Move a batch returned by `peek` to the back of the queue, such as to
+    /// send the batches behind it while its delivery is backing off.
+    pub fn requeue(&self, spooled: &Spooled) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next;
+        fs::rename(self.path(spooled.id), self.path(id))?;
+        sync_dir(&self.dir)?;
+        state.ids.retain(|&other| other != spooled.id);
+        state.ids.push_back(id);
+        state.next += 1;
+        Ok(())
+    }
+
+    /// Returns the number of batches in the queue.
+    pub fn len(&self) -> Result<usize, Error> {
+        Ok(self.state.lock().unwrap().ids.len())
+    }
+
+    /// Returns `true` if the queue holds no batches.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    fn forget(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.ids.front() == Some(&id) {
+            state.ids.pop_front();
+        } else {
+            state.ids.retain(|&other| other != id);
+        }
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, EXTENSION))
+    }
+
+    fn read(&self, id: u64) -> Result<Spooled, Error> {
+        let mut reader = BufReader::new(File::open(self.path(id))?);
+
+        let mut write_key = String::new();
+        reader.read_line(&mut write_key)?;
+        let mut len = String::new();
+        reader.read_line(&mut len)?;
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+
+        let len = len.trim().parse().map_err(|_| {
+            AnalyticsError::InvalidRequest(format!(
+                "corrupt spool file {}",
+                self.path(id).display()
+            ))
+        })?;
+
+        Ok(Spooled {
+            id,
+            write_key: write_key.trim_end_matches('\n').to_owned(),
+            batch: SerializedBatch::from_parts(body, len),
+        })
+    }
+
+    fn ids(dir: &Path) -> Result<Vec<u64>, Error> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                ids.push(id);
+            }
+        }
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+}
+
+/// Sync a directory, so that files renamed into it survive a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<(), Error> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batcher::Batcher;
+    use crate::message::{BatchMessage, Track, User};
+
+    fn batch(user_id: &str) -> SerializedBatch {
+        let mut batcher = Batcher::new(None);
+        batcher
+            .push(BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: user_id.to_owned(),
+                },
+                event: "Foo".to_owned(),
+                ..Default::default()
+            }))
+            .unwrap();
+        batcher.flush_serialized().unwrap()
+    }
+
+    #[test]
+    fn test_fifo_across_reopen() {
+        let dir = std::env::temp_dir().join(format!("analytics-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(None, spool.peek().unwrap());
+        spool.push("a", &batch("foo")).unwrap();
+        spool.push("b", &batch("bar")).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(&dir).unwrap();
+        spool.push("c", &batch("baz")).unwrap();
+        assert_eq!(3, spool.len().unwrap());
+
+        let first = spool.peek().unwrap().unwrap();
+        assert_eq!("a", first.write_key);
+        assert_eq!(batch("foo"), first.batch);
+        spool.remove(&first).unwrap();
+
+        let second = spool.peek().unwrap().unwrap();
+        assert_eq!("b", second.write_key);
+        spool.bury(&second).unwrap();
+
+        let third = spool.peek().unwrap().unwrap();
+        assert_eq!("c", third.write_key);
+        assert_eq!(1, third.batch.len());
+        spool.remove(&third).unwrap();
+
+        assert!(spool.is_empty().unwrap());
+        assert_eq!(1, fs::read_dir(dir.join("dead")).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_removes_tmp_on_open() {
+        let dir = std::env::temp_dir().join(format!("analytics-spool-tmp-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir).unwrap();
+        spool.push("a", &batch("foo")).unwrap();
+        drop(spool);
+        // As if the process crashed while writing the next batch.
+        fs::write(dir.join(format!("{:020}.tmp", 1)), "a\n1\n{\"batch\":[").unwrap();
+
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(1, spool.len().unwrap());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        spool.push("b", &batch("bar")).unwrap();
+        assert_eq!(2, spool.len().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ids_survive_draining() {
+        let dir = std::env::temp_dir().join(format!("analytics-spool-dead-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir).unwrap();
+        spool.push("a", &batch("foo")).unwrap();
+        spool.bury(&spool.peek().unwrap().unwrap()).unwrap();
+        assert!(spool.is_empty().unwrap());
+        drop(spool);
+
+        let spool = Spool::open(&dir).unwrap();
+        spool.push("b", &batch("bar")).unwrap();
+        spool.bury(&spool.peek().unwrap().unwrap()).unwrap();
+
+        assert_eq!(2, fs::read_dir(dir.join("dead")).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_requeue() {
+        let dir =
+            std::env::temp_dir().join(format!("analytics-spool-requeue-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir).unwrap();
+        spool.push("a", &batch("foo")).unwrap();
+        spool.push("b", &batch("bar")).unwrap();
+        spool.requeue(&spool.peek().unwrap().unwrap()).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(&dir).unwrap();
+        let keys: Vec<_> = (0..2)
+            .map(|_| {
+                let spooled = spool.peek().unwrap().unwrap();
+                spool.remove(&spooled).unwrap();
+                spooled.write_key
+            })
+            .collect();
+        assert_eq!(vec!["b", "a"], keys);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+//! A client which prints messages instead of sending them.
+
+use crate::client::Client;
+use crate::file;
+use crate::message::Message;
+use failure::Error;
+use std::io::{self, Write};
+
+/// A client which writes every message to standard output as
+/// newline-delimited JSON, in the same format as
+/// [`FileClient`](../file/struct.FileClient.html).
+///
+/// This lets the output of a job be redirected to a file, or piped into
+/// `analytics import`, without any network access.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdoutClient;
+
+impl StdoutClient {
+    /// Construct a new `StdoutClient`.
+    pub fn new() -> StdoutClient {
+        StdoutClient
+    }
+}
+
+impl Client for StdoutClient {
+    fn send(&self, _write_key: &str, msg: &Message) -> Result<(), Error> {
+        let lines = file::to_lines(msg)?;
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(&lines)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! A `Client` which keeps every message in memory.
+
+use crate::client::Client;
+use crate::message::{BatchMessage, Message, Track};
+use failure::Error;
+use std::sync::Mutex;
+
+/// A `Client` which never touches the network, and instead stores every
+/// message sent through it for later inspection.
+///
+/// ```
+/// use analytics::client::Client;
+/// use analytics::message::{Message, Track, User};
+/// use analytics::testing::MemoryClient;
+///
+/// let client = MemoryClient::new();
+/// client.send("write_key", &Message::Track(Track {
+///     user: User::UserId { user_id: "foo".to_owned() },
+///     event: "Signed Up".to_owned(),
+///     ..Default::default()
+/// })).unwrap();
+///
+/// assert_eq!(1, client.events("Signed Up").len());
+/// assert_eq!(1, client.by_user("foo").len());
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryClient {
+    sent: Mutex<Vec<(String, Message)>>,
+}
+
+impl MemoryClient {
+    /// Construct a new, empty client.
+    pub fn new() -> MemoryClient {
+        MemoryClient::default()
+    }
+
+    /// Returns every message sent so far, along with its write key.
+    pub fn sent(&self) -> Vec<(String, Message)> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Returns every message sent so far, with batches flattened into the
+    /// messages they contain.
+    pub fn messages(&self) -> Vec<BatchMessage> {
+        self.filter(|_, _| true)
+    }
+
+    /// Returns every message sent with the given write key.
+    pub fn messages_for(&self, write_key: &str) -> Vec<BatchMessage> {
+        self.filter(|key, _| key == write_key)
+    }
+
+    /// Returns every `track` message for the event with the given name.
+    pub fn events(&self, name: &str) -> Vec<Track> {
+        self.messages()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                BatchMessage::Track(track) if track.event == name => Some(track),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns every message whose user ID or anonymous ID is `id`.
+    pub fn by_user(&self, id: &str) -> Vec<BatchMessage> {
+        self.filter(|_, msg| {
+            let user = msg.user();
+            user.user_id() == Some(id) || user.anonymous_id() == Some(id)
+        })
+    }
+
+    /// Returns every message of the given type, such as `"identify"`.
+    pub fn by_type(&self, type_name: &str) -> Vec<BatchMessage> {
+        self.filter(|_, msg| msg.type_name() == type_name)
+    }
+
+    /// Returns the number of messages sent so far, counting each message in a
+    /// batch individually.
+    pub fn len(&self) -> usize {
+        self.messages().len()
+    }
+
+    /// Returns `true` if no messages have been sent so far, such as when
+    /// only empty batches were.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every message sent so far.
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+
+    fn filter<F>(&self, f: F) -> Vec<BatchMessage>
+    where
+        F: Fn(&str, &BatchMessage) -> bool,
+    {
+        self.sent()
+            .into_iter()
+            .flat_map(|(key, msg)| {
+                msg.into_batch_messages()
+                    .into_iter()
+                    .map(move |msg| (key.clone(), msg))
+            })
+            .filter(|(key, msg)| f(key, msg))
+            .map(|(_, msg)| msg)
+            .collect()
+    }
+}
+
+impl Client for MemoryClient {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((write_key.to_owned(), msg.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batcher::Batcher;
+    use crate::message::{Identify, User};
+    use serde_json::json;
+
+    #[test]
+    fn test_queries() {
+        let client = MemoryClient::new();
+        client
+            .send("a", &Message::Batch(Default::default()))
+            .unwrap();
+        assert!(client.is_empty());
+        client.clear();
+
+        let track = |user: User, event: &str| {
+            BatchMessage::Track(Track {
+                user,
+                event: event.to_owned(),
+                properties: json!({}),
+                ..Default::default()
+            })
+        };
+        let foo = || User::UserId {
+            user_id: "foo".to_owned(),
+        };
+        let bar = || User::Both {
+            user_id: "bar".to_owned(),
+            anonymous_id: "anon".to_owned(),
+        };
+
+        client
+            .send(
+                "a",
+                &Message::Identify(Identify {
+                    user: foo(),
+                    traits: json!({}),
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+
+        let mut batcher = Batcher::new(None);
+        batcher.push(track(foo(), "Signed Up")).unwrap();
+        batcher.push(track(bar(), "Signed Up")).unwrap();
+        batcher.push(track(bar(), "Logged In")).unwrap();
+        client
+            .send_serialized("b", &batcher.flush_serialized().unwrap())
+            .unwrap();
+
+        assert_eq!(2, client.sent().len());
+        assert_eq!(4, client.len());
+        assert_eq!(1, client.messages_for("a").len());
+        assert_eq!(3, client.messages_for("b").len());
+        assert_eq!(2, client.events("Signed Up").len());
+        assert_eq!(2, client.by_user("foo").len());
+        assert_eq!(2, client.by_user("anon").len());
+        assert_eq!(1, client.by_type("identify").len());
+        assert_eq!(
+            vec![track(bar(), "Logged In")],
+            client.by_type("track").split_off(2)
+        );
+
+        client.clear();
+        assert!(client.is_empty());
+    }
+}
@@ -0,0 +1,11 @@
+//! Test doubles for code which sends messages to Segment.
+//!
+//! This module is available with the `testing` feature.
+
+mod memory;
+mod recording;
+mod server;
+
+pub use self::memory::MemoryClient;
+pub use self::recording::{Entry, RecordingClient};
+pub use self::server::{MockResponse, MockServer, Received};
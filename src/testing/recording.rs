@@ -0,0 +1,197 @@
+//! A `Client` which keeps a transcript of what it forwards.
+
+use crate::batcher::SerializedBatch;
+use crate::client::Client;
+use crate::message::Message;
+use failure::Error;
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// A `Client` which forwards every message to another `Client`, keeping a
+/// transcript of each message and its outcome.
+///
+/// The transcript serializes to stable, pretty-printed JSON with `snapshot`,
+/// which is suitable for comparing against a golden file:
+///
+/// ```
+/// use analytics::client::Client;
+/// use analytics::message::{Message, Track, User};
+/// use analytics::testing::{MemoryClient, RecordingClient};
+///
+/// let client = RecordingClient::new(MemoryClient::new());
+/// client.send("write_key", &Message::Track(Track {
+///     user: User::UserId { user_id: "foo".to_owned() },
+///     event: "Signed Up".to_owned(),
+///     ..Default::default()
+/// })).unwrap();
+///
+/// assert_eq!(1, client.transcript().len());
+/// assert!(client.snapshot().contains("Signed Up"));
+/// ```
+#[derive(Debug)]
+pub struct RecordingClient<C> {
+    inner: C,
+    transcript: Mutex<Vec<Entry>>,
+}
+
+/// A message forwarded by a `RecordingClient`, and its outcome.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    /// The write key the message was sent with.
+    pub write_key: String,
+
+    /// The message which was sent.
+    pub message: Message,
+
+    /// The error the inner client failed with, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<C: Client> RecordingClient<C> {
+    /// Construct a client which forwards to `inner`.
+    pub fn new(inner: C) -> RecordingClient<C> {
+        RecordingClient {
+            inner,
+            transcript: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the client being forwarded to.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns every message forwarded so far, in order.
+    pub fn transcript(&self) -> Vec<Entry> {
+        self.transcript.lock().unwrap().clone()
+    }
+
+    /// Returns the transcript as pretty-printed JSON.
+    pub fn snapshot(&self) -> String {
+        serde_json::to_string_pretty(&*self.transcript.lock().unwrap()).unwrap()
+    }
+
+    /// Forget every message forwarded so far.
+    pub fn clear(&self) {
+        self.transcript.lock().unwrap().clear();
+    }
+
+    fn record<T>(&self, write_key: &str, message: Message, result: &Result<T, Error>) {
+        self.transcript.lock().unwrap().push(Entry {
+            write_key: write_key.to_owned(),
+            message,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+}
+
+impl<C: Client> Client for RecordingClient<C> {
+    fn send(&self, write_key: &str, msg: &Message) -> Result<(), Error> {
+        let result = self.inner.send(write_key, msg);
+        self.record(write_key, msg.clone(), &result);
+        result
+    }
+
+    fn send_serialized(&self, write_key: &str, batch: &SerializedBatch) -> Result<(), Error> {
+        let result = self.inner.send_serialized(write_key, batch);
+        // A batch which doesn't decode was still sent; it just can't be
+        // recorded.
+        if let Ok(message) = batch.to_message() {
+            self.record(write_key, message, &result);
+        }
+        result
+    }
+
+    fn send_serialized_with_status(
+        &self,
+        write_key: &str,
+        batch: &SerializedBatch,
+    ) -> Result<Option<u16>, Error> {
+        let result = self.inner.send_serialized_with_status(write_key, batch);
+        if let Ok(message) = batch.to_message() {
+            self.record(write_key, message, &result);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Track, User};
+    use crate::testing::MemoryClient;
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    use crate::testing::{MockResponse, MockServer};
+    use serde_json::json;
+
+    fn track() -> Message {
+        Message::Track(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Signed Up".to_owned(),
+            properties: json!({ "plan": "pro" }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let client = RecordingClient::new(MemoryClient::new());
+        client.send("write_key", &track()).unwrap();
+
+        assert_eq!(1, client.inner().len());
+        assert_eq!(
+            r#"[
+  {
+    "writeKey": "write_key",
+    "message": {
+      "userId": "foo",
+      "event": "Signed Up",
+      "properties": {
+        "plan": "pro"
+      }
+    }
+  }
+]"#,
+            client.snapshot()
+        );
+    }
+
+    struct Accept;
+
+    impl Client for Accept {
+        fn send(&self, _: &str, _: &Message) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn send_serialized(&self, _: &str, _: &SerializedBatch) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_undecodable_batch() {
+        let client = RecordingClient::new(Accept);
+        let batch = SerializedBatch::from_parts(b"not json".to_vec(), 1);
+        client.send_serialized("write_key", &batch).unwrap();
+        assert!(client.transcript().is_empty());
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    #[test]
+    fn test_records_errors() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::status(500));
+
+        let client = RecordingClient::new(server.client());
+        assert!(client.send("write_key", &track()).is_err());
+        client.send("write_key", &track()).unwrap();
+
+        let transcript = client.transcript();
+        assert!(transcript[0].error.is_some());
+        assert_eq!(None, transcript[1].error);
+    }
+}
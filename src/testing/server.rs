@@ -0,0 +1,380 @@
+//! An in-process server which mimics the Segment tracking API.
+
+use crate::endpoint::Endpoint;
+#[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+use crate::http::HttpClient;
+use crate::ingest;
+use crate::message::{BatchMessage, Message};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tiny_http::{Request, Server};
+
+/// A local HTTP server implementing the Segment tracking API, which records
+/// every message it receives.
+///
+/// By default, every valid request is accepted with a `200`. Responses can be
+/// scripted with `enqueue` to exercise error handling:
+///
+/// ```
+/// use analytics::client::Client;
+/// use analytics::message::{Message, Track, User};
+/// use analytics::testing::{MockResponse, MockServer};
+///
+/// let server = MockServer::start();
+/// server.enqueue(MockResponse::status(503));
+///
+/// let client = server.client();
+/// let msg = Message::Track(Track {
+///     user: User::UserId { user_id: "foo".to_owned() },
+///     event: "Foo".to_owned(),
+///     ..Default::default()
+/// });
+///
+/// assert!(client.send("write_key", &msg).is_err());
+/// client.send("write_key", &msg).unwrap();
+///
+/// assert_eq!(2, server.received().len());
+/// assert_eq!(vec![msg], server.messages());
+/// ```
+///
+/// The server shuts down when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    server: Arc<Server>,
+    state: Arc<Mutex<State>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    write_key: Option<String>,
+    responses: VecDeque<MockResponse>,
+    received: Vec<Received>,
+}
+
+/// A request received by a `MockServer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+    /// The path the request was made to, such as `/v1/batch`.
+    pub path: String,
+
+    /// The write key the request was authenticated with, if any.
+    pub write_key: Option<String>,
+
+    /// The message carried by the request, if it was valid.
+    pub message: Option<Message>,
+
+    /// The status the server responded with.
+    pub status: u16,
+}
+
+/// A scripted response for a `MockServer` to make.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockResponse {
+    /// The status to respond with, or `None` to respond as normal.
+    status: Option<u16>,
+    delay: Option<Duration>,
+    body: Option<String>,
+}
+
+impl MockResponse {
+    /// Respond with the given HTTP status, such as `500` or `429`.
+    pub fn status(status: u16) -> MockResponse {
+        MockResponse {
+            status: Some(status),
+            delay: None,
+            body: None,
+        }
+    }
+
+    /// Respond normally, but only after waiting for `delay`.
+    pub fn delay(delay: Duration) -> MockResponse {
+        MockResponse {
+            status: None,
+            delay: Some(delay),
+            body: None,
+        }
+    }
+
+    /// Wait for `delay` before making this response.
+    pub fn with_delay(mut self, delay: Duration) -> MockResponse {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Respond with `body` instead of the usual `{"success":true}` or
+    /// `{"success":false}`.
+    pub fn with_body(mut self, body: &str) -> MockResponse {
+        self.body = Some(body.to_owned());
+        self
+    }
+}
+
+impl MockServer {
+    /// Start a server listening on a random local port.
+    pub fn start() -> MockServer {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("could not start mock server"));
+        let addr = server.server_addr().to_ip().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let handle = {
+            let server = Arc::clone(&server);
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    let state = Arc::clone(&state);
+                    thread::spawn(move || handle(request, &state));
+                }
+            })
+        };
+
+        MockServer {
+            addr,
+            server,
+            state,
+            handle: Some(handle),
+        }
+    }
+
+    /// Start a server which rejects requests not authenticated with
+    /// `write_key` with a `401`.
+    pub fn with_write_key(write_key: &str) -> MockServer {
+        let server = MockServer::start();
+        server.state.lock().unwrap().write_key = Some(write_key.to_owned());
+        server
+    }
+
+    /// Returns the address this server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns an endpoint which sends to this server.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::new(&format!("http://{}", self.addr)).unwrap()
+    }
+
+    /// Returns an `HttpClient` which sends to this server.
+    #[cfg(any(feature = "reqwest", feature = "ureq", feature = "hyper"))]
+    pub fn client(&self) -> HttpClient {
+        HttpClient::builder()
+            .with_endpoint(self.endpoint())
+            .build()
+            .unwrap()
+    }
+
+    /// Script the response to a future request. Scripted responses are used
+    /// in the order they were enqueued, after which requests are accepted
+    /// normally again.
+    pub fn enqueue(&self, response: MockResponse) {
+        self.state.lock().unwrap().responses.push_back(response);
+    }
+
+    /// Returns every request received so far, including rejected ones.
+    pub fn received(&self) -> Vec<Received> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Returns the messages of every request accepted so far.
+    pub fn messages(&self) -> Vec<Message> {
+        self.received()
+            .into_iter()
+            .filter(|r| r.status < 300)
+            .filter_map(|r| r.message)
+            .collect()
+    }
+
+    /// Returns every accepted message as a `BatchMessage`, with batches
+    /// flattened into the messages they contain.
+    pub fn batch_messages(&self) -> Vec<BatchMessage> {
+        self.messages()
+            .into_iter()
+            .flat_map(Message::into_batch_messages)
+            .collect()
+    }
+
+    /// Forget every request received so far.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().received.clear();
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn handle(mut request: Request, state: &Mutex<State>) {
+    let ingest::TrackingRequest {
+        path,
+        write_key,
+        message,
+    } = ingest::read_request(&mut request);
+    let rejected = message.as_ref().err().map(ingest::error_status);
+    let message = message.ok();
+
+    let (scripted, expected_key) = {
+        let mut state = state.lock().unwrap();
+        (state.responses.pop_front(), state.write_key.clone())
+    };
+
+    let (scripted_status, delay, scripted_body) = match scripted {
+        Some(response) => (response.status, response.delay, response.body),
+        None => (None, None, None),
+    };
+    let status = match (scripted_status, &message) {
+        (Some(status), _) => status,
+        (None, None) => rejected.unwrap_or(400),
+        (None, Some(_)) if expected_key.is_some() && write_key != expected_key => 401,
+        (None, Some(_)) => 200,
+    };
+
+    if let Some(delay) = delay {
+        thread::sleep(delay);
+    }
+
+    state.lock().unwrap().received.push(Received {
+        path,
+        write_key,
+        message,
+        status,
+    });
+
+    let body = scripted_body.unwrap_or_else(|| {
+        match status {
+            400 => r#"{"success":false,"code":"invalid_request","message":"invalid request"}"#,
+            401 => r#"{"success":false,"code":"unauthorized","message":"invalid write key"}"#,
+            status if status < 300 => r#"{"success":true}"#,
+            _ => r#"{"success":false}"#,
+        }
+        .to_owned()
+    });
+    let _ = ingest::respond_with(request, status, &body);
+}
+
+#[cfg(all(test, any(feature = "reqwest", feature = "ureq", feature = "hyper")))]
+mod tests {
+    use super::*;
+    use crate::batcher::Batcher;
+    use crate::client::Client;
+    use crate::http::Compression;
+    use crate::message::{Identify, Track, User};
+    use serde_json::json;
+    use std::time::Instant;
+
+    fn track(user_id: &str) -> Track {
+        Track {
+            user: User::UserId {
+                user_id: user_id.to_owned(),
+            },
+            event: "Foo".to_owned(),
+            properties: json!({ "foo": "bar" }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_records_messages() {
+        let server = MockServer::with_write_key("write_key");
+        let client = server.client();
+
+        let identify = Message::Identify(Identify {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            traits: json!({ "plan": "pro" }),
+            ..Default::default()
+        });
+        client.send("write_key", &identify).unwrap();
+
+        let mut batcher = Batcher::new(None);
+        batcher.push(BatchMessage::Track(track("foo"))).unwrap();
+        batcher.push(BatchMessage::Track(track("bar"))).unwrap();
+        client
+            .send_serialized("write_key", &batcher.flush_serialized().unwrap())
+            .unwrap();
+
+        let received = server.received();
+        assert_eq!("/v1/identify", received[0].path);
+        assert_eq!("/v1/batch", received[1].path);
+        assert_eq!(Some("write_key".to_owned()), received[1].write_key);
+        assert_eq!(
+            vec![
+                BatchMessage::Identify(match identify {
+                    Message::Identify(m) => m,
+                    _ => unreachable!(),
+                }),
+                BatchMessage::Track(track("foo")),
+                BatchMessage::Track(track("bar")),
+            ],
+            server.batch_messages()
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_requests() {
+        let server = MockServer::with_write_key("write_key");
+        let client = server.client();
+
+        assert!(client
+            .send("wrong_key", &Message::Track(track("foo")))
+            .is_err());
+        assert_eq!(401, server.received()[0].status);
+
+        let response = reqwest::blocking::Client::new()
+            .post(server.endpoint().url("/v1/track"))
+            .basic_auth("write_key", Some(""))
+            .body(r#"{"event":"Foo"}"#)
+            .send()
+            .unwrap();
+        assert_eq!(400, response.status().as_u16());
+        assert!(server.messages().is_empty());
+    }
+
+    #[test]
+    fn test_scripted_responses() {
+        let server = MockServer::with_write_key("write_key");
+        let client = server.client();
+        let msg = Message::Track(track("foo"));
+
+        server.enqueue(MockResponse::status(500));
+        server.enqueue(MockResponse::status(429));
+        server.enqueue(MockResponse::delay(Duration::from_millis(200)));
+
+        assert!(client.send("write_key", &msg).is_err());
+        assert!(client.send("write_key", &msg).is_err());
+
+        let start = Instant::now();
+        client.send("write_key", &msg).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // A delayed response is otherwise made as normal.
+        server.enqueue(MockResponse::delay(Duration::from_millis(10)));
+        assert!(client.send("wrong_key", &msg).is_err());
+
+        let statuses: Vec<u16> = server.received().iter().map(|r| r.status).collect();
+        assert_eq!(vec![500, 429, 200, 401], statuses);
+        assert_eq!(vec![msg], server.messages());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let server = MockServer::start();
+        let client = HttpClient::builder()
+            .with_endpoint(server.endpoint())
+            .with_compression(Compression::Gzip)
+            .build()
+            .unwrap();
+
+        let msg = Message::Track(track("foo"));
+        client.send("write_key", &msg).unwrap();
+        assert_eq!(vec![msg], server.messages());
+    }
+}
@@ -0,0 +1,481 @@
+//! Turning `tracing` spans and events into analytics events.
+//!
+//! [`AnalyticsLayer`](struct.AnalyticsLayer.html) is a `tracing-subscriber`
+//! layer which picks out the spans and events marked as analytics events,
+//! converts them into `track` messages, and hands them to a
+//! [`BufferedSender`](../buffered/struct.BufferedSender.html):
+//!
+//! ```no_run
+//! use analytics::buffered::{BufferedOptions, BufferedSender};
+//! use analytics::http::HttpClient;
+//! use analytics::tracing::AnalyticsLayer;
+//! use std::sync::Arc;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let sender = Arc::new(BufferedSender::new(
+//!     HttpClient::default(),
+//!     "YOUR_WRITE_KEY",
+//!     BufferedOptions::default(),
+//! ));
+//! tracing_subscriber::registry()
+//!     .with(AnalyticsLayer::new(sender))
+//!     .init();
+//!
+//! // Sends a "Signed Up" event for "some_user_id", with a `plan` property.
+//! tracing::info!(analytics.event = "Signed Up", user_id = "some_user_id", plan = "pro");
+//! ```
+//!
+//! An event is marked by a field naming the analytics event, `analytics.event`
+//! by default, or by being emitted with a designated target, in which case
+//! its message is the event name. The remaining fields become the event's
+//! properties.
+//!
+//! The user is taken from the `user_id` and `anonymous_id` fields, of the
+//! event itself or of the nearest span they were recorded on, so they can be
+//! set once on a request's span. Events without either are skipped, as
+//! Segment would reject them.
+//!
+//! A span marked as an analytics event is sent when it closes, with the
+//! fields recorded on it by then. Of other spans, only those with a user
+//! field are kept track of, and then only for their user.
+//!
+//! The layer never waits for room in the sender's queue, whatever its
+//! `OverflowPolicy`: events emitted while the queue is full are dropped, so
+//! that logging doesn't hold up the application.
+
+use crate::buffered::BufferedSender;
+use crate::message::{BatchMessage, Track, User};
+use chrono::Utc;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::Arc;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A `tracing-subscriber` layer which sends analytics events.
+pub struct AnalyticsLayer {
+    sender: Arc<BufferedSender>,
+    target: Option<String>,
+    event_field: String,
+    user_id_field: String,
+    anonymous_id_field: String,
+}
+
+impl AnalyticsLayer {
+    /// Construct a layer which enqueues events on `sender`.
+    pub fn new(sender: Arc<BufferedSender>) -> AnalyticsLayer {
+        AnalyticsLayer {
+            sender,
+            target: None,
+            event_field: "analytics.event".to_owned(),
+            user_id_field: "user_id".to_owned(),
+            anonymous_id_field: "anonymous_id".to_owned(),
+        }
+    }
+
+    /// Also treat everything emitted with `target` as an analytics event.
+    pub fn with_target(mut self, target: &str) -> AnalyticsLayer {
+        self.target = Some(target.to_owned());
+        self
+    }
+
+    /// Set the field which marks an analytics event and holds its name.
+    /// Defaults to `analytics.event`.
+    pub fn with_event_field(mut self, field: &str) -> AnalyticsLayer {
+        self.event_field = field.to_owned();
+        self
+    }
+
+    /// Set the field holding the user ID. Defaults to `user_id`.
+    pub fn with_user_id_field(mut self, field: &str) -> AnalyticsLayer {
+        self.user_id_field = field.to_owned();
+        self
+    }
+
+    /// Set the field holding the anonymous ID. Defaults to `anonymous_id`.
+    pub fn with_anonymous_id_field(mut self, field: &str) -> AnalyticsLayer {
+        self.anonymous_id_field = field.to_owned();
+        self
+    }
+
+    fn has_target(&self, metadata: &Metadata) -> bool {
+        self.target.as_deref() == Some(metadata.target())
+    }
+
+    /// Returns whether a span or event may be an analytics event, judging by
+    /// its target and the fields it declares.
+    fn is_marked(&self, metadata: &Metadata) -> bool {
+        self.has_target(metadata) || metadata.fields().field(&self.event_field).is_some()
+    }
+
+    /// Returns whether a span or event declares a user field.
+    fn has_identity(&self, metadata: &Metadata) -> bool {
+        let fields = metadata.fields();
+        fields.field(&self.user_id_field).is_some()
+            || fields.field(&self.anonymous_id_field).is_some()
+    }
+
+    /// Build a message from an event's fields, looking up the user in the
+    /// given spans if the fields don't identify one.
+    fn track<I>(&self, fields: Fields, mut scope: I) -> Option<BatchMessage>
+    where
+        I: Iterator<Item = Identity>,
+    {
+        let mut identity = fields.identity;
+        while identity.is_empty() {
+            match scope.next() {
+                Some(span) => identity = span,
+                None => return None,
+            }
+        }
+
+        let user = match (identity.user_id, identity.anonymous_id) {
+            (Some(user_id), Some(anonymous_id)) => User::Both {
+                user_id,
+                anonymous_id,
+            },
+            (Some(user_id), None) => User::UserId { user_id },
+            (None, Some(anonymous_id)) => User::AnonymousId { anonymous_id },
+            (None, None) => unreachable!(),
+        };
+
+        Some(BatchMessage::Track(Track {
+            user,
+            event: fields.event?,
+            properties: Value::Object(fields.properties),
+            timestamp: Some(Utc::now()),
+            ..Default::default()
+        }))
+    }
+
+    fn visit<F>(&self, is_event: bool, message_is_name: bool, properties: bool, record: F) -> Fields
+    where
+        F: FnOnce(&mut FieldVisitor),
+    {
+        let mut visitor = FieldVisitor {
+            layer: self,
+            message_is_name,
+            properties,
+            fields: Fields {
+                is_event,
+                ..Default::default()
+            },
+        };
+        record(&mut visitor);
+        visitor.fields
+    }
+}
+
+impl<S> Layer<S> for AnalyticsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let marked = self.is_marked(attrs.metadata());
+        if !marked && !self.has_identity(attrs.metadata()) {
+            return;
+        }
+        let has_target = self.has_target(attrs.metadata());
+        let fields = self.visit(has_target, has_target, marked, |v| attrs.record(v));
+
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if has_target && fields.event.is_none() {
+                // Spans have no message, so are named after the span itself.
+                extensions.insert(Fields {
+                    event: Some(attrs.metadata().name().to_owned()),
+                    ..fields
+                });
+            } else {
+                extensions.insert(fields);
+            }
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if let Some(fields) = extensions.get_mut::<Fields>() {
+                let mut visitor = FieldVisitor {
+                    layer: self,
+                    message_is_name: false,
+                    properties: self.is_marked(span.metadata()),
+                    fields: std::mem::take(fields),
+                };
+                values.record(&mut visitor);
+                *fields = visitor.fields;
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !self.is_marked(event.metadata()) {
+            return;
+        }
+        let has_target = self.has_target(event.metadata());
+        let fields = self.visit(has_target, has_target, true, |v| event.record(v));
+        if !fields.is_event {
+            return;
+        }
+
+        let scope = ctx.event_scope(event).into_iter().flatten().map(|span| {
+            span.extensions()
+                .get::<Fields>()
+                .map(|fields| fields.identity.clone())
+                .unwrap_or_default()
+        });
+
+        if let Some(msg) = self.track(fields, scope) {
+            let _ = self.sender.try_enqueue(msg);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let fields = match span.extensions_mut().remove::<Fields>() {
+            Some(fields) if fields.is_event => fields,
+            _ => return,
+        };
+
+        let scope = span.scope().skip(1).map(|span| {
+            span.extensions()
+                .get::<Fields>()
+                .map(|fields| fields.identity.clone())
+                .unwrap_or_default()
+        });
+
+        if let Some(msg) = self.track(fields, scope) {
+            let _ = self.sender.try_enqueue(msg);
+        }
+    }
+}
+
+/// The fields recorded on a span or event.
+#[derive(Default)]
+struct Fields {
+    is_event: bool,
+    event: Option<String>,
+    identity: Identity,
+    properties: Map<String, Value>,
+}
+
+/// The user fields recorded on a span or event.
+#[derive(Default, Clone)]
+struct Identity {
+    user_id: Option<String>,
+    anonymous_id: Option<String>,
+}
+
+impl Identity {
+    fn is_empty(&self) -> bool {
+        self.user_id.is_none() && self.anonymous_id.is_none()
+    }
+}
+
+struct FieldVisitor<'a> {
+    layer: &'a AnalyticsLayer,
+    message_is_name: bool,
+    /// Whether to keep fields other than the event and user as properties.
+    properties: bool,
+    fields: Fields,
+}
+
+impl FieldVisitor<'_> {
+    fn record(&mut self, field: &Field, value: Value) {
+        let name = field.name();
+        let fields = &mut self.fields;
+
+        let as_string = || match value {
+            Value::String(ref s) => s.clone(),
+            ref value => value.to_string(),
+        };
+
+        if name == self.layer.event_field || (self.message_is_name && name == "message") {
+            fields.is_event = true;
+            fields.event = Some(as_string());
+        } else if name == self.layer.user_id_field {
+            fields.identity.user_id = Some(as_string());
+        } else if name == self.layer.anonymous_id_field {
+            fields.identity.anonymous_id = Some(as_string());
+        } else if self.properties {
+            fields.properties.insert(name.to_owned(), value);
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffered::BufferedOptions;
+    use crate::testing::MemoryClient;
+    use serde_json::json;
+    use tracing_subscriber::prelude::*;
+
+    fn with_layer<F: FnOnce()>(
+        configure: fn(AnalyticsLayer) -> AnalyticsLayer,
+        f: F,
+    ) -> Vec<Track> {
+        let client = Arc::new(MemoryClient::new());
+        let sender = Arc::new(BufferedSender::new(
+            client.clone(),
+            "write_key",
+            BufferedOptions::default(),
+        ));
+        let layer = configure(AnalyticsLayer::new(sender.clone()));
+
+        ::tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), f);
+        sender.flush().unwrap();
+
+        client
+            .messages()
+            .into_iter()
+            .map(|msg| match msg {
+                BatchMessage::Track(track) => track,
+                msg => panic!("unexpected message: {:?}", msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_events() {
+        let tracks = with_layer(
+            |layer| layer,
+            || {
+                ::tracing::info!(
+                    analytics.event = "Signed Up",
+                    user_id = "foo",
+                    plan = "pro",
+                    seats = 3
+                );
+                ::tracing::info!(plan = "pro", "not an analytics event");
+                ::tracing::info!(analytics.event = "No User");
+
+                let span = ::tracing::info_span!("request", user_id = "bar", anonymous_id = "baz");
+                let _guard = span.enter();
+                ::tracing::info!(analytics.event = "Clicked", button = ?Some(1));
+            },
+        );
+
+        assert_eq!(2, tracks.len());
+        assert_eq!("Signed Up", tracks[0].event);
+        assert_eq!(
+            User::UserId {
+                user_id: "foo".to_owned()
+            },
+            tracks[0].user
+        );
+        assert_eq!(json!({ "plan": "pro", "seats": 3 }), tracks[0].properties);
+        assert!(tracks[0].timestamp.is_some());
+
+        assert_eq!("Clicked", tracks[1].event);
+        assert_eq!(
+            User::Both {
+                user_id: "bar".to_owned(),
+                anonymous_id: "baz".to_owned()
+            },
+            tracks[1].user
+        );
+        assert_eq!(json!({ "button": "Some(1)" }), tracks[1].properties);
+    }
+
+    #[test]
+    fn test_target_and_spans() {
+        let tracks = with_layer(
+            |layer| layer.with_target("analytics").with_user_id_field("uid"),
+            || {
+                ::tracing::info!(target: "analytics", uid = "foo", plan = "pro", "Signed Up");
+
+                let span = ::tracing::info_span!(
+                    "checkout",
+                    analytics.event = "Checked Out",
+                    uid = "foo",
+                    total = ::tracing::field::Empty
+                );
+                span.record("total", 42);
+                drop(span);
+            },
+        );
+
+        assert_eq!(2, tracks.len());
+        assert_eq!("Signed Up", tracks[0].event);
+        assert_eq!(json!({ "plan": "pro" }), tracks[0].properties);
+        assert_eq!("Checked Out", tracks[1].event);
+        assert_eq!(Some("foo"), tracks[1].user.user_id());
+        assert_eq!(json!({ "total": 42 }), tracks[1].properties);
+    }
+
+    #[test]
+    fn test_never_blocks() {
+        use crate::buffered::OverflowPolicy;
+        use crate::client::Client;
+        use crate::message::Message;
+        use failure::Error;
+        use std::sync::Mutex;
+
+        // Holds up sending until the gate is unlocked.
+        struct Gate(Mutex<()>);
+
+        impl Client for Gate {
+            fn send(&self, _: &str, _: &Message) -> Result<(), Error> {
+                let _open = self.0.lock().unwrap();
+                Ok(())
+            }
+        }
+
+        let gate = Arc::new(Gate(Mutex::new(())));
+        let closed = gate.0.lock().unwrap();
+        let sender = Arc::new(BufferedSender::new(
+            gate.clone(),
+            "write_key",
+            BufferedOptions {
+                max_batch_len: 1,
+                max_queue_len: 1,
+                overflow_policy: OverflowPolicy::Block,
+                ..Default::default()
+            },
+        ));
+        let layer = AnalyticsLayer::new(sender.clone());
+
+        ::tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for _ in 0..3 {
+                ::tracing::info!(analytics.event = "Signed Up", user_id = "foo");
+            }
+        });
+        assert!(sender.dropped() >= 1);
+
+        drop(closed);
+        sender.flush().unwrap();
+    }
+}